@@ -0,0 +1,230 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::atomic_spin_cell::{from_bits_unchecked, to_bits_unchecked};
+use crate::sync::{AtomicU64, Ordering, UnsafeCell};
+use crate::{AtomicPayload, SpinWait};
+use std::mem::{self, MaybeUninit};
+
+/// A cell for any `AtomicPayload`, modeled on crossbeam's `AtomicCell`: a
+/// payload of at most 8 bytes lives in an `AtomicU64` and never waits, and a
+/// wider one is copied in and out under that word used as a flag. Which
+/// path a payload takes is decided at compile time from its size, and
+/// `is_lock_free` reports it, so generic code can hold a `u16` and a
+/// `[u64; 2]` in the same type.
+///
+/// On the lock-free path `load` never spins, and `store`, `swap` and
+/// `compare_exchange` are single atomic instructions, as in
+/// `AtomicSpinCell`. On the fallback path every operation is a short hold of
+/// the flag, which a `load` waits out while a writer has it. Either way
+/// loads are `Acquire`, stores `Release` and read-modify-writes `AcqRel`.
+/// `with_lock` covers compound updates on both paths.
+///
+/// The fallback storage is there on both paths, so a lock-free cell is the
+/// word plus an unused slot for `T`.
+pub struct AtomicCellCompat<T: AtomicPayload> {
+    // The value on the lock-free path; on the fallback path the flag, 0
+    // while free and 1 while held.
+    bits: AtomicU64,
+    // The value on the fallback path, read and written only under the flag.
+    slot: UnsafeCell<MaybeUninit<T>>,
+}
+
+// As for `AtomicSpinCell`: sharing the cell hands out copies of the value.
+unsafe impl<T: AtomicPayload + Send> Sync for AtomicCellCompat<T> {}
+
+impl<T: AtomicPayload> AtomicCellCompat<T> {
+    const LOCK_FREE: bool = mem::size_of::<T>() <= mem::size_of::<u64>();
+
+    const_unless_loom! {
+        pub fn new(value: T) -> AtomicCellCompat<T> {
+            if Self::LOCK_FREE {
+                AtomicCellCompat {
+                    bits: AtomicU64::new(unsafe { to_bits_unchecked(value) }),
+                    slot: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            } else {
+                AtomicCellCompat {
+                    bits: AtomicU64::new(0),
+                    slot: UnsafeCell::new(MaybeUninit::new(value)),
+                }
+            }
+        }
+    }
+
+    /// Whether `T` takes the lock-free path, that is whether it fits in
+    /// 8 bytes.
+    pub const fn is_lock_free() -> bool {
+        Self::LOCK_FREE
+    }
+
+    pub fn load(&self) -> T {
+        if Self::LOCK_FREE {
+            unsafe { from_bits_unchecked(self.bits.load(Ordering::Acquire)) }
+        } else {
+            self.with_flag(|slot| *slot)
+        }
+    }
+
+    pub fn store(&self, value: T) {
+        if Self::LOCK_FREE {
+            self.bits
+                .store(unsafe { to_bits_unchecked(value) }, Ordering::Release);
+        } else {
+            self.with_flag(|slot| *slot = value);
+        }
+    }
+
+    pub fn swap(&self, value: T) -> T {
+        if Self::LOCK_FREE {
+            let previous = self
+                .bits
+                .swap(unsafe { to_bits_unchecked(value) }, Ordering::AcqRel);
+            unsafe { from_bits_unchecked(previous) }
+        } else {
+            self.with_flag(|slot| mem::replace(slot, value))
+        }
+    }
+
+    /// Stores `new` if the cell holds `current`, returning the previous value
+    /// either way. The comparison is bitwise on both paths, so for floats
+    /// `0.0` and `-0.0` differ and a NaN matches itself.
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        if Self::LOCK_FREE {
+            let (current, new) = unsafe { (to_bits_unchecked(current), to_bits_unchecked(new)) };
+            self.bits
+                .compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+                .map(|bits| unsafe { from_bits_unchecked(bits) })
+                .map_err(|bits| unsafe { from_bits_unchecked(bits) })
+        } else {
+            self.with_flag(|slot| {
+                let previous = *slot;
+
+                if bytes_of(&previous) == bytes_of(&current) {
+                    *slot = new;
+                    Ok(previous)
+                } else {
+                    Err(previous)
+                }
+            })
+        }
+    }
+
+    /// Runs `f` on the value and keeps what it leaves there, returning `f`'s
+    /// result. On the fallback path `f` runs once, under the flag, so it
+    /// should be short and must not touch the cell. On the lock-free path
+    /// it runs on a copy that is compare-exchanged back, and runs again on a
+    /// fresh copy when another thread wrote the cell in between.
+    pub fn with_lock<R>(&self, mut f: impl FnMut(&mut T) -> R) -> R {
+        if !Self::LOCK_FREE {
+            return self.with_flag(f);
+        }
+
+        let mut current = self.bits.load(Ordering::Acquire);
+
+        loop {
+            let mut value = unsafe { from_bits_unchecked(current) };
+            let result = f(&mut value);
+            let new = unsafe { to_bits_unchecked(value) };
+
+            match self
+                .bits
+                .compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return result,
+                Err(actual) => {
+                    current = actual;
+                    crate::sync::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Replaces the value with `f` of it and returns the old one. `f` may run
+    /// more than once on the lock-free path, as in `AtomicSpinCell::update`.
+    pub fn update(&self, mut f: impl FnMut(T) -> T) -> T {
+        self.with_lock(|value| mem::replace(value, f(*value)))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.load()
+    }
+
+    // The fallback path: `f` gets the slot while the flag is held. Holds
+    // are a copy long, so waiters spin first and only then back off.
+    fn with_flag<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut backoff = SpinWait::new();
+
+        while self
+            .bits
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            backoff.spin();
+        }
+
+        // Releases the flag even if `f` panics; the slot always holds a
+        // whole `T`, since `f` only ever sees it through `&mut T`.
+        struct Release<'a>(&'a AtomicU64);
+
+        impl Drop for Release<'_> {
+            fn drop(&mut self) {
+                self.0.store(0, Ordering::Release);
+            }
+        }
+
+        let _release = Release(&self.bits);
+        f(unsafe { (*self.slot_ptr()).assume_init_mut() })
+    }
+
+    #[cfg(not(loom))]
+    fn slot_ptr(&self) -> *mut MaybeUninit<T> {
+        self.slot.get()
+    }
+
+    // Under loom the slot is reached through `with_mut`, so the model checker
+    // records each access against the flag's happens-before edges.
+    #[cfg(loom)]
+    fn slot_ptr(&self) -> *mut MaybeUninit<T> {
+        self.slot.with_mut(|ptr| ptr)
+    }
+}
+
+// A payload has no padding, so all of its bytes are initialized.
+fn bytes_of<T: AtomicPayload>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+impl<T: AtomicPayload + Default> Default for AtomicCellCompat<T> {
+    fn default() -> AtomicCellCompat<T> {
+        AtomicCellCompat::new(T::default())
+    }
+}
+
+impl<T: AtomicPayload> From<T> for AtomicCellCompat<T> {
+    fn from(value: T) -> AtomicCellCompat<T> {
+        AtomicCellCompat::new(value)
+    }
+}
+
+impl<T: AtomicPayload + std::fmt::Debug> std::fmt::Debug for AtomicCellCompat<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicCellCompat")
+            .field("value", &self.load())
+            .field("lock_free", &Self::LOCK_FREE)
+            .finish()
+    }
+}
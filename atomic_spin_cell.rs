@@ -0,0 +1,230 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::sync::{self, AtomicU64, Ordering};
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+
+/// Payloads `AtomicSpinCell` and `AtomicCellCompat` can hold: `Copy` values
+/// without padding. `AtomicSpinCell` takes those that fit in the 8-byte
+/// atomic word, which is the cutoff because `AtomicU64` is the widest atomic
+/// std offers on every 64-bit target; a wider type fails to compile at its
+/// `new`. `AtomicCellCompat` takes any size and falls back to a flag above
+/// the cutoff. Implemented for the primitive integers including `u128` and
+/// `i128`, floats, `bool`, `char`, `()` and arrays of payloads; a fieldless
+/// `#[repr(u8)]` enum can implement it too.
+///
+/// # Safety
+///
+/// The type must contain no padding or other uninitialized bytes, since its
+/// bytes are copied into the word and compared as they are. Padded types
+/// belong behind a `SpinLock`, whose `load` and `store` give the same
+/// one-call interface.
+pub unsafe trait AtomicPayload: Copy {}
+
+macro_rules! atomic_payload {
+    ($($ty:ty),*) => {
+        $(unsafe impl AtomicPayload for $ty {})*
+    };
+}
+
+atomic_payload!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    bool,
+    char,
+    ()
+);
+
+unsafe impl<T: AtomicPayload, const N: usize> AtomicPayload for [T; N] {}
+
+/// A lock-free cell for a small `Copy` value, backed by a single `AtomicU64`:
+/// the counter-behind-a-lock case without a lock word, a CAS on acquisition
+/// or any spinning on `load` and `store`. Only `update` loops, retrying its
+/// compare-exchange while other writers get in first.
+///
+/// Loads are `Acquire`, stores `Release` and read-modify-writes `AcqRel`, so
+/// a value published through the cell carries the writes made before it,
+/// as a lock release would. The `_with` variants take the orderings
+/// instead, for a statistic that can be `Relaxed` or a flag that needs
+/// `SeqCst`.
+pub struct AtomicSpinCell<T: AtomicPayload> {
+    bits: AtomicU64,
+    _marker: std::marker::PhantomData<T>,
+}
+
+// Sharing the cell hands copies of the value to other threads, so sharing
+// needs `T: Send`, as for a lock; `Send` follows `T` through the marker.
+unsafe impl<T: AtomicPayload + Send> Sync for AtomicSpinCell<T> {}
+
+impl<T: AtomicPayload> AtomicSpinCell<T> {
+    const_unless_loom! {
+        pub fn new(value: T) -> AtomicSpinCell<T> {
+            AtomicSpinCell {
+                bits: AtomicU64::new(to_bits(value)),
+                _marker: std::marker::PhantomData,
+            }
+        }
+    }
+
+    pub fn load(&self) -> T {
+        self.load_with(Ordering::Acquire)
+    }
+
+    pub fn store(&self, value: T) {
+        self.store_with(value, Ordering::Release);
+    }
+
+    pub fn swap(&self, value: T) -> T {
+        self.swap_with(value, Ordering::AcqRel)
+    }
+
+    /// Stores `new` if the cell holds `current`, returning the previous value
+    /// either way. The comparison is bitwise, so for floats `0.0` and `-0.0`
+    /// differ and a NaN matches itself.
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        self.compare_exchange_with(current, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+
+    /// `load` with the given ordering, which may not be `Release` or
+    /// `AcqRel`. The ordering rules, and the panics for the ones that do not
+    /// apply, are those of the std atomics.
+    pub fn load_with(&self, order: Ordering) -> T {
+        from_bits(self.bits.load(order))
+    }
+
+    /// `store` with the given ordering, which may not be `Acquire` or
+    /// `AcqRel`.
+    pub fn store_with(&self, value: T, order: Ordering) {
+        self.bits.store(to_bits(value), order);
+    }
+
+    pub fn swap_with(&self, value: T, order: Ordering) -> T {
+        from_bits(self.bits.swap(to_bits(value), order))
+    }
+
+    /// `compare_exchange` with `success` ordering the exchange and `failure`
+    /// the load that found another value; `failure` may not be `Release`
+    /// or `AcqRel`.
+    pub fn compare_exchange_with(
+        &self,
+        current: T,
+        new: T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<T, T> {
+        self.bits
+            .compare_exchange(to_bits(current), to_bits(new), success, failure)
+            .map(from_bits)
+            .map_err(from_bits)
+    }
+
+    /// Replaces the value with `f` of it and returns the old one. `f` may run
+    /// more than once when other threads write the cell in between.
+    pub fn update(&self, mut f: impl FnMut(T) -> T) -> T {
+        let mut current = self.bits.load(Ordering::Acquire);
+
+        loop {
+            let new = to_bits(f(from_bits(current)));
+
+            match self
+                .bits
+                .compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(previous) => return from_bits(previous),
+                Err(actual) => {
+                    current = actual;
+                    sync::spin_loop();
+                }
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.load()
+    }
+}
+
+// The value's bytes at the start of an otherwise zeroed word; the inline
+// const rejects payloads wider than the word at compile time.
+const fn to_bits<T: AtomicPayload>(value: T) -> u64 {
+    const { assert!(mem::size_of::<T>() <= mem::size_of::<u64>()) };
+    unsafe { to_bits_unchecked(value) }
+}
+
+// The inverse of `to_bits`: every word in the cell came from a valid `T`.
+fn from_bits<T: AtomicPayload>(bits: u64) -> T {
+    unsafe { from_bits_unchecked(bits) }
+}
+
+// `to_bits` without the compile-time check, for `AtomicCellCompat`, which
+// names both paths in one function and takes this one only for payloads
+// that fit. The caller guarantees `T` is at most 8 bytes.
+pub(crate) const unsafe fn to_bits_unchecked<T: AtomicPayload>(value: T) -> u64 {
+    let mut bits = 0u64;
+
+    ptr::copy_nonoverlapping(
+        &value as *const T as *const u8,
+        &mut bits as *mut u64 as *mut u8,
+        mem::size_of::<T>(),
+    );
+
+    bits
+}
+
+// The inverse of `to_bits_unchecked`, under the same guarantee.
+pub(crate) unsafe fn from_bits_unchecked<T: AtomicPayload>(bits: u64) -> T {
+    let mut value = MaybeUninit::<T>::uninit();
+
+    ptr::copy_nonoverlapping(
+        &bits as *const u64 as *const u8,
+        value.as_mut_ptr() as *mut u8,
+        mem::size_of::<T>(),
+    );
+    value.assume_init()
+}
+
+impl<T: AtomicPayload + Default> Default for AtomicSpinCell<T> {
+    fn default() -> AtomicSpinCell<T> {
+        AtomicSpinCell::new(T::default())
+    }
+}
+
+impl<T: AtomicPayload> From<T> for AtomicSpinCell<T> {
+    fn from(value: T) -> AtomicSpinCell<T> {
+        AtomicSpinCell::new(value)
+    }
+}
+
+impl<T: AtomicPayload + std::fmt::Debug> std::fmt::Debug for AtomicSpinCell<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicSpinCell")
+            .field("value", &self.load())
+            .finish()
+    }
+}
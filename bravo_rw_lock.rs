@@ -0,0 +1,291 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(feature = "held_locks")]
+use crate::held_locks;
+use crate::raw_spin_lock::thread_token;
+use crate::sync::{AtomicBool, Ordering, UnsafeCell};
+use crate::{
+    wait_while, CachePadded, GuardMarker, SpinRwLock, SpinRwLockReadGuard, SpinRwLockWriteGuard,
+};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU64;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// Visible-reader slots per lock. Threads take slot `token % READER_SLOTS`,
+// so up to this many reading threads never collide.
+const READER_SLOTS: usize = 64;
+// After a write revokes the bias, readers stay on the shared counter for this
+// many times as long as the revocation took (BRAVO's inhibition multiplier),
+// which bounds the time writers spend revoking to about 1/(N+1).
+const BIAS_INHIBIT_MULTIPLIER: u64 = 9;
+
+/// A reader-biased reader-writer lock (BRAVO). While the bias is on, a reader
+/// marks its own cache-padded slot instead of touching the shared reader
+/// count, so readers on different cores do not contend with each other.
+/// A writer takes the underlying `SpinRwLock`, turns the bias off and waits
+/// for every slot to drain; the bias comes back once readers have gone a
+/// while without a writer.
+///
+/// Each lock carries `READER_SLOTS` (64) padded slots, several kilobytes, so
+/// it is meant for a few hot, read-mostly locks rather than many small ones.
+pub struct BravoRwLock<T> {
+    rw: SpinRwLock<()>,
+    read_bias: AtomicBool,
+    // Nanoseconds since `epoch()` before which readers leave the bias off.
+    // Only a heuristic, so a plain std atomic even under loom.
+    inhibit_until: AtomicU64,
+    readers: Box<[CachePadded<AtomicBool>]>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for BravoRwLock<T> where T: Send + Sync {}
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn now_nanos() -> u64 {
+    epoch().elapsed().as_nanos() as u64
+}
+
+impl<T> BravoRwLock<T> {
+    pub fn new(data: T) -> BravoRwLock<T> {
+        BravoRwLock {
+            rw: SpinRwLock::new(()),
+            read_bias: AtomicBool::new(true),
+            inhibit_until: AtomicU64::new(0),
+            readers: (0..READER_SLOTS)
+                .map(|_| CachePadded::new(AtomicBool::new(false)))
+                .collect(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> BravoRwLockReadGuard<'_, T> {
+        match self.read_biased() {
+            Some(guard) => guard,
+            None => self.read_shared(self.rw.read()),
+        }
+    }
+
+    /// Fails only if a writer holds the lock or is waiting for it.
+    pub fn try_read(&self) -> Option<BravoRwLockReadGuard<'_, T>> {
+        self.read_biased()
+            .or_else(|| self.rw.try_read().map(|guard| self.read_shared(guard)))
+    }
+
+    // A read through the reader's slot, if the bias is on and the slot free.
+    fn read_biased(&self) -> Option<BravoRwLockReadGuard<'_, T>> {
+        if self.read_bias.load(Ordering::Relaxed) {
+            let slot = &self.readers[thread_token() % READER_SLOTS];
+
+            // Publishing the slot and then re-checking the bias pairs with a
+            // writer clearing the bias and then scanning the slots: with both
+            // sides SeqCst, either the writer sees the slot or the reader sees
+            // the bias gone.
+            if slot
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                if self.read_bias.load(Ordering::SeqCst) {
+                    // Recorded under the inner lock's address, as reads
+                    // through it are.
+                    #[cfg(feature = "held_locks")]
+                    held_locks::acquired(&self.rw as *const SpinRwLock<()> as usize, None);
+                    return Some(BravoRwLockReadGuard {
+                        lock: self,
+                        inner: ReadState::Slot(slot),
+                        _marker: PhantomData,
+                    });
+                }
+
+                slot.store(false, Ordering::Release);
+            }
+        }
+
+        None
+    }
+
+    fn read_shared<'a>(
+        &'a self,
+        guard: SpinRwLockReadGuard<'a, ()>,
+    ) -> BravoRwLockReadGuard<'a, T> {
+        // No writer can be revoking while the shared read lock is held.
+        if !self.read_bias.load(Ordering::Relaxed)
+            && now_nanos() >= self.inhibit_until.load(Ordering::Relaxed)
+        {
+            self.read_bias.store(true, Ordering::Relaxed);
+        }
+
+        BravoRwLockReadGuard {
+            lock: self,
+            inner: ReadState::Shared { _guard: guard },
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn write(&self) -> BravoRwLockWriteGuard<'_, T> {
+        let guard = self.rw.write();
+
+        if self.read_bias.load(Ordering::Relaxed) {
+            let start = now_nanos();
+            self.read_bias.store(false, Ordering::SeqCst);
+
+            for slot in self.readers.iter() {
+                wait_while(|| slot.load(Ordering::SeqCst));
+            }
+
+            let now = now_nanos();
+            self.inhibit_until.store(
+                now + (now - start) * BIAS_INHIBIT_MULTIPLIER,
+                Ordering::Relaxed,
+            );
+        }
+
+        BravoRwLockWriteGuard {
+            lock: self,
+            _guard: guard,
+        }
+    }
+
+    /// Fails if the underlying lock is held, or if a reader holds one of the
+    /// slots. A failed attempt leaves the bias as it was.
+    pub fn try_write(&self) -> Option<BravoRwLockWriteGuard<'_, T>> {
+        let guard = self.rw.try_write()?;
+
+        if self.read_bias.load(Ordering::Relaxed) {
+            self.read_bias.store(false, Ordering::SeqCst);
+
+            if self.readers.iter().any(|slot| slot.load(Ordering::SeqCst)) {
+                // Readers that saw the bias gone are waiting on the inner
+                // lock, which is still held, so turning it back on is safe.
+                self.read_bias.store(true, Ordering::Relaxed);
+                return None;
+            }
+        }
+
+        Some(BravoRwLockWriteGuard {
+            lock: self,
+            _guard: guard,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data_ptr() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr_shared(&self) -> *const T {
+        self.data.get()
+    }
+
+    #[cfg(loom)]
+    fn data_ptr(&self) -> *mut T {
+        self.data.with_mut(|ptr| ptr)
+    }
+
+    #[cfg(loom)]
+    fn data_ptr_shared(&self) -> *const T {
+        self.data.with(|ptr| ptr)
+    }
+}
+
+impl<T: Default> Default for BravoRwLock<T> {
+    fn default() -> BravoRwLock<T> {
+        BravoRwLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for BravoRwLock<T> {
+    fn from(data: T) -> BravoRwLock<T> {
+        BravoRwLock::new(data)
+    }
+}
+
+enum ReadState<'a> {
+    // Read through the bias: the reader's slot, cleared on drop.
+    Slot(&'a AtomicBool),
+    // Read through the underlying lock, released when the guard drops.
+    Shared { _guard: SpinRwLockReadGuard<'a, ()> },
+}
+
+pub struct BravoRwLockReadGuard<'a, T> {
+    lock: &'a BravoRwLock<T>,
+    inner: ReadState<'a>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for BravoRwLockReadGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for BravoRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr_shared() }
+    }
+}
+
+forward_to_data!(BravoRwLockReadGuard<T>);
+
+impl<T> Drop for BravoRwLockReadGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if let ReadState::Slot(slot) = self.inner {
+            #[cfg(feature = "held_locks")]
+            held_locks::released(&self.lock.rw as *const SpinRwLock<()> as usize);
+            slot.store(false, Ordering::Release);
+        }
+    }
+}
+
+pub struct BravoRwLockWriteGuard<'a, T> {
+    lock: &'a BravoRwLock<T>,
+    _guard: SpinRwLockWriteGuard<'a, ()>,
+}
+
+unsafe impl<T> Sync for BravoRwLockWriteGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for BravoRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
+
+impl<T> DerefMut for BravoRwLockWriteGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data_ptr() }
+    }
+}
+
+forward_to_data!(BravoRwLockWriteGuard<T>);
@@ -0,0 +1,86 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+macro_rules! alignment {
+    ($($name:ident = $align:literal),* $(,)?) => {
+        $(
+            #[doc = concat!("Zero-sized marker with ", stringify!($align), "-byte alignment, for `CachePadded`.")]
+            #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+            #[repr(align($align))]
+            pub struct $name;
+        )*
+    };
+}
+
+alignment!(Align32 = 32, Align64 = 64, Align128 = 128, Align256 = 256);
+
+/// The destructive-interference size of the target. x86_64 prefetches cache
+/// lines in adjacent pairs and recent aarch64 and powerpc64 parts use 128-byte
+/// lines, so those get 128; everything else gets 64.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64"))]
+pub type CacheLine = Align128;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64")))]
+pub type CacheLine = Align64;
+
+/// Aligns and pads `T` to `A` (a cache line by default), so that it never
+/// shares a line with neighbouring data, as in `SpinLock<CachePadded<T>>`
+/// or `[CachePadded<SpinLock<T>>; N]`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct CachePadded<T, A = CacheLine> {
+    _align: [A; 0],
+    value: T,
+}
+
+impl<T, A> CachePadded<T, A> {
+    pub const fn new(value: T) -> CachePadded<T, A> {
+        CachePadded { _align: [], value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T, A> Deref for CachePadded<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, A> DerefMut for CachePadded<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T, A> From<T> for CachePadded<T, A> {
+    fn from(value: T) -> CachePadded<T, A> {
+        CachePadded::new(value)
+    }
+}
+
+impl<T: fmt::Debug, A> fmt::Debug for CachePadded<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachePadded").field("value", &self.value).finish()
+    }
+}
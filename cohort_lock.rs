@@ -0,0 +1,244 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(all(feature = "numa", target_os = "linux"))]
+use crate::numa;
+use crate::sync::{AtomicBool, AtomicUsize, Ordering, UnsafeCell};
+use crate::{CachePadded, GuardMarker, RawSpinLock};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+// Consecutive handoffs within one node before the global lock has to be
+// released, so threads on other nodes are not starved.
+const MAX_LOCAL_HANDOFFS: usize = 64;
+
+// The per-node half of a `CohortLock`. `owns_global` and `handoffs` are only
+// touched with `lock` held.
+struct Cohort {
+    lock: RawSpinLock,
+    // Threads of this node queued on `lock`.
+    waiters: AtomicUsize,
+    // Whether this node's cohort holds the global lock, passed from one local
+    // holder to the next.
+    owns_global: AtomicBool,
+    handoffs: AtomicUsize,
+}
+
+/// A NUMA-aware cohort lock: one local lock per node plus a global lock.
+/// A thread takes its node's local lock, then the global lock unless a
+/// previous holder on the same node passed it along. On release, if another
+/// thread of the node is waiting the global lock stays with the node, so
+/// the protected data keeps bouncing between cores that share a cache
+/// instead of crossing the interconnect. After `MAX_LOCAL_HANDOFFS` (64)
+/// local handoffs in a row the global lock is released with a fair unlock,
+/// which hands it to a waiting node.
+///
+/// Nodes are detected with `getcpu` under the `numa` feature on Linux;
+/// otherwise there is a single node and this is a plain lock with one extra
+/// acquisition.
+pub struct CohortLock<T> {
+    global: RawSpinLock,
+    cohorts: Box<[CachePadded<Cohort>]>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for CohortLock<T> where T: Send {}
+
+impl<T> CohortLock<T> {
+    /// One cohort per NUMA node of the machine.
+    pub fn new(data: T) -> CohortLock<T> {
+        #[cfg(all(feature = "numa", target_os = "linux"))]
+        let nodes = numa::node_count();
+        #[cfg(not(all(feature = "numa", target_os = "linux")))]
+        let nodes = 1;
+
+        CohortLock::with_nodes(nodes, data)
+    }
+
+    /// `nodes` cohorts, for callers that place threads themselves with
+    /// `lock_on_node`. Panics if `nodes` is 0.
+    pub fn with_nodes(nodes: usize, data: T) -> CohortLock<T> {
+        assert!(nodes > 0, "a CohortLock needs at least one node");
+
+        CohortLock {
+            global: RawSpinLock::new(),
+            cohorts: (0..nodes)
+                .map(|_| {
+                    CachePadded::new(Cohort {
+                        lock: RawSpinLock::new(),
+                        waiters: AtomicUsize::new(0),
+                        owns_global: AtomicBool::new(false),
+                        handoffs: AtomicUsize::new(0),
+                    })
+                })
+                .collect(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Locks through the cohort of the node the calling thread runs on.
+    pub fn lock(&self) -> CohortLockGuard<'_, T> {
+        self.lock_on_node(current_node())
+    }
+
+    /// Makes a single attempt through the calling thread's cohort. Fails if
+    /// the cohort's local lock is held, or if the global lock is needed and
+    /// held.
+    pub fn try_lock(&self) -> Option<CohortLockGuard<'_, T>> {
+        let node = current_node() % self.cohorts.len();
+        let cohort = &self.cohorts[node];
+
+        if !cohort.lock.try_lock() {
+            return None;
+        }
+
+        if !cohort.owns_global.load(Ordering::Relaxed) {
+            if !self.global.try_lock_nested() {
+                unsafe { cohort.lock.unlock() };
+                return None;
+            }
+
+            cohort.owns_global.store(true, Ordering::Relaxed);
+        }
+
+        Some(CohortLockGuard {
+            lock: self,
+            node,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Locks through the cohort of `node` (modulo the node count), for
+    /// threads pinned to a known node.
+    pub fn lock_on_node(&self, node: usize) -> CohortLockGuard<'_, T> {
+        let node = node % self.cohorts.len();
+        let cohort = &self.cohorts[node];
+
+        cohort.waiters.fetch_add(1, Ordering::Relaxed);
+        cohort.lock.lock();
+        cohort.waiters.fetch_sub(1, Ordering::Relaxed);
+
+        if !cohort.owns_global.load(Ordering::Relaxed) {
+            self.global.lock_nested();
+            cohort.owns_global.store(true, Ordering::Relaxed);
+        }
+
+        CohortLockGuard {
+            lock: self,
+            node,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The number of cohorts.
+    pub fn nodes(&self) -> usize {
+        self.cohorts.len()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data_ptr() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    fn unlock(&self, node: usize) {
+        let cohort = &self.cohorts[node];
+        let handoffs = cohort.handoffs.load(Ordering::Relaxed);
+
+        // A counted waiter cannot give up, so it will take the local lock and
+        // find the global one already held.
+        if cohort.waiters.load(Ordering::Relaxed) != 0 && handoffs < MAX_LOCAL_HANDOFFS {
+            cohort.handoffs.store(handoffs + 1, Ordering::Relaxed);
+        } else {
+            cohort.handoffs.store(0, Ordering::Relaxed);
+            cohort.owns_global.store(false, Ordering::Relaxed);
+            unsafe { self.global.unlock_fair_nested() };
+        }
+
+        unsafe { cohort.lock.unlock() };
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    #[cfg(loom)]
+    fn data_ptr(&self) -> *mut T {
+        self.data.with_mut(|ptr| ptr)
+    }
+}
+
+fn current_node() -> usize {
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    return numa::current_node();
+    #[cfg(not(all(feature = "numa", target_os = "linux")))]
+    return 0;
+}
+
+impl<T: Default> Default for CohortLock<T> {
+    fn default() -> CohortLock<T> {
+        CohortLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for CohortLock<T> {
+    fn from(data: T) -> CohortLock<T> {
+        CohortLock::new(data)
+    }
+}
+
+pub struct CohortLockGuard<'a, T> {
+    lock: &'a CohortLock<T>,
+    node: usize,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for CohortLockGuard<'_, T> where T: Sync {}
+
+impl<T> CohortLockGuard<'_, T> {
+    /// The cohort the lock was taken through.
+    pub fn node(&self) -> usize {
+        self.node
+    }
+}
+
+impl<T> Deref for CohortLockGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
+
+impl<T> DerefMut for CohortLockGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data_ptr() }
+    }
+}
+
+forward_to_data!(CohortLockGuard<T>);
+
+impl<T> Drop for CohortLockGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.unlock(self.node);
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Drop-in replacements for `std::sync` types, built on `SpinLock`.
+//!
+//! Switching an existing codebase over is a matter of changing
+//! `use std::sync::Mutex` to `use spin_lock::compat::Mutex`. The `spin`
+//! module does the same for the `spin` crate's types.
+pub mod spin;
+
+use crate::{SpinLock, SpinLockGuard, TryLockError as SpinTryLockError};
+use std::fmt;
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+pub type MutexGuard<'a, T> = SpinLockGuard<'a, T>;
+
+/// A spin lock with the API and poisoning semantics of `std::sync::Mutex`.
+pub struct Mutex<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> Mutex<T> {
+    const_unless_loom! {
+        pub fn new(t: T) -> Mutex<T> {
+            Mutex {
+                inner: SpinLock::new(t),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.poison_result(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(SpinTryLockError::Poisoned(err)) => Err(TryLockError::Poisoned(err)),
+            Err(_) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn clear_poison(&self) {
+        self.inner.clear_poison();
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.inner.is_poisoned();
+        let data = self.inner.get_mut();
+
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.inner.is_poisoned();
+        let data = self.inner.into_inner();
+
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn poison_result<'a>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        if self.inner.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(t: T) -> Mutex<T> {
+        Mutex::new(t)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+
+        match self.inner.try_lock() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(SpinTryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(_) => d.field("data", &format_args!("<locked>")),
+        };
+
+        d.field("poisoned", &self.is_poisoned()).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,523 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Stand-ins for the `spin` crate's (0.9) `Mutex`, `RwLock`, `Once` and
+//! `Lazy`, built on `SpinLock`, `SpinRwLock` and `SpinOnceCell`.
+//!
+//! The names, signatures and semantics follow `spin`: `lock()` hands back a
+//! guard with no poisoning, `try_lock()` an `Option`, `Once::call_once` a
+//! `&T`, and a panicking initializer poisons its `Once` for good. Waiters
+//! only issue spin hints, as with `spin`'s default `Spin` relax strategy,
+//! instead of yielding and sleeping. Migrating is a matter of changing
+//! `use spin::Mutex` to `use spin_lock::compat::spin::Mutex`; this crate
+//! still needs `std`.
+//!
+//! ```
+//! use spin_lock::compat::spin;
+//!
+//! static COUNTER: spin::Mutex<u32> = spin::Mutex::new(0);
+//! static CONFIG: spin::Once<String> = spin::Once::new();
+//!
+//! *COUNTER.lock() += 1;
+//! assert_eq!(CONFIG.call_once(|| "ready".to_string()), "ready");
+//! ```
+use crate::{
+    SpinLock, SpinLockConfig, SpinLockGuard, SpinOnceCell, SpinRwLock, SpinRwLockReadGuard,
+    SpinRwLockUpgradableGuard, SpinRwLockWriteGuard, Strategy,
+};
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The backoff of `spin`'s `Spin` relax strategy: spin hints and nothing else.
+const SPIN: SpinLockConfig = SpinLockConfig {
+    strategy: Strategy::Busy,
+    ..SpinLockConfig::DEFAULT
+};
+
+/// `spin::Mutex`: a `SpinLock` whose waiters only spin, without poisoning.
+pub struct Mutex<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> Mutex<T> {
+    const_unless_loom! {
+        pub fn new(value: T) -> Mutex<T> {
+            Mutex {
+                inner: SpinLock::with_strategy(value, Strategy::Busy),
+            }
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        MutexGuard(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock_n(0).map(MutexGuard)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.raw_.is_locked()
+    }
+
+    /// Releases the lock without a guard, as after `MutexGuard::leak`.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held, and its guard, if any, must not be used or
+    /// dropped afterwards.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.raw_.unlock();
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(value: T) -> Mutex<T> {
+        Mutex::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+
+        d.finish()
+    }
+}
+
+pub struct MutexGuard<'a, T>(SpinLockGuard<'a, T>);
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Keeps the lock held for good and hands out the data for as long as
+    /// the lock lives; `Mutex::force_unlock` is the only way back.
+    pub fn leak(this: Self) -> &'a mut T {
+        let mut this = std::mem::ManuallyDrop::new(this);
+        let data: *mut T = &mut *this.0;
+        unsafe { &mut *data }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+forward_to_data!(MutexGuard<T>);
+
+/// `spin::RwLock`: a `SpinRwLock` whose waiters only spin. Like `spin`'s,
+/// an upgradable read keeps new readers out.
+pub struct RwLock<T> {
+    inner: SpinRwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    const_unless_loom! {
+        pub fn new(value: T) -> RwLock<T> {
+            RwLock {
+                inner: SpinRwLock::new(value),
+            }
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        spin_until(|| self.try_read())
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        spin_until(|| self.try_write())
+    }
+
+    pub fn upgradeable_read(&self) -> RwLockUpgradableGuard<'_, T> {
+        spin_until(|| self.try_upgradeable_read())
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.inner.try_read().map(RwLockReadGuard)
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.inner.try_write().map(RwLockWriteGuard)
+    }
+
+    pub fn try_upgradeable_read(&self) -> Option<RwLockUpgradableGuard<'_, T>> {
+        self.inner.try_upgradeable_read().map(RwLockUpgradableGuard)
+    }
+
+    /// Readers at this instant, counting an upgradable one; only a hint.
+    pub fn reader_count(&self) -> usize {
+        self.inner.reader_count()
+    }
+
+    /// 1 while a writer holds the lock, 0 otherwise; only a hint.
+    pub fn writer_count(&self) -> usize {
+        self.inner.writer_count()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    fn from(value: T) -> RwLock<T> {
+        RwLock::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+
+        d.finish()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T>(SpinRwLockReadGuard<'a, T>);
+
+impl<'a, T> RwLockReadGuard<'a, T> {
+    /// Keeps the read held for good, so no writer ever gets in again.
+    pub fn leak(this: Self) -> &'a T {
+        let this = std::mem::ManuallyDrop::new(this);
+        let data: *const T = &*this.0;
+        unsafe { &*data }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+forward_to_data!(RwLockReadGuard<T>);
+
+pub struct RwLockWriteGuard<'a, T>(SpinRwLockWriteGuard<'a, T>);
+
+impl<'a, T> RwLockWriteGuard<'a, T> {
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        RwLockReadGuard(SpinRwLockWriteGuard::downgrade(self.0))
+    }
+
+    pub fn downgrade_to_upgradeable(self) -> RwLockUpgradableGuard<'a, T> {
+        RwLockUpgradableGuard(SpinRwLockWriteGuard::downgrade_to_upgradable(self.0))
+    }
+
+    /// Keeps the write held for good.
+    pub fn leak(this: Self) -> &'a mut T {
+        let mut this = std::mem::ManuallyDrop::new(this);
+        let data: *mut T = &mut *this.0;
+        unsafe { &mut *data }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+forward_to_data!(RwLockWriteGuard<T>);
+
+pub struct RwLockUpgradableGuard<'a, T>(SpinRwLockUpgradableGuard<'a, T>);
+
+impl<'a, T> RwLockUpgradableGuard<'a, T> {
+    /// Spins until the other readers have left, then becomes a write.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let mut guard = self;
+
+        loop {
+            match guard.try_upgrade() {
+                Ok(write) => return write,
+                Err(upgradable) => guard = upgradable,
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        SpinRwLockUpgradableGuard::try_upgrade(self.0)
+            .map(RwLockWriteGuard)
+            .map_err(RwLockUpgradableGuard)
+    }
+
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        RwLockReadGuard(SpinRwLockUpgradableGuard::downgrade(self.0))
+    }
+
+    /// Keeps the upgradable read held for good.
+    pub fn leak(this: Self) -> &'a T {
+        let this = std::mem::ManuallyDrop::new(this);
+        let data: *const T = &*this.0;
+        unsafe { &*data }
+    }
+}
+
+impl<T> Deref for RwLockUpgradableGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+forward_to_data!(RwLockUpgradableGuard<T>);
+
+/// `spin::Once`: a `SpinOnceCell` that a panicking initializer poisons.
+/// Every later `call_once`, `poll` or `wait` then panics, where the cell on
+/// its own would let the next caller try again.
+pub struct Once<T = ()> {
+    cell: SpinOnceCell<T>,
+    panicked: AtomicBool,
+}
+
+impl<T> Once<T> {
+    #[cfg(not(loom))]
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: Once<T> = Once::new();
+
+    const_unless_loom! {
+        pub fn new() -> Once<T> {
+            Once {
+                cell: SpinOnceCell::new(),
+                panicked: AtomicBool::new(false),
+            }
+        }
+    }
+
+    const_unless_loom! {
+        /// A `Once` that has already run, holding `value`.
+        pub fn initialized(value: T) -> Once<T> {
+            Once {
+                cell: SpinOnceCell::with_value(value),
+                panicked: AtomicBool::new(false),
+            }
+        }
+    }
+
+    /// Runs `f` if no call has completed yet, spinning while another
+    /// thread's runs, and returns the value.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self.try_call_once(|| Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like `call_once`, but an error from `f` is returned and leaves the
+    /// `Once` to run again.
+    pub fn try_call_once<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(value) = self.poll() {
+            return Ok(value);
+        }
+
+        self.cell.get_or_try_init_with(SPIN, || {
+            // The flag is set before the cell resets, so a waiter that gets
+            // in next sees it.
+            struct Poison<'a>(&'a AtomicBool);
+
+            impl Drop for Poison<'_> {
+                fn drop(&mut self) {
+                    self.0.store(true, Ordering::Release);
+                }
+            }
+
+            self.check_poison();
+            let poison = Poison(&self.panicked);
+            let result = f();
+            std::mem::forget(poison);
+            result
+        })
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.cell.get()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.cell.get_mut()
+    }
+
+    /// The value if a call has completed, without waiting for one in
+    /// progress.
+    pub fn poll(&self) -> Option<&T> {
+        self.check_poison();
+        self.cell.get()
+    }
+
+    /// Spins until another thread's call completes.
+    pub fn wait(&self) -> &T {
+        spin_until(|| self.poll())
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.cell.get().is_some()
+    }
+
+    pub fn try_into_inner(self) -> Option<T> {
+        self.cell.into_inner()
+    }
+
+    fn check_poison(&self) {
+        if self.panicked.load(Ordering::Acquire) {
+            panic!("Once previously poisoned by a panicked initializer");
+        }
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Once<T> {
+        Once::new()
+    }
+}
+
+impl<T> From<T> for Once<T> {
+    fn from(value: T) -> Once<T> {
+        Once::initialized(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Once");
+
+        match self.get() {
+            Some(value) => d.field("data", value),
+            None => d.field("data", &format_args!("<uninitialized>")),
+        };
+
+        d.finish()
+    }
+}
+
+/// `spin::Lazy`: a value computed by `F` on first access, through a `Once`.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// `init` is only taken inside `call_once`, by the one thread running it.
+unsafe impl<T, F: Send> Sync for Lazy<T, F> where Once<T>: Sync {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    const_unless_loom! {
+        pub fn new(f: F) -> Lazy<T, F> {
+            Lazy {
+                cell: Once::new(),
+                init: Cell::new(Some(f)),
+            }
+        }
+    }
+
+    /// The value, computing it first if no access has yet. Panics if an
+    /// earlier computation did.
+    pub fn force(this: &Self) -> &T {
+        this.cell.call_once(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    fn default() -> Lazy<T> {
+        Lazy::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lazy")
+            .field("cell", &self.cell)
+            .finish_non_exhaustive()
+    }
+}
+
+// Retries `attempt` with a spin hint in between until it succeeds, as
+// `spin`'s own locks do.
+fn spin_until<R>(mut attempt: impl FnMut() -> Option<R>) -> R {
+    loop {
+        if let Some(value) = attempt() {
+            return value;
+        }
+
+        std::hint::spin_loop();
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread::{self, ThreadId};
+
+// Number of wait iterations after which a waiter publishes itself in the
+// wait-for graph and looks for a cycle; repeated at every multiple.
+const DEADLOCK_CHECK_ATTEMPTS: usize = 10_000;
+
+#[derive(Default)]
+struct Registry {
+    held: HashMap<usize, ThreadId>,
+    waiting: HashMap<ThreadId, (usize, String)>,
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+    REGISTRY
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn thread_label() -> String {
+    let current = thread::current();
+
+    match current.name() {
+        Some(name) => format!("'{}' ({:?})", name, current.id()),
+        None => format!("{:?}", current.id()),
+    }
+}
+
+pub(crate) fn acquired(lock: usize) {
+    registry().held.insert(lock, thread::current().id());
+}
+
+pub(crate) fn released(lock: usize) {
+    registry().held.remove(&lock);
+}
+
+pub(crate) struct Watch {
+    lock: usize,
+    attempts: usize,
+    registered: bool,
+}
+
+impl Watch {
+    pub(crate) fn begin(lock: usize) -> Watch {
+        Watch {
+            lock,
+            attempts: 0,
+            registered: false,
+        }
+    }
+
+    pub(crate) fn spin(&mut self) {
+        self.attempts += 1;
+
+        if self.attempts.is_multiple_of(DEADLOCK_CHECK_ATTEMPTS) {
+            self.check();
+        }
+    }
+
+    #[cold]
+    fn check(&mut self) {
+        let me = thread::current().id();
+        let mut registry = registry();
+
+        if !self.registered {
+            registry.waiting.insert(me, (self.lock, thread_label()));
+            self.registered = true;
+        }
+
+        if let Some(report) = find_cycle(&registry, me) {
+            registry.waiting.remove(&me);
+            self.registered = false;
+            drop(registry);
+            panic!("{}", report);
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if self.registered {
+            registry().waiting.remove(&thread::current().id());
+        }
+    }
+}
+
+// Follows waiter -> lock -> holder edges starting from `start`; a path that
+// comes back to `start` is a deadlock.
+fn find_cycle(registry: &Registry, start: ThreadId) -> Option<String> {
+    let mut report = String::from("deadlock detected:");
+    let mut thread = start;
+
+    for _ in 0..registry.waiting.len() {
+        let (lock, label) = registry.waiting.get(&thread)?;
+        let owner = *registry.held.get(lock)?;
+
+        let _ = write!(report, "\n  thread {} waits for lock {:#x}", label, lock);
+
+        if let Some((_, owner_label)) = registry.waiting.get(&owner) {
+            let _ = write!(report, " held by thread {}", owner_label);
+        }
+
+        if owner == start {
+            return Some(report);
+        }
+
+        thread = owner;
+    }
+
+    None
+}
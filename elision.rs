@@ -0,0 +1,110 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::arch::asm;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// What `xbegin` leaves in eax when the transaction has started; on an abort
+// execution resumes after it with the abort status there instead.
+const XBEGIN_STARTED: u32 = !0;
+
+// Abort status bits: the hardware thinks a retry may succeed, and the abort
+// came from our own `xabort`.
+const XABORT_RETRY: u32 = 1 << 1;
+const XABORT_EXPLICIT: u32 = 1 << 0;
+
+// Conflict aborts retried before the caller falls back to the real lock.
+const ELISION_ATTEMPTS: usize = 3;
+
+// Whether the CPU has RTM. The detection macro caches its CPUID result, so
+// this is a load and a bit test; `xtest` itself faults without RTM.
+#[inline]
+pub(crate) fn supported() -> bool {
+    std::is_x86_feature_detected!("rtm")
+}
+
+// Starts a transaction that has read the lock word and found it zero (free),
+// so any thread that really takes the lock aborts it. Returns false, outside any
+// transaction, once the lock is seen held, an abort is not worth retrying or
+// the attempts run out.
+#[inline]
+pub(crate) fn begin(lock: &AtomicU8) -> bool {
+    if !supported() {
+        return false;
+    }
+
+    for _ in 0..ELISION_ATTEMPTS {
+        let status = xbegin();
+
+        if status == XBEGIN_STARTED {
+            if lock.load(Ordering::Relaxed) == 0 {
+                return true;
+            }
+
+            // Resumes after `xbegin` above with XABORT_EXPLICIT set.
+            xabort();
+        }
+
+        if status & XABORT_EXPLICIT != 0 || status & XABORT_RETRY == 0 {
+            break;
+        }
+    }
+
+    false
+}
+
+// Whether the caller is inside a transaction started by `begin`.
+#[inline]
+pub(crate) fn active() -> bool {
+    supported() && xtest()
+}
+
+// Commits the innermost transaction.
+#[inline]
+pub(crate) fn end() {
+    unsafe {
+        asm!("xend", options(nostack));
+    }
+}
+
+#[inline(always)]
+fn xbegin() -> u32 {
+    let mut status = XBEGIN_STARTED;
+
+    unsafe {
+        asm!("xbegin 2f", "2:", inout("eax") status, options(nostack));
+    }
+
+    status
+}
+
+#[inline(always)]
+fn xabort() {
+    unsafe {
+        asm!("xabort 0xff", options(nostack));
+    }
+}
+
+#[inline(always)]
+fn xtest() -> bool {
+    let in_transaction: u8;
+
+    unsafe {
+        asm!("xtest", "setnz {0}", out(reg_byte) in_transaction, options(nomem, nostack));
+    }
+
+    in_transaction != 0
+}
@@ -0,0 +1,132 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+// A C interface to `RawSpinLock`, for sharing one lock between C and Rust
+// code. Handles are opaque and heap-allocated, every call returns a status
+// code instead of unwinding into C, and `spin_lock.h` declares the same
+// functions for the C side.
+
+use crate::RawSpinLock;
+use std::ffi::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// The call succeeded; for `spinlock_try_lock`, the lock was acquired.
+pub const SPINLOCK_OK: c_int = 0;
+/// `spinlock_try_lock` found the lock held.
+pub const SPINLOCK_BUSY: c_int = 1;
+/// The handle was null.
+pub const SPINLOCK_NULL: c_int = -1;
+/// `spinlock_unlock` was called on a lock that is not locked.
+pub const SPINLOCK_NOT_LOCKED: c_int = -2;
+/// A panic (from a feature such as `deadlock_detection`) was caught at the
+/// boundary; the lock is in the state it was in before the call.
+pub const SPINLOCK_PANICKED: c_int = -3;
+
+/// The lock behind a C handle. Opaque to C, which only ever sees a pointer
+/// to it; the data it protects is whatever the caller decides.
+pub struct CSpinLock {
+    raw: RawSpinLock,
+}
+
+// Unwinding out of an `extern "C"` function aborts, so each call catches
+// panics and reports them as `SPINLOCK_PANICKED`. The lock's own state
+// carries no invariant a panic could leave half-updated.
+unsafe fn guarded(lock: *const CSpinLock, f: impl FnOnce(&RawSpinLock) -> c_int) -> c_int {
+    match lock.as_ref() {
+        Some(lock) => {
+            panic::catch_unwind(AssertUnwindSafe(|| f(&lock.raw))).unwrap_or(SPINLOCK_PANICKED)
+        }
+        None => SPINLOCK_NULL,
+    }
+}
+
+/// Allocates a new, unlocked lock. Returns null if construction panicked.
+/// Release it with `spinlock_free`.
+#[no_mangle]
+pub extern "C" fn spinlock_new() -> *mut CSpinLock {
+    panic::catch_unwind(|| {
+        Box::into_raw(Box::new(CSpinLock {
+            raw: RawSpinLock::new(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Blocks until the lock is acquired. Returns `SPINLOCK_OK`, or
+/// `SPINLOCK_NULL` for a null handle.
+///
+/// # Safety
+///
+/// `lock` must be null or a live handle from `spinlock_new`.
+#[no_mangle]
+pub unsafe extern "C" fn spinlock_lock(lock: *const CSpinLock) -> c_int {
+    guarded(lock, |raw| {
+        raw.lock();
+        SPINLOCK_OK
+    })
+}
+
+/// Makes a single acquisition attempt. Returns `SPINLOCK_OK` if the lock
+/// was acquired, `SPINLOCK_BUSY` if it is held, or `SPINLOCK_NULL`.
+///
+/// # Safety
+///
+/// As for `spinlock_lock`.
+#[no_mangle]
+pub unsafe extern "C" fn spinlock_try_lock(lock: *const CSpinLock) -> c_int {
+    guarded(lock, |raw| {
+        if raw.try_lock() {
+            SPINLOCK_OK
+        } else {
+            SPINLOCK_BUSY
+        }
+    })
+}
+
+/// Releases the lock. Returns `SPINLOCK_OK`, `SPINLOCK_NOT_LOCKED` if the
+/// lock is free, or `SPINLOCK_NULL`.
+///
+/// # Safety
+///
+/// As for `spinlock_lock`, and the calling thread must be the one holding
+/// the lock. Only a free lock is caught; releasing one held by another
+/// thread lets both race on the data.
+#[no_mangle]
+pub unsafe extern "C" fn spinlock_unlock(lock: *const CSpinLock) -> c_int {
+    guarded(lock, |raw| {
+        // An elided section never set the flag, but is still held.
+        if !raw.is_locked() && !raw.elided() {
+            return SPINLOCK_NOT_LOCKED;
+        }
+
+        unsafe { raw.unlock() };
+        SPINLOCK_OK
+    })
+}
+
+/// Frees a handle from `spinlock_new`. A null handle is ignored.
+///
+/// # Safety
+///
+/// `lock` must be null or a live handle from `spinlock_new` that no thread
+/// holds or is waiting for, and it must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn spinlock_free(lock: *mut CSpinLock) {
+    if !lock.is_null() {
+        drop(unsafe { Box::from_raw(lock) });
+    }
+}
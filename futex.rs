@@ -0,0 +1,68 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::ffi::{c_int, c_long};
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+#[cfg(target_arch = "x86_64")]
+const SYS_FUTEX: c_long = 202;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64"))]
+const SYS_FUTEX: c_long = 98;
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+const SYS_FUTEX: c_long = 240;
+
+const FUTEX_WAIT_PRIVATE: c_int = 128;
+const FUTEX_WAKE_PRIVATE: c_int = 129;
+
+#[repr(C)]
+struct Timespec {
+    tv_sec: c_long,
+    tv_nsec: c_long,
+}
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+// Blocks while `word` still holds `expected`, until a `wake_one` on it or
+// `timeout`. Returns early (EAGAIN) if the value has already moved on, and
+// spurious returns are fine: callers re-check the lock either way.
+pub(crate) fn wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = Timespec {
+        tv_sec: timeout.as_secs() as c_long,
+        tv_nsec: timeout.subsec_nanos() as c_long,
+    };
+
+    unsafe {
+        syscall(
+            SYS_FUTEX,
+            word.as_ptr(),
+            FUTEX_WAIT_PRIVATE,
+            expected,
+            &timeout as *const Timespec,
+            ptr::null::<u32>(),
+            0u32,
+        );
+    }
+}
+
+pub(crate) fn wake_one(word: &AtomicU32) {
+    unsafe {
+        syscall(SYS_FUTEX, word.as_ptr(), FUTEX_WAKE_PRIVATE, 1u32);
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(not(feature = "send_guard"))]
+use std::cell::RefCell;
+#[cfg(feature = "send_guard")]
+use std::sync::{Mutex, PoisonError};
+
+type Held = Vec<(usize, Option<&'static str>)>;
+
+// The locks the current thread holds, oldest first: the lock's address, and
+// its name for a `SpinLock::new_named` lock. Locks taken twice (shared reads)
+// appear twice. A `Vec`, since a thread rarely holds more than a few.
+#[cfg(not(feature = "send_guard"))]
+thread_local! {
+    static HELD: RefCell<Held> = const { RefCell::new(Vec::new()) };
+}
+
+// With `send_guard` a guard may be dropped on another thread than the one
+// that took it, so the list is kept for the whole process instead: it
+// records which guards exist, not which thread owns them.
+#[cfg(feature = "send_guard")]
+static HELD: Mutex<Held> = Mutex::new(Vec::new());
+
+// `try_with`, so guards dropped by other thread-locals' destructors after
+// this one is gone do not panic.
+#[cfg(not(feature = "send_guard"))]
+fn with_held<R>(f: impl FnOnce(&mut Held) -> R) -> Option<R> {
+    HELD.try_with(|held| f(&mut held.borrow_mut())).ok()
+}
+
+#[cfg(feature = "send_guard")]
+fn with_held<R>(f: impl FnOnce(&mut Held) -> R) -> Option<R> {
+    let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+    Some(f(&mut held))
+}
+
+pub(crate) fn acquired(lock: usize, name: Option<&'static str>) {
+    with_held(|held| held.push((lock, name)));
+}
+
+// Forgets the latest acquisition of `lock`. Without `send_guard`, a lock
+// released on another thread than the one that took it is not found, and is
+// left alone.
+pub(crate) fn released(lock: usize) {
+    with_held(|held| {
+        if let Some(index) = held.iter().rposition(|&(held, _)| held == lock) {
+            held.remove(index);
+        }
+    });
+}
+
+/// The number of spin locks the calling thread holds: every lock of this
+/// crate taken through a guard or a raw `lock`, counting each shared read
+/// of a reader-writer lock separately. With `send_guard`, the number held
+/// by any thread.
+pub fn held_lock_count() -> usize {
+    with_held(|held| held.len()).unwrap_or(0)
+}
+
+/// Panics, naming each lock (by its `new_named` name or its address), if
+/// the calling thread holds any spin lock. Call it before parking the
+/// thread, blocking on I/O or calling back into user code. With
+/// `send_guard`, panics if any thread holds one.
+#[track_caller]
+pub fn assert_no_locks_held() {
+    let locks: Vec<String> = with_held(|held| {
+        held.iter()
+            .map(|&(lock, name)| match name {
+                Some(name) => format!("'{}' ({:#x})", name, lock),
+                None => format!("{:#x}", lock),
+            })
+            .collect()
+    })
+    .unwrap_or_default();
+
+    if !locks.is_empty() {
+        panic!(
+            "{} {} spin lock(s): {}",
+            HOLDER,
+            locks.len(),
+            locks.join(", ")
+        );
+    }
+}
+
+#[cfg(not(feature = "send_guard"))]
+const HOLDER: &str = "this thread holds";
+#[cfg(feature = "send_guard")]
+const HOLDER: &str = "live guards hold";
@@ -0,0 +1,357 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! `Lock` and `RwLockLike`, for code that is generic over which of the
+//! crate's locks it uses.
+
+use crate::{
+    compat, BravoRwLock, BravoRwLockReadGuard, BravoRwLockWriteGuard, CohortLock, CohortLockGuard,
+    PrioritySpinLock, PrioritySpinLockGuard, SpinLock, SpinLockGuard, SpinRwLock,
+    SpinRwLockReadGuard, SpinRwLockWriteGuard,
+};
+#[cfg(all(feature = "priority_ceiling", unix))]
+use crate::{CeilingSpinLock, CeilingSpinLockGuard};
+use std::ops::{Deref, DerefMut};
+use std::sync::{PoisonError, TryLockError};
+
+/// A mutual-exclusion lock around a `T`, for code generic over the lock
+/// type. It is implemented by `SpinLock`, `PrioritySpinLock`, `CohortLock`,
+/// `CeilingSpinLock` and both `compat` mutexes, and by `std::sync::Mutex`
+/// with the `std_locks` feature. Poisoning is ignored throughout, as by
+/// `SpinLock::lock`. The locks' inherent methods of the same names still
+/// win in non-generic code, so implementing the trait changes no call.
+///
+/// A store whose lock flavor is a compile-time tuning knob:
+///
+/// ```
+/// use spin_lock::{CohortLock, Lock, SpinLock};
+/// use std::collections::HashMap;
+///
+/// struct Store<L> {
+///     shards: Vec<L>,
+/// }
+///
+/// impl<L: Lock<HashMap<u64, String>>> Store<L> {
+///     fn put(&self, key: u64, value: String) {
+///         self.shard(key).with_lock(|map| map.insert(key, value));
+///     }
+///
+///     fn get(&self, key: u64) -> Option<String> {
+///         self.shard(key).lock().get(&key).cloned()
+///     }
+///
+///     fn shard(&self, key: u64) -> &L {
+///         &self.shards[key as usize % self.shards.len()]
+///     }
+/// }
+///
+/// fn build<L: Lock<HashMap<u64, String>>>(new: fn(HashMap<u64, String>) -> L) -> Store<L> {
+///     Store { shards: (0..8).map(|_| new(HashMap::new())).collect() }
+/// }
+///
+/// let spinning = build(SpinLock::new);
+/// let cohorts = build(CohortLock::new);
+/// spinning.put(1, "one".to_string());
+/// cohorts.put(1, "one".to_string());
+/// assert_eq!(spinning.get(1), cohorts.get(1));
+/// ```
+pub trait Lock<T: ?Sized> {
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    /// Blocks until the lock is acquired.
+    fn lock(&self) -> Self::Guard<'_>;
+
+    /// Makes a single acquisition attempt.
+    fn try_lock(&self) -> Option<Self::Guard<'_>>;
+
+    /// Runs `f` with the lock held.
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+/// A reader-writer lock around a `T`, the `Lock` counterpart for shared
+/// reads. It is implemented by `SpinRwLock`, `BravoRwLock` and
+/// `compat::spin::RwLock`, and by `std::sync::RwLock` with the `std_locks`
+/// feature, whose poisoning is ignored.
+pub trait RwLockLike<T: ?Sized> {
+    type ReadGuard<'a>: Deref<Target = T>
+    where
+        Self: 'a;
+    type WriteGuard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    /// Blocks until shared access is granted.
+    fn read(&self) -> Self::ReadGuard<'_>;
+
+    /// Makes a single attempt at shared access.
+    fn try_read(&self) -> Option<Self::ReadGuard<'_>>;
+
+    /// Blocks until exclusive access is granted.
+    fn write(&self) -> Self::WriteGuard<'_>;
+
+    /// Makes a single attempt at exclusive access.
+    fn try_write(&self) -> Option<Self::WriteGuard<'_>>;
+
+    /// Runs `f` with shared access.
+    fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.read())
+    }
+
+    /// Runs `f` with exclusive access.
+    fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.write())
+    }
+}
+
+impl<T: ?Sized> Lock<T> for SpinLock<T> {
+    type Guard<'a>
+        = SpinLockGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> SpinLockGuard<'_, T> {
+        SpinLock::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.try_lock_n(0)
+    }
+
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        SpinLock::with_lock(self, f)
+    }
+}
+
+impl<T> Lock<T> for PrioritySpinLock<T> {
+    type Guard<'a>
+        = PrioritySpinLockGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> PrioritySpinLockGuard<'_, T> {
+        PrioritySpinLock::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<PrioritySpinLockGuard<'_, T>> {
+        PrioritySpinLock::try_lock(self)
+    }
+}
+
+impl<T> Lock<T> for CohortLock<T> {
+    type Guard<'a>
+        = CohortLockGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> CohortLockGuard<'_, T> {
+        CohortLock::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<CohortLockGuard<'_, T>> {
+        CohortLock::try_lock(self)
+    }
+}
+
+#[cfg(all(feature = "priority_ceiling", unix))]
+impl<T> Lock<T> for CeilingSpinLock<T> {
+    type Guard<'a>
+        = CeilingSpinLockGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> CeilingSpinLockGuard<'_, T> {
+        CeilingSpinLock::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<CeilingSpinLockGuard<'_, T>> {
+        CeilingSpinLock::try_lock(self)
+    }
+}
+
+impl<T> Lock<T> for compat::Mutex<T> {
+    type Guard<'a>
+        = compat::MutexGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> compat::MutexGuard<'_, T> {
+        compat::Mutex::lock(self).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn try_lock(&self) -> Option<compat::MutexGuard<'_, T>> {
+        match compat::Mutex::try_lock(self) {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl<T> Lock<T> for compat::spin::Mutex<T> {
+    type Guard<'a>
+        = compat::spin::MutexGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> compat::spin::MutexGuard<'_, T> {
+        compat::spin::Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<compat::spin::MutexGuard<'_, T>> {
+        compat::spin::Mutex::try_lock(self)
+    }
+}
+
+#[cfg(feature = "std_locks")]
+impl<T: ?Sized> Lock<T> for std::sync::Mutex<T> {
+    type Guard<'a>
+        = std::sync::MutexGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, T> {
+        std::sync::Mutex::lock(self).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn try_lock(&self) -> Option<std::sync::MutexGuard<'_, T>> {
+        match std::sync::Mutex::try_lock(self) {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl<T> RwLockLike<T> for SpinRwLock<T> {
+    type ReadGuard<'a>
+        = SpinRwLockReadGuard<'a, T>
+    where
+        Self: 'a;
+    type WriteGuard<'a>
+        = SpinRwLockWriteGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn read(&self) -> SpinRwLockReadGuard<'_, T> {
+        SpinRwLock::read(self)
+    }
+
+    fn try_read(&self) -> Option<SpinRwLockReadGuard<'_, T>> {
+        SpinRwLock::try_read(self)
+    }
+
+    fn write(&self) -> SpinRwLockWriteGuard<'_, T> {
+        SpinRwLock::write(self)
+    }
+
+    fn try_write(&self) -> Option<SpinRwLockWriteGuard<'_, T>> {
+        SpinRwLock::try_write(self)
+    }
+}
+
+impl<T> RwLockLike<T> for BravoRwLock<T> {
+    type ReadGuard<'a>
+        = BravoRwLockReadGuard<'a, T>
+    where
+        Self: 'a;
+    type WriteGuard<'a>
+        = BravoRwLockWriteGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn read(&self) -> BravoRwLockReadGuard<'_, T> {
+        BravoRwLock::read(self)
+    }
+
+    fn try_read(&self) -> Option<BravoRwLockReadGuard<'_, T>> {
+        BravoRwLock::try_read(self)
+    }
+
+    fn write(&self) -> BravoRwLockWriteGuard<'_, T> {
+        BravoRwLock::write(self)
+    }
+
+    fn try_write(&self) -> Option<BravoRwLockWriteGuard<'_, T>> {
+        BravoRwLock::try_write(self)
+    }
+}
+
+impl<T> RwLockLike<T> for compat::spin::RwLock<T> {
+    type ReadGuard<'a>
+        = compat::spin::RwLockReadGuard<'a, T>
+    where
+        Self: 'a;
+    type WriteGuard<'a>
+        = compat::spin::RwLockWriteGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn read(&self) -> compat::spin::RwLockReadGuard<'_, T> {
+        compat::spin::RwLock::read(self)
+    }
+
+    fn try_read(&self) -> Option<compat::spin::RwLockReadGuard<'_, T>> {
+        compat::spin::RwLock::try_read(self)
+    }
+
+    fn write(&self) -> compat::spin::RwLockWriteGuard<'_, T> {
+        compat::spin::RwLock::write(self)
+    }
+
+    fn try_write(&self) -> Option<compat::spin::RwLockWriteGuard<'_, T>> {
+        compat::spin::RwLock::try_write(self)
+    }
+}
+
+#[cfg(feature = "std_locks")]
+impl<T: ?Sized> RwLockLike<T> for std::sync::RwLock<T> {
+    type ReadGuard<'a>
+        = std::sync::RwLockReadGuard<'a, T>
+    where
+        Self: 'a;
+    type WriteGuard<'a>
+        = std::sync::RwLockWriteGuard<'a, T>
+    where
+        Self: 'a;
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        std::sync::RwLock::read(self).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn try_read(&self) -> Option<std::sync::RwLockReadGuard<'_, T>> {
+        match std::sync::RwLock::try_read(self) {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        std::sync::RwLock::write(self).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn try_write(&self) -> Option<std::sync::RwLockWriteGuard<'_, T>> {
+        match std::sync::RwLock::try_write(self) {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{CachePadded, GuardMarker, RawSpinLock};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// `N` cache-line-padded raw spin locks, for guarding a sharded structure
+/// such as a hash table split into `N` buckets. A key always maps to the same
+/// stripe: the hash is SipHash with fixed keys, not a per-instance seed.
+///
+/// Holding a stripe guard while calling `lock_all` deadlocks, as does
+/// taking two stripes in an order other than increasing index.
+pub struct LockStripe<const N: usize> {
+    locks: [CachePadded<RawSpinLock>; N],
+}
+
+impl<const N: usize> LockStripe<N> {
+    pub fn new() -> LockStripe<N> {
+        assert!(N > 0, "a LockStripe needs at least one stripe");
+
+        LockStripe {
+            locks: std::array::from_fn(|_| CachePadded::new(RawSpinLock::new())),
+        }
+    }
+
+    /// The stripe `key` maps to.
+    pub fn index_for<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % N as u64) as usize
+    }
+
+    pub fn lock_for<K: Hash + ?Sized>(&self, key: &K) -> StripeGuard<'_> {
+        self.lock_index(self.index_for(key))
+    }
+
+    /// Panics if `index` is not below `N`.
+    pub fn lock_index(&self, index: usize) -> StripeGuard<'_> {
+        let lock = &self.locks[index];
+        lock.lock();
+
+        StripeGuard {
+            lock,
+            index,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn try_lock_index(&self, index: usize) -> Option<StripeGuard<'_>> {
+        let lock = &self.locks[index];
+
+        lock.try_lock().then(|| StripeGuard {
+            lock,
+            index,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Takes every stripe, in index order, for operations that touch all of
+    /// the shards at once (resizing, clearing, iterating). Callers of
+    /// `lock_for` only ever hold one stripe, so they cannot deadlock with it.
+    pub fn lock_all(&self) -> StripeAllGuard<'_, N> {
+        for lock in &self.locks {
+            lock.lock();
+        }
+
+        StripeAllGuard {
+            stripe: self,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<const N: usize> Default for LockStripe<N> {
+    fn default() -> LockStripe<N> {
+        LockStripe::new()
+    }
+}
+
+pub struct StripeGuard<'a> {
+    lock: &'a RawSpinLock,
+    index: usize,
+    _marker: GuardMarker,
+}
+
+// The stripe guards reach no data, so sharing one only shares `index`.
+unsafe impl Sync for StripeGuard<'_> {}
+
+impl StripeGuard<'_> {
+    /// The stripe this guard holds, to pick the matching shard.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for StripeGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.lock.unlock() }
+    }
+}
+
+pub struct StripeAllGuard<'a, const N: usize> {
+    stripe: &'a LockStripe<N>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<const N: usize> Sync for StripeAllGuard<'_, N> {}
+
+impl<const N: usize> Drop for StripeAllGuard<'_, N> {
+    fn drop(&mut self) {
+        for lock in self.stripe.locks.iter().rev() {
+            unsafe { lock.unlock() }
+        }
+    }
+}
@@ -14,19 +14,213 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+#![cfg_attr(loom, allow(dead_code, unused_imports))]
+
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
-use spin_lock::SpinLock;
+use std::time::{Duration, Instant, SystemTime};
+use spin_lock::{
+    AtomicCellCompat, AtomicPayload, AtomicSpinCell, BravoRwLock, CohortLock, Lock, LockStripe, PrioritySpinLock, RawSharedSpinLock,
+    RawSpinLock, RcuCell, RobustSharedSpinLock, RwLockLike, SpinLock, SpinLockConfig, SpinLockGuard,
+    SpinQueue, SpinRwLock, SpinSlot, Strategy, TryLockError, WaitStrategy, spin_lock,
+};
 
+#[cfg(not(miri))]
 const THREAD_COUNT: usize = 32;
+#[cfg(not(miri))]
 const JOB_COUNT: usize = 1000000;
 
+// Miri interprets every instruction, so keep the workload small enough
+// for `./main miri` under Miri to finish in CI.
+#[cfg(miri)]
+const THREAD_COUNT: usize = 4;
+#[cfg(miri)]
+const JOB_COUNT: usize = 10;
+
+// Which types are `Send` and `Sync`, checked when this file compiles so a
+// refactor cannot quietly add or drop either. Without a manifest there is no
+// trybuild suite to hold compile-fail tests, so `lacks!` checks the negative
+// cases by inference instead: `Lacks<_>::check` resolves only when just the
+// blanket impl applies, and is ambiguous for a type with the trait.
+macro_rules! implements {
+    ($tr:path: $($ty:ty),* $(,)?) => {
+        $(
+            const _: fn() = || {
+                fn check<T: ?Sized + $tr>() {}
+                check::<$ty>();
+            };
+        )*
+    };
+}
+
+macro_rules! lacks {
+    ($tr:path: $($ty:ty),* $(,)?) => {
+        $(
+            const _: fn() = || {
+                trait Lacks<A> {
+                    fn check() {}
+                }
+
+                impl<T: ?Sized> Lacks<()> for T {}
+                impl<T: ?Sized + $tr> Lacks<u8> for T {}
+                <$ty as Lacks<_>>::check();
+            };
+        )*
+    };
+}
+
+// Payloads for each combination: `u32` is both, `Cell` only `Send`, and a
+// `MutexGuard` only `Sync`.
+type Both = u32;
+type SendOnly = std::cell::Cell<u32>;
+type SyncOnly = std::sync::MutexGuard<'static, u32>;
+
+// Exclusive locks hand `&mut T` to one thread at a time, so, like
+// `std::sync::Mutex`, both traits need `T: Send` and nothing more.
+macro_rules! exclusive_locks {
+    ($check:ident!($tr:path) for $payload:ty) => {
+        $check!($tr:
+            SpinLock<$payload>,
+            PrioritySpinLock<$payload>,
+            CohortLock<$payload>,
+            spin_lock::SpinLockU8<$payload>,
+            spin_lock::compat::Mutex<$payload>,
+            spin_lock::compat::spin::Mutex<$payload>,
+            SpinQueue<$payload>,
+            SpinSlot<$payload>,
+        );
+    };
+}
+
+exclusive_locks!(implements!(Send) for SendOnly);
+exclusive_locks!(implements!(Sync) for SendOnly);
+exclusive_locks!(lacks!(Send) for SyncOnly);
+exclusive_locks!(lacks!(Sync) for SyncOnly);
+
+// Shared reads hand `&T` to several threads at once, so sharing also needs
+// `T: Sync`, as for `std::sync::RwLock`.
+macro_rules! shared_locks {
+    ($check:ident!($tr:path) for $payload:ty) => {
+        $check!($tr:
+            SpinRwLock<$payload>,
+            BravoRwLock<$payload>,
+            spin_lock::SpinOnceCell<$payload>,
+            spin_lock::compat::spin::RwLock<$payload>,
+            spin_lock::compat::spin::Once<$payload>,
+            spin_lock::compat::spin::Lazy<$payload>,
+        );
+    };
+}
+
+shared_locks!(implements!(Send) for SendOnly);
+shared_locks!(implements!(Sync) for Both);
+shared_locks!(lacks!(Sync) for SendOnly);
+shared_locks!(lacks!(Send) for SyncOnly);
+
+// Readers of an `RcuCell` keep `Arc<T>`s, so both traits need what
+// `Arc<T>` needs. An `AtomicSpinCell` hands out copies, so both need `T: Send`.
+implements!(Send: RcuCell<Both>, AtomicSpinCell<Both>);
+implements!(Sync: RcuCell<Both>, AtomicSpinCell<Both>);
+lacks!(Send: RcuCell<SendOnly>);
+lacks!(Sync: RcuCell<SendOnly>);
+
+// `compat::Mutex::new` is `const` like `std::sync::Mutex::new`, so a
+// `static` survives the import swap.
+#[cfg(not(loom))]
+static _COMPAT_STATIC: spin_lock::compat::Mutex<u32> = spin_lock::compat::Mutex::new(0);
+
+// The locks without data are both, always.
+implements!(Send: RawSpinLock, LockStripe<4>, RawSharedSpinLock, RobustSharedSpinLock);
+implements!(Sync: RawSpinLock, LockStripe<4>, RawSharedSpinLock, RobustSharedSpinLock);
+
+// Sharing a guard shares `&T`, so every guard is `Sync` exactly when `T` is.
+// The guards without data, and the packed guards that own a copy of their
+// integer, are always `Sync`.
+macro_rules! data_guards {
+    ($check:ident!($tr:path) for $payload:ty) => {
+        $check!($tr:
+            SpinLockGuard<'static, $payload>,
+            spin_lock::MappedSpinLockGuard<'static, $payload>,
+            spin_lock::LockManyGuard<'static, $payload>,
+            spin_lock::SpinRwLockReadGuard<'static, $payload>,
+            spin_lock::SpinRwLockWriteGuard<'static, $payload>,
+            spin_lock::SpinRwLockUpgradableGuard<'static, $payload>,
+            spin_lock::BravoRwLockReadGuard<'static, $payload>,
+            spin_lock::BravoRwLockWriteGuard<'static, $payload>,
+            spin_lock::PrioritySpinLockGuard<'static, $payload>,
+            spin_lock::CohortLockGuard<'static, $payload>,
+            spin_lock::compat::spin::MutexGuard<'static, $payload>,
+            spin_lock::compat::spin::RwLockReadGuard<'static, $payload>,
+            spin_lock::compat::spin::RwLockWriteGuard<'static, $payload>,
+            spin_lock::compat::spin::RwLockUpgradableGuard<'static, $payload>,
+        );
+    };
+}
+
+macro_rules! dataless_guards {
+    ($check:ident!($tr:path)) => {
+        $check!($tr:
+            spin_lock::StripeGuard<'static>,
+            spin_lock::StripeAllGuard<'static, 4>,
+            spin_lock::RobustSharedSpinLockGuard<'static>,
+            spin_lock::PackedSpinLockU8Guard<'static>,
+            spin_lock::PackedSpinLockU16Guard<'static>,
+        );
+    };
+}
+
+data_guards!(implements!(Sync) for Both);
+data_guards!(lacks!(Sync) for SendOnly);
+dataless_guards!(implements!(Sync));
+
+// Guards are `!Send` unless the `send_guard` feature is on, since a lock is
+// normally released by the thread that took it. With the feature, a guard
+// is `Send` when its lock may be shared, so a read or write guard of a
+// reader-writer lock needs `T: Send + Sync`.
+#[cfg(not(feature = "send_guard"))]
+data_guards!(lacks!(Send) for Both);
+#[cfg(not(feature = "send_guard"))]
+dataless_guards!(lacks!(Send));
+#[cfg(feature = "send_guard")]
+data_guards!(implements!(Send) for Both);
+#[cfg(feature = "send_guard")]
+dataless_guards!(implements!(Send));
+#[cfg(feature = "send_guard")]
+data_guards!(lacks!(Send) for SyncOnly);
+#[cfg(feature = "send_guard")]
+lacks!(Send:
+    spin_lock::SpinRwLockReadGuard<'static, SendOnly>,
+    spin_lock::SpinRwLockWriteGuard<'static, SendOnly>,
+    spin_lock::SpinRwLockUpgradableGuard<'static, SendOnly>,
+    spin_lock::BravoRwLockReadGuard<'static, SendOnly>,
+    spin_lock::BravoRwLockWriteGuard<'static, SendOnly>,
+    spin_lock::compat::spin::RwLockReadGuard<'static, SendOnly>,
+    spin_lock::compat::spin::RwLockWriteGuard<'static, SendOnly>,
+    spin_lock::compat::spin::RwLockUpgradableGuard<'static, SendOnly>,
+);
+
+// A ceiling lock is exclusive, and its guard restores the priority of the
+// thread that took it, so the guard stays `!Send` even with `send_guard`.
+#[cfg(all(feature = "priority_ceiling", unix))]
+implements!(Send: spin_lock::CeilingSpinLock<SendOnly>);
+#[cfg(all(feature = "priority_ceiling", unix))]
+implements!(Sync: spin_lock::CeilingSpinLock<SendOnly>, spin_lock::CeilingSpinLockGuard<'static, Both>);
+#[cfg(all(feature = "priority_ceiling", unix))]
+lacks!(Sync: spin_lock::CeilingSpinLock<SyncOnly>, spin_lock::CeilingSpinLockGuard<'static, SendOnly>);
+#[cfg(all(feature = "priority_ceiling", unix))]
+lacks!(Send: spin_lock::CeilingSpinLockGuard<'static, Both>);
+
 fn unix_timestamp() -> u128 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis()
 }
 
-fn main() {
+#[cfg(not(loom))]
+fn bench_counter() {
     let lock_ = Arc::new(SpinLock::new(0));
     let start = unix_timestamp();
     let mut vec = Vec::new();
@@ -41,12 +235,801 @@ fn main() {
                         println!("Error: {}", e);
                         return;
                     }*/
-                    lock_.lock();
+                    *lock_.lock() += a;
+                }
+            }
+        });
+
+        vec.push(thread);
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!(
+        "SpinLock: {} {}",
+        *lock_.lock(),
+        unix_timestamp() - start
+    );
+}
+
+// The counter workload above, cut down for ThreadSanitizer, which runs it
+// about ten times slower, and spread over the ways to take and release a
+// lock: `lock`, `unlock_fair`, a `try_lock` loop, `try_lock_for` and a
+// `RawSpinLock` over a plain `UnsafeCell`. The counters are ordinary
+// memory, so a release that failed to publish a critical section's write
+// to the next holder shows up as a data race on them.
+#[cfg(not(loom))]
+fn tsan() {
+    const ROUNDS: u64 = 2000;
+
+    struct Guarded(RawSpinLock, UnsafeCell<u64>);
+    unsafe impl Sync for Guarded {}
+
+    let lock_ = SpinLock::new(0u64);
+    let raw = Guarded(RawSpinLock::new(), UnsafeCell::new(0));
+    let start = unix_timestamp();
+
+    thread::scope(|scope| {
+        for thread in 0..THREAD_COUNT {
+            let (lock_, raw) = (&lock_, &raw);
+
+            scope.spawn(move || {
+                for round in 0..ROUNDS {
+                    match (thread as u64 + round) % 4 {
+                        0 => *lock_.lock() += 1,
+                        1 => {
+                            let mut guard = lock_.lock();
+                            *guard += 1;
+                            SpinLockGuard::unlock_fair(guard);
+                        }
+                        2 => loop {
+                            if let Ok(mut guard) = lock_.try_lock() {
+                                *guard += 1;
+                                break;
+                            }
+
+                            thread::yield_now();
+                        },
+                        _ => *lock_.try_lock_for(Duration::from_secs(60)).unwrap() += 1,
+                    }
+
+                    raw.0.lock();
                     unsafe {
-                        *lock_.data.get() += a;
+                        *raw.1.get() += 1;
+                        raw.0.unlock();
+                    }
+                }
+            });
+        }
+    });
+
+    let expected = THREAD_COUNT as u64 * ROUNDS;
+    assert_eq!(*lock_.lock(), expected);
+    assert_eq!(raw.1.into_inner(), expected);
+    println!("tsan: {} threads, {} increments per lock, {}", THREAD_COUNT, expected, unix_timestamp() - start);
+}
+
+// The workload for Miri: THREAD_COUNT threads sharing a `SpinLock` through
+// `lock`, `unlock_fair`, a `try_lock` loop and `with_lock`, the single
+// thread guard checks of `smoke`, `split_map` and `guard_fmt`, readers,
+// writers and upgraders sharing a `SpinRwLock`, `AtomicSpinCell` counting
+// and publishing across threads, `RcuCell` readers racing its writers, and
+// with `seqlock` the paths of `read_snapshot` that do not race a writer.
+// Nothing here reads a clock or sleeps, so it runs with Miri's isolation
+// on. Miri checks each access to the data under a guard against the
+// aliasing rules and the memory model.
+#[cfg(not(loom))]
+fn miri() {
+    let lock_ = SpinLock::new(0usize);
+
+    thread::scope(|scope| {
+        for thread in 0..THREAD_COUNT {
+            let lock_ = &lock_;
+
+            scope.spawn(move || {
+                for round in 0..JOB_COUNT {
+                    match (thread + round) % 4 {
+                        0 => *lock_.lock() += 1,
+                        1 => {
+                            let mut guard = lock_.lock();
+                            *guard += 1;
+                            SpinLockGuard::unlock_fair(guard);
+                        }
+                        2 => loop {
+                            if let Ok(mut guard) = lock_.try_lock() {
+                                *guard += 1;
+                                break;
+                            }
+
+                            thread::yield_now();
+                        },
+                        _ => lock_.with_lock(|value| *value += 1),
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(lock_.into_inner(), THREAD_COUNT * JOB_COUNT);
+    smoke();
+    split_map();
+    guard_fmt();
+
+    // Both lanes move together under every kind of write guard, so a reader
+    // that sees them differ saw a write in progress.
+    let rw = SpinRwLock::new([0usize; 2]);
+
+    thread::scope(|scope| {
+        for thread in 0..THREAD_COUNT {
+            let rw = &rw;
+
+            scope.spawn(move || {
+                for round in 0..JOB_COUNT {
+                    match (thread + round) % 3 {
+                        0 => {
+                            let lanes = rw.read();
+                            assert_eq!(lanes[0], lanes[1]);
+                        }
+                        1 => {
+                            let mut lanes = rw.write();
+                            lanes[0] += 1;
+                            lanes[1] += 1;
+                        }
+                        _ => {
+                            let lanes = rw.upgradeable_read();
+                            let seen = lanes[0];
+                            let mut lanes = spin_lock::SpinRwLockUpgradableGuard::upgrade(lanes);
+                            assert_eq!(lanes[0], seen, "a write got in during the upgrade");
+                            lanes[0] += 1;
+                            lanes[1] += 1;
+                            let lanes = spin_lock::SpinRwLockWriteGuard::downgrade(lanes);
+                            assert_eq!(lanes[0], lanes[1]);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let writes = (0..THREAD_COUNT)
+        .flat_map(|thread| (0..JOB_COUNT).map(move |round| (thread + round) % 3))
+        .filter(|&kind| kind != 0)
+        .count();
+    assert_eq!(rw.into_inner(), [writes; 2]);
+
+    let count = AtomicSpinCell::new(0u32);
+    let phase = AtomicSpinCell::new(Phase::Idle);
+    let result = AtomicSpinCell::new(0.0f64);
+
+    thread::scope(|scope| {
+        for _ in 0..THREAD_COUNT {
+            scope.spawn(|| {
+                for _ in 0..JOB_COUNT {
+                    count.update(|value| value + 1);
+                }
+            });
+        }
+
+        scope.spawn(|| {
+            result.store_with(2.5, Ordering::Relaxed);
+            phase.store_with(Phase::Done, Ordering::Release);
+        });
+
+        while phase.load_with(Ordering::Acquire) != Phase::Done {
+            thread::yield_now();
+        }
+
+        assert_eq!(result.load_with(Ordering::Relaxed), 2.5);
+    });
+
+    assert_eq!(count.into_inner(), (THREAD_COUNT * JOB_COUNT) as u32);
+
+    // Readers racing `update` and a `compare_and_swap` retry loop. A writer
+    // that freed a value a reader was still taking a reference to shows up
+    // as a use-after-free on the `Box`.
+    let cell = RcuCell::new(Box::new(0usize));
+
+    thread::scope(|scope| {
+        for _ in 2..THREAD_COUNT {
+            scope.spawn(|| {
+                let mut last = 0;
+
+                for _ in 0..JOB_COUNT * 2 {
+                    let generation = **cell.read();
+                    assert!(generation >= last, "went back from {} to {}", last, generation);
+                    last = generation;
+                }
+            });
+        }
+
+        scope.spawn(|| {
+            for _ in 0..JOB_COUNT {
+                cell.update(|generation| Box::new(**generation + 1));
+            }
+        });
+
+        for _ in 0..JOB_COUNT {
+            loop {
+                let current = cell.read();
+
+                if cell.compare_and_swap(&current, Box::new(**current + 1)).is_ok() {
+                    break;
+                }
+            }
+        }
+    });
+
+    assert_eq!(**cell.read(), 2 * JOB_COUNT);
+
+    // An uncontended snapshot copies without the lock, and one taken while
+    // another thread holds the lock waits for it. A snapshot racing a
+    // writer's copy is the data race the seqlock accepts, so it is left
+    // out.
+    #[cfg(feature = "seqlock")]
+    {
+        let pair = SpinLock::new([1u64, 1]);
+        assert_eq!(pair.read_snapshot(), [1, 1]);
+        let held = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut guard = pair.lock();
+                held.store(true, Ordering::Release);
+
+                for _ in 0..JOB_COUNT {
+                    thread::yield_now();
+                }
+
+                *guard = [2, 2];
+            });
+
+            while !held.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+
+            assert_eq!(pair.read_snapshot(), [2, 2]);
+        });
+    }
+
+    println!("miri: {} threads, {} rounds each, guards, RW guards, AtomicSpinCell and RcuCell ok", THREAD_COUNT, JOB_COUNT);
+}
+
+// A waiter on a held lock gives up once another thread sets its flag 50 ms
+// in: `lock_cancellable` must return `None` within a few backoff sleeps of
+// that, and leave the lock with its holder. Without the flag set it must
+// acquire the lock as soon as the holder lets go.
+#[cfg(not(loom))]
+fn cancellable() {
+    let lock_ = SpinLock::new(0u64);
+    let cancel = AtomicBool::new(false);
+    let guard = lock_.lock();
+
+    let late = thread::scope(|scope| {
+        let waiter = scope.spawn(|| {
+            let got = lock_.lock_cancellable(&cancel).is_some();
+            (got, Instant::now())
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        cancel.store(true, Ordering::Relaxed);
+        let set = Instant::now();
+        let (got, returned) = waiter.join().unwrap();
+        assert!(!got, "cancelled waiter took the lock");
+        returned.saturating_duration_since(set)
+    });
+
+    assert!(late < Duration::from_millis(100), "cancelled waiter took {:?} to return", late);
+    assert!(lock_.try_lock().is_err(), "cancellation released the lock");
+    assert!(lock_.lock_cancellable(&cancel).is_none());
+
+    cancel.store(false, Ordering::Relaxed);
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| *lock_.lock_cancellable(&cancel).expect("uncancelled waiter gave up") += 1);
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        waiter.join().unwrap();
+    });
+
+    assert_eq!(*lock_.lock(), 1);
+    assert!(lock_.lock_cancellable(&AtomicBool::new(true)).is_some(), "free lock not taken on the first attempt");
+    println!("cancellable: waiter returned {:?} after the flag was set", late);
+}
+
+// Walks the lock word through its states, read back with `is_locked` and
+// `is_contended`: free, held, held with a waiter asleep, then held by that
+// waiter once the release clears the word. Two sleeping waiters: the one that
+// takes the lock leaves it contended for the other. An `unlock_fair` handoff
+// keeps the word as it is, one with no waiter clears it, and every other
+// release leaves the lock free.
+#[cfg(not(loom))]
+fn lock_word() {
+    let word = |lock_: &RawSpinLock| (lock_.is_locked(), lock_.is_contended());
+    let settle = |what: &str, done: &dyn Fn() -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
+
+    let lock_ = RawSpinLock::new();
+    assert_eq!(word(&lock_), (false, false));
+
+    lock_.lock();
+    assert_eq!(word(&lock_), (true, false));
+    assert!(!lock_.try_lock());
+    unsafe { lock_.unlock() };
+    assert_eq!(word(&lock_), (false, false));
+
+    // Each waiter counts itself in `held` once it has the lock, and lets go
+    // when `released` reaches its count.
+    let held = AtomicUsize::new(0);
+    let released = AtomicUsize::new(0);
+    let waiter = || {
+        lock_.lock();
+        let count = held.fetch_add(1, Ordering::SeqCst) + 1;
+
+        while released.load(Ordering::SeqCst) < count {
+            thread::yield_now();
+        }
+
+        unsafe { lock_.unlock() };
+    };
+
+    lock_.lock();
+    thread::scope(|scope| {
+        scope.spawn(waiter);
+        settle("the waiter to sleep", &|| lock_.is_contended());
+        assert_eq!(word(&lock_), (true, true));
+        assert!(!lock_.try_lock());
+
+        unsafe { lock_.unlock() };
+        settle("the waiter to take the lock", &|| held.load(Ordering::SeqCst) == 1);
+        assert_eq!(word(&lock_), (true, false));
+        released.store(1, Ordering::SeqCst);
+    });
+    assert_eq!(word(&lock_), (false, false));
+
+    lock_.lock();
+    thread::scope(|scope| {
+        scope.spawn(waiter);
+        scope.spawn(waiter);
+        settle("a waiter to sleep", &|| lock_.is_contended());
+        // Long enough for the other to register too.
+        thread::sleep(Duration::from_millis(50));
+
+        unsafe { lock_.unlock() };
+        settle("the first waiter to take the lock", &|| held.load(Ordering::SeqCst) == 2);
+        assert_eq!(word(&lock_), (true, true));
+
+        released.store(2, Ordering::SeqCst);
+        settle("the second waiter to take the lock", &|| held.load(Ordering::SeqCst) == 3);
+        assert_eq!(word(&lock_), (true, false));
+        released.store(3, Ordering::SeqCst);
+    });
+    assert_eq!(word(&lock_), (false, false));
+
+    lock_.lock();
+    thread::scope(|scope| {
+        scope.spawn(waiter);
+        settle("the waiter to sleep", &|| lock_.is_contended());
+
+        unsafe { lock_.unlock_fair() };
+        assert_eq!(word(&lock_), (true, true));
+        settle("the waiter to claim the handoff", &|| held.load(Ordering::SeqCst) == 4);
+        assert_eq!(word(&lock_), (true, true));
+        released.store(4, Ordering::SeqCst);
+    });
+    assert_eq!(word(&lock_), (false, false));
+
+    // With no one waiting there is no handoff to make.
+    lock_.lock();
+    unsafe { lock_.unlock_fair() };
+    assert_eq!(word(&lock_), (false, false));
+    println!("lock_word: every transition checked");
+}
+
+// Both forms of `spin_lock!`: the closure form binding the data, the binding
+// form keeping the guard, each yielding its body's value, on a lock and on an
+// `Arc` of one. A `?` or `break` out of the body must release the lock.
+#[cfg(not(loom))]
+fn lock_macro() {
+    let counter = SpinLock::new(0u64);
+    let shared = Arc::new(SpinLock::new(Vec::new()));
+
+    for round in 0..10 {
+        spin_lock!(counter => |count| *count += 1);
+        spin_lock!(shared => |items| items.push(round));
+    }
+
+    let total = spin_lock!(let count = counter; *count);
+    assert_eq!(total, 10);
+    let doubled = spin_lock!(let mut count = counter; *count += 5; *count * 2);
+    assert_eq!(doubled, 30);
+    let sum: u64 = spin_lock!(shared => |items| items.iter().sum());
+    assert_eq!(sum, 45);
+
+    let take_first = |lock_: &SpinLock<Vec<u64>>| -> Option<u64> {
+        spin_lock!(lock_ => |items| {
+            let first = items.first().copied()?;
+            items.remove(0);
+            Some(first)
+        })
+    };
+    let empty = SpinLock::new(Vec::new());
+    assert_eq!(take_first(&empty), None);
+    assert!(empty.try_lock().is_ok(), "`?` out of the body kept the lock");
+    assert_eq!(take_first(&shared), Some(0));
+
+    loop {
+        spin_lock!(let mut count = counter; if *count > 0 { break; } *count += 1);
+    }
+    assert!(counter.try_lock().is_ok(), "`break` out of the body kept the lock");
+
+    println!("macro: counter {}, {} items left", spin_lock!(counter => |count| *count), shared.lock().len());
+}
+
+// Code written against the `spin` crate, with only its imports changed:
+// statics built by const `new`s, a `Once` and a `Lazy`, upgrading and
+// downgrading an `RwLock`, and a `Once` whose failed `try_call_once` runs
+// again while a panicking `call_once` poisons it.
+#[cfg(not(loom))]
+fn spin_compat() {
+    use spin_lock::compat::spin;
+
+    static HITS: spin::Mutex<u64> = spin::Mutex::new(0);
+    static TABLE: spin::RwLock<Vec<u64>> = spin::RwLock::new(Vec::new());
+    static START: spin::Once<Instant> = spin::Once::new();
+    static SQUARES: spin::Lazy<Vec<u64>> = spin::Lazy::new(|| (0..16).map(|n| n * n).collect());
+
+    thread::scope(|scope| {
+        for id in 0..4u64 {
+            scope.spawn(move || {
+                START.call_once(Instant::now);
+                for round in 0..1000 {
+                    *HITS.lock() += 1;
+                    if round % 100 == 0 {
+                        let table = TABLE.upgradeable_read();
+                        if !table.contains(&id) {
+                            table.upgrade().push(id);
+                        }
+                    }
+                    assert_eq!(SQUARES[round as usize % 16], (round % 16) * (round % 16));
+                }
+            });
+        }
+    });
+    assert_eq!(*HITS.lock(), 4000);
+    assert!(!HITS.is_locked());
+    let mut ids = TABLE.read().clone();
+    ids.sort();
+    assert_eq!(ids, [0, 1, 2, 3]);
+
+    let held = HITS.lock();
+    assert!(HITS.try_lock().is_none() && HITS.is_locked());
+    drop(held);
+
+    let write = TABLE.write();
+    assert_eq!(TABLE.writer_count(), 1);
+    let upgradable = write.downgrade_to_upgradeable();
+    assert_eq!((TABLE.reader_count(), TABLE.writer_count()), (1, 0));
+    assert!(TABLE.try_read().is_none(), "an upgradable read shuts out new readers");
+    let read = upgradable.downgrade();
+    let second = TABLE.read();
+    assert_eq!(TABLE.reader_count(), 2);
+    let upgradable = TABLE.upgradeable_read();
+    let Err(upgradable) = upgradable.try_upgrade() else { panic!("upgraded past two readers") };
+    drop((read, second));
+    drop(upgradable.try_upgrade().expect("upgrade with the readers gone"));
+
+    let config: spin::Once<u32> = spin::Once::new();
+    assert_eq!(config.try_call_once(|| "not yet".parse::<u32>()).map_err(|_| "parse"), Err("parse"));
+    assert!(!config.is_completed() && config.poll().is_none());
+    assert_eq!(config.try_call_once(|| "7".parse::<u32>()), Ok(&7));
+    assert_eq!(*config.call_once(|| unreachable!()), 7);
+
+    let poisoned: spin::Once = spin::Once::new();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let call = |f: fn()| std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *poisoned.call_once(f)));
+    let (first, second) = (call(|| panic!("initializer failed")), call(|| ()));
+    std::panic::set_hook(hook);
+    assert!(first.is_err() && second.is_err(), "a panicked Once stays poisoned");
+
+    let leaked = spin::Mutex::new(5);
+    *spin::MutexGuard::leak(leaked.lock()) += 1;
+    assert!(leaked.try_lock().is_none());
+    unsafe { leaked.force_unlock() };
+    assert_eq!(leaked.into_inner(), 6);
+
+    println!(
+        "spin_compat: {} hits, table {:?}, started {:?} ago",
+        *HITS.lock(),
+        TABLE.read(),
+        START.wait().elapsed()
+    );
+}
+
+// Every implementation of `Lock` and `RwLockLike`, driven only through the
+// traits: threads counting under the lock, and `try_lock` (or `try_read`
+// and `try_write`) failing exactly while the lock is held against it. A
+// `BravoRwLock` reader on a slot must also turn a `try_write` away.
+#[cfg(not(loom))]
+fn lock_trait() {
+    fn count<L: Lock<u64> + Sync>(name: &str, lock_: L) {
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for round in 0..2000 {
+                        match round % 3 {
+                            0 => *lock_.lock() += 1,
+                            1 => lock_.with_lock(|count| *count += 1),
+                            _ => loop {
+                                if let Some(mut count) = lock_.try_lock() {
+                                    *count += 1;
+                                    break;
+                                }
+                                std::hint::spin_loop();
+                            },
+                        }
+                    }
+                });
+            }
+        });
+
+        let held = lock_.lock();
+        assert!(lock_.try_lock().is_none(), "{}: try_lock got in under a guard", name);
+        drop(held);
+        assert_eq!(lock_.with_lock(|count| *count), 8000, "{}", name);
+        println!("lock_trait: {} counted 8000", name);
+    }
+
+    fn read_write<L: RwLockLike<u64> + Sync>(name: &str, lock_: L) {
+        thread::scope(|scope| {
+            for id in 0..4 {
+                let lock_ = &lock_;
+                scope.spawn(move || {
+                    for _ in 0..2000 {
+                        if id % 2 == 0 {
+                            lock_.with_write(|count| *count += 1);
+                        } else {
+                            let seen = lock_.with_read(|count| *count);
+                            assert!(seen <= 4000);
+                        }
+                    }
+                });
+            }
+        });
+
+        let read = lock_.read();
+        assert!(lock_.try_read().is_some() && lock_.try_write().is_none(), "{}", name);
+        drop(read);
+        let write = lock_.write();
+        assert!(lock_.try_read().is_none() && lock_.try_write().is_none(), "{}", name);
+        drop(write);
+        assert_eq!(*lock_.try_read().expect("free lock"), 4000, "{}", name);
+        println!("lock_trait: {} wrote 4000", name);
+    }
+
+    count("SpinLock", SpinLock::new(0));
+    count("PrioritySpinLock", PrioritySpinLock::new(0));
+    count("CohortLock", CohortLock::with_nodes(2, 0));
+    count("compat::Mutex", spin_lock::compat::Mutex::new(0));
+    count("compat::spin::Mutex", spin_lock::compat::spin::Mutex::new(0));
+    #[cfg(all(feature = "priority_ceiling", unix))]
+    count("CeilingSpinLock", spin_lock::CeilingSpinLock::new(0, 1));
+    #[cfg(feature = "std_locks")]
+    count("std::sync::Mutex", std::sync::Mutex::new(0));
+
+    read_write("SpinRwLock", SpinRwLock::new(0));
+    read_write("BravoRwLock", BravoRwLock::new(0));
+    read_write("compat::spin::RwLock", spin_lock::compat::spin::RwLock::new(0));
+    #[cfg(feature = "std_locks")]
+    read_write("std::sync::RwLock", std::sync::RwLock::new(0));
+
+    // A read on the bias leaves the inner lock free, so only the slot scan
+    // can refuse the writer, after which the bias must be back on.
+    let bravo = BravoRwLock::new(0u64);
+    let read = bravo.read();
+    thread::scope(|scope| {
+        scope.spawn(|| assert!(bravo.try_write().is_none(), "try_write ignored a slot reader"));
+    });
+    let other = thread::scope(|scope| scope.spawn(|| bravo.try_read().is_some()).join().unwrap());
+    assert!(other, "a reader was turned away next to a reader");
+    drop(read);
+    assert!(RwLockLike::try_write(&bravo).is_some());
+}
+
+// `acquire` in each of its outcomes: a free lock taken with or without a
+// timeout, a zero and a 50 ms timeout running out against a holder, a
+// waiter without a timeout getting the lock once the holder lets go, and a
+// poisoned lock reported with its guard either way.
+#[cfg(not(loom))]
+fn acquire() {
+    let lock_ = SpinLock::new(0u64);
+    drop(lock_.acquire(None).expect("free lock"));
+    drop(lock_.acquire(Some(Duration::ZERO)).expect("free lock, zero timeout"));
+
+    let guard = lock_.lock();
+    let timeout = Duration::from_millis(50);
+    let (zero, timed, waited) = thread::scope(|scope| {
+        let zero = scope.spawn(|| {
+            let start = Instant::now();
+            (matches!(lock_.acquire(Some(Duration::ZERO)), Err(TryLockError::TimedOut)), start.elapsed())
+        }).join().unwrap();
+        let timed = scope.spawn(|| {
+            let start = Instant::now();
+            (matches!(lock_.acquire(Some(timeout)), Err(TryLockError::TimedOut)), start.elapsed())
+        }).join().unwrap();
+
+        let waiter = scope.spawn(|| {
+            let start = Instant::now();
+            *lock_.acquire(None).expect("waiter without a timeout gave up") += 1;
+            start.elapsed()
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        (zero, timed, waiter.join().unwrap())
+    });
+
+    assert!(zero.0 && zero.1 < Duration::from_millis(5), "zero timeout: {:?}", zero);
+    assert!(timed.0 && timed.1 >= timeout && timed.1 < timeout * 3, "50 ms timeout: {:?}", timed);
+    assert!(waited >= Duration::from_millis(20), "waiter returned after {:?}", waited);
+    assert_eq!(*lock_.lock(), 1);
+
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lock_.with_lock(|_| panic!("poison"))));
+    std::panic::set_hook(hook);
+
+    for timeout in [None, Some(timeout)] {
+        match lock_.acquire(timeout) {
+            Err(TryLockError::Poisoned(err)) => assert_eq!(*err.into_inner(), 1),
+            other => panic!("acquire({:?}) on a poisoned lock: {:?}", timeout, other.map(|guard| *guard)),
+        }
+    }
+    assert!(matches!(lock_.try_lock(), Err(TryLockError::Poisoned(_))), "a poisoned acquire kept the lock");
+
+    println!("acquire: zero timeout gave up after {:?}, 50 ms after {:?}, waiter got the lock after {:?}", zero.1, timed.1, waited);
+}
+
+// Steady: every thread hammers the lock continuously.
+// Bursty: threads take the lock in short bursts separated by idle periods,
+// so the spins needed to acquire swing between near zero and heavy.
+#[cfg(not(loom))]
+fn bench_contention_patterns() {
+    for (name, burst, idle) in [("steady", JOB_COUNT, 0), ("bursty", (JOB_COUNT / 100).max(1), 1)] {
+        let lock_ = Arc::new(SpinLock::new(0));
+        let start = unix_timestamp();
+        let mut vec = Vec::new();
+
+        for _ in 0..THREAD_COUNT {
+            let lock_ = Arc::clone(&lock_);
+
+            let thread = thread::spawn(move || {
+                let mut done = 0;
+
+                while done < JOB_COUNT {
+                    for _ in 0..burst {
+                        lock_.with_lock(|data| *data += 1);
+                    }
+
+                    done += burst;
+
+                    if idle > 0 {
+                        thread::sleep(Duration::from_millis(idle));
                     }
-                    lock_.unlock();
                 }
+            });
+
+            vec.push(thread);
+        }
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        println!(
+            "SpinLock {}: {} {}",
+            name,
+            *lock_.lock(),
+            unix_timestamp() - start
+        );
+    }
+}
+
+// A test-and-set lock that retries its CAS in a tight loop, as the
+// baseline for `bench_ttas`.
+struct TasLock(AtomicBool);
+
+impl TasLock {
+    fn with_lock(&self, data: &UnsafeCell<u64>) {
+        while self.0.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::hint::spin_loop();
+        }
+
+        unsafe { *data.get() += 1 };
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+struct TasCounter {
+    lock_: TasLock,
+    data: UnsafeCell<u64>,
+}
+
+unsafe impl Sync for TasCounter {}
+
+// 16 and 32 threads incrementing one counter, behind the tight-CAS test-and-
+// set lock above and behind SpinLock, whose waiters read-spin and CAS only
+// when they see the lock free (test-and-test-and-set).
+fn bench_ttas() {
+    let jobs = (JOB_COUNT / 100).max(1);
+
+    for threads in [16, 32] {
+        let tas = Arc::new(TasCounter { lock_: TasLock(AtomicBool::new(false)), data: UnsafeCell::new(0) });
+        let start = unix_timestamp();
+        let mut vec = Vec::new();
+
+        for _ in 0..threads {
+            let tas = Arc::clone(&tas);
+            vec.push(thread::spawn(move || {
+                for _ in 0..jobs {
+                    tas.lock_.with_lock(&tas.data);
+                }
+            }));
+        }
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        let tas_elapsed = unix_timestamp() - start;
+        let spin = Arc::new(SpinLock::new(0u64));
+        let start = unix_timestamp();
+
+        for _ in 0..threads {
+            let spin = Arc::clone(&spin);
+            vec.push(thread::spawn(move || {
+                for _ in 0..jobs {
+                    *spin.lock() += 1;
+                }
+            }));
+        }
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(unsafe { *tas.data.get() }, *spin.lock());
+        println!("{} threads: TAS {} TTAS SpinLock {}", threads, tas_elapsed, unix_timestamp() - start);
+    }
+}
+
+// Many threads holding the lock briefly keep the waiters in the
+// yield/sleep backoff; the elapsed time (and, with the `stats` feature, the
+// contended share and sleep count) shows how often waiters collide.
+#[cfg(not(loom))]
+fn bench_backoff() {
+    let lock_ = Arc::new(SpinLock::new(0));
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for _ in 0..THREAD_COUNT {
+        let lock_ = Arc::clone(&lock_);
+
+        let thread = thread::spawn(move || {
+            for _ in 0..JOB_COUNT / 10 {
+                lock_.with_lock(|data| {
+                    for _ in 0..100 {
+                        std::hint::spin_loop();
+                    }
+
+                    *data += 1;
+                });
             }
         });
 
@@ -58,8 +1041,3235 @@ fn main() {
     }
 
     println!(
-        "SpinLock: {} {}",
-        unsafe { *lock_.data.get() },
+        "SpinLock backoff: {} {}",
+        *lock_.lock(),
         unix_timestamp() - start
     );
+
+    #[cfg(feature = "stats")]
+    println!("{:?}", lock_.stats());
+}
+
+// A single thread taking and releasing the lock, so every acquisition goes
+// through the inlined fast path; shows its per-pair cost.
+#[cfg(not(loom))]
+fn bench_uncontended() {
+    let lock_ = SpinLock::new(0);
+    let pairs = JOB_COUNT * 100;
+    let start = Instant::now();
+
+    for _ in 0..pairs {
+        *lock_.lock() += 1;
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "SpinLock uncontended: {} {:.2}ns/pair",
+        *lock_.lock(),
+        elapsed.as_nanos() as f64 / pairs as f64
+    );
+
+    // The same counter through the out-of-line, closure-erased `with_lock_dyn`.
+    let start = Instant::now();
+
+    for _ in 0..pairs {
+        lock_.with_lock_dyn(&mut |value| *value += 1);
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "SpinLock with_lock_dyn: {} {:.2}ns/pair",
+        *lock_.lock(),
+        elapsed.as_nanos() as f64 / pairs as f64
+    );
+
+    // The same counter through the one-call `load`/`store`, and through an
+    // `AtomicSpinCell`, which has no lock word at all.
+    let start = Instant::now();
+
+    for _ in 0..pairs {
+        lock_.store(lock_.load() + 1);
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "SpinLock load/store: {} {:.2}ns/pair",
+        lock_.load(),
+        elapsed.as_nanos() as f64 / pairs as f64
+    );
+
+    let cell = AtomicSpinCell::new(0u64);
+    let start = Instant::now();
+
+    for _ in 0..pairs {
+        cell.store(std::hint::black_box(cell.load()) + 1);
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "AtomicSpinCell load/store: {} {:.2}ns/pair",
+        cell.load(),
+        elapsed.as_nanos() as f64 / pairs as f64
+    );
+}
+
+// Stealer threads take and release the lock back to back, so it keeps
+// flipping free and being taken before the waiter's CAS lands. Every call to
+// `lock_with_max_attempts` must still return, which the worst-case call
+// time shows. It is deprecated in favour of `acquire` but still supported.
+#[cfg(not(loom))]
+#[allow(deprecated)]
+fn bench_max_attempts() {
+    let lock_ = Arc::new(SpinLock::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut vec = Vec::new();
+
+    for _ in 0..4 {
+        let lock_ = Arc::clone(&lock_);
+        let stop = Arc::clone(&stop);
+
+        vec.push(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                lock_.with_lock(|data| *data += 1);
+            }
+        }));
+    }
+
+    let (mut acquired, mut failed, mut worst) = (0, 0, Duration::ZERO);
+
+    for _ in 0..(JOB_COUNT / 1000).max(1) {
+        let start = Instant::now();
+
+        match lock_.lock_with_max_attempts() {
+            Ok(_guard) => acquired += 1,
+            Err(_) => failed += 1,
+        }
+
+        worst = worst.max(start.elapsed());
+    }
+
+    stop.store(true, Ordering::Relaxed);
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!("SpinLock max attempts: {} acquired, {} failed, worst {:?}", acquired, failed, worst);
+
+    // Against a lock one holder keeps the whole time, the error must account
+    // for the full timeout and for every look finding it held.
+    let timeout = Duration::from_millis(50);
+    let guard = lock_.lock();
+    let waiter = {
+        let lock_ = Arc::clone(&lock_);
+
+        thread::spawn(move || {
+            let timed = lock_.with_lock_timeout(timeout, |_| ());
+            (timed, lock_.lock_with_max_attempts().err())
+        })
+    };
+    let (timed, bounded) = waiter.join().unwrap();
+    drop(guard);
+
+    let err = timed.unwrap_err();
+    assert!(err.waited() >= timeout, "reported {:?} for a {:?} timeout", err.waited(), timeout);
+    assert!(err.held_throughout() && err.slept() > 0 && err.attempts() > 1, "{}", err);
+    println!("with_lock_timeout: {}", err);
+
+    let err = bounded.unwrap();
+    assert!(err.held_throughout() && err.attempts() == 500, "{}", err);
+    println!("lock_with_max_attempts: {}", err);
+}
+
+const STRIPE_COUNT: usize = 16;
+
+// A hash table split into shards, one per stripe.
+struct StripedMap {
+    locks: LockStripe<STRIPE_COUNT>,
+    shards: Vec<UnsafeCell<HashMap<usize, usize>>>,
+}
+
+unsafe impl Sync for StripedMap {}
+
+impl StripedMap {
+    fn increment(&self, key: usize) {
+        let guard = self.locks.lock_for(&key);
+        let shard = unsafe { &mut *self.shards[guard.index()].get() };
+        *shard.entry(key).or_insert(0) += 1;
+    }
+
+    fn len(&self) -> usize {
+        let _all = self.locks.lock_all();
+        self.shards.iter().map(|shard| unsafe { (*shard.get()).len() }).sum()
+    }
+}
+
+// 16 threads updating a shared hash table, behind one SpinLock and then
+// split over 16 stripes.
+fn bench_stripes() {
+    const THREADS: usize = 16;
+    let jobs = (JOB_COUNT / 10).max(1);
+
+    let single = Arc::new(SpinLock::new(HashMap::new()));
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for t in 0..THREADS {
+        let single = Arc::clone(&single);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..jobs {
+                single.with_lock(|map| *map.entry((t * jobs + i) % 4096).or_insert(0) += 1);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!("SpinLock<HashMap>: {} keys {}", single.with_lock(|map| map.len()), unix_timestamp() - start);
+
+    let striped = Arc::new(StripedMap {
+        locks: LockStripe::new(),
+        shards: (0..STRIPE_COUNT).map(|_| UnsafeCell::new(HashMap::new())).collect(),
+    });
+    let start = unix_timestamp();
+
+    for t in 0..THREADS {
+        let striped = Arc::clone(&striped);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..jobs {
+                striped.increment((t * jobs + i) % 4096);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!("LockStripe<{}>: {} keys {}", STRIPE_COUNT, striped.len(), unix_timestamp() - start);
+
+    // A table of 64 independent locks, one per shard, built in one call.
+    let table: Arc<[SpinLock<HashMap<usize, usize>>; 64]> = Arc::new(SpinLock::array_of(HashMap::new()));
+    let start = unix_timestamp();
+
+    for t in 0..THREADS {
+        let table = Arc::clone(&table);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..jobs {
+                let key = (t * jobs + i) % 4096;
+                *table[key % 64].lock().entry(key).or_insert(0) += 1;
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    // Per-shard totals, collected into a run-time sized table of their own.
+    let totals = SpinLock::vec_from_iter(table.iter().map(|shard| shard.lock().values().sum::<usize>()));
+    assert_eq!(totals.len(), 64);
+    assert_eq!(totals.iter().map(SpinLock::load).sum::<usize>(), THREADS * jobs);
+    println!(
+        "[SpinLock<HashMap>; 64]: {} keys {}",
+        table.iter().map(|shard| shard.lock().len()).sum::<usize>(),
+        unix_timestamp() - start
+    );
+}
+
+// Writers keep both halves of the pair equal and check that no reader is
+// inside while they hold the lock; readers check that they never see a
+// half-written pair.
+fn stress_bravo() {
+    let lock_ = Arc::new(BravoRwLock::new((0u64, 0u64)));
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut vec = Vec::new();
+
+    for t in 0..8 {
+        let lock_ = Arc::clone(&lock_);
+        let active = Arc::clone(&active);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..(JOB_COUNT / 10).max(1) {
+                if t < 2 && i % 100 == 0 {
+                    let mut guard = lock_.write();
+                    assert_eq!(active.load(Ordering::SeqCst), 0, "writer overlapped a reader");
+                    guard.0 += 1;
+                    guard.1 += 1;
+                } else {
+                    let guard = lock_.read();
+                    active.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(guard.0, guard.1, "reader saw a torn write");
+                    active.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!("BravoRwLock stress: {} writes, no overlap", lock_.read().0);
+}
+
+// Read-only throughput with 1 to 8 threads, SpinRwLock against BravoRwLock.
+fn bench_bravo() {
+    stress_bravo();
+
+    for threads in [1, 2, 4, 8] {
+        let plain = Arc::new(SpinRwLock::new(0u64));
+        let start = unix_timestamp();
+        let mut vec = Vec::new();
+
+        for _ in 0..threads {
+            let plain = Arc::clone(&plain);
+            vec.push(thread::spawn(move || {
+                for _ in 0..JOB_COUNT {
+                    std::hint::black_box(*plain.read());
+                }
+            }));
+        }
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        let plain_elapsed = unix_timestamp() - start;
+        let bravo = Arc::new(BravoRwLock::new(0u64));
+        let start = unix_timestamp();
+
+        for _ in 0..threads {
+            let bravo = Arc::clone(&bravo);
+            vec.push(thread::spawn(move || {
+                for _ in 0..JOB_COUNT {
+                    std::hint::black_box(*bravo.read());
+                }
+            }));
+        }
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        println!(
+            "{} readers: SpinRwLock {} BravoRwLock {}",
+            threads,
+            plain_elapsed,
+            unix_timestamp() - start
+        );
+    }
+}
+
+// Two simulated nodes with two threads each hammer a CohortLock; the node
+// that is not favoured by the local handoffs must still get through.
+fn stress_cohort() {
+    let lock_ = Arc::new(CohortLock::with_nodes(2, [0u64; 2]));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut vec = Vec::new();
+
+    for t in 0..4 {
+        let lock_ = Arc::clone(&lock_);
+        let stop = Arc::clone(&stop);
+
+        vec.push(thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let mut guard = lock_.lock_on_node(t % 2);
+                let node = guard.node();
+                guard[node] += 1;
+            }
+        }));
+    }
+
+    thread::sleep(Duration::from_millis(200));
+    stop.store(true, Ordering::Relaxed);
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    let counts = *lock_.lock();
+    assert!(counts.iter().all(|&count| count > 0), "a node was starved: {:?}", counts);
+    println!("CohortLock per-node acquisitions: {:?}", counts);
+}
+
+// Under `numa`, on a machine with at least two nodes that have CPUs, first
+// runs THREAD_COUNT threads spread over the nodes, each pinned to its node's
+// CPUs, for PINNED_RUN against a SpinLock and then a CohortLock, and reports
+// each node's throughput. Then THREAD_COUNT unpinned threads increment a
+// counter behind each lock. With a single node the cohort lock is a plain
+// lock with a second acquisition, so that comparison shows nothing.
+fn bench_cohort() {
+    stress_cohort();
+
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    {
+        let nodes = numa_nodes();
+
+        if nodes.len() >= 2 {
+            bench_cohort_pinned(&nodes);
+        } else {
+            println!("cohort: {} node(s) with CPUs, so no pinned run; the numbers below show nothing about cohorts", nodes.len());
+        }
+    }
+    #[cfg(not(all(feature = "numa", target_os = "linux")))]
+    println!("cohort: the pinned run needs the numa feature on Linux; the numbers below show nothing about cohorts");
+
+    let jobs = (JOB_COUNT / 10).max(1);
+    let spin = Arc::new(SpinLock::new(0));
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for _ in 0..THREAD_COUNT {
+        let spin = Arc::clone(&spin);
+        vec.push(thread::spawn(move || {
+            for _ in 0..jobs {
+                spin.with_lock(|data| *data += 1);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!("SpinLock: {} {}", spin.with_lock(|data| *data), unix_timestamp() - start);
+
+    let cohort = Arc::new(CohortLock::new(0));
+    let start = unix_timestamp();
+
+    for _ in 0..THREAD_COUNT {
+        let cohort = Arc::clone(&cohort);
+        vec.push(thread::spawn(move || {
+            for _ in 0..jobs {
+                *cohort.lock() += 1;
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    println!(
+        "CohortLock ({} nodes): {} {}",
+        cohort.nodes(),
+        *cohort.lock(),
+        unix_timestamp() - start
+    );
+}
+
+#[cfg(all(feature = "numa", target_os = "linux"))]
+const PINNED_RUN: Duration = Duration::from_millis(500);
+
+#[cfg(all(feature = "numa", target_os = "linux"))]
+extern "C" {
+    fn sched_setaffinity(pid: i32, size: usize, mask: *const u64) -> i32;
+}
+
+// The NUMA nodes that have CPUs, with their CPUs, from
+// /sys/devices/system/node/node*/cpulist ("0-3,8-11").
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn numa_nodes() -> Vec<(usize, Vec<usize>)> {
+    let Ok(dir) = std::fs::read_dir("/sys/devices/system/node") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<(usize, Vec<usize>)> = dir
+        .flatten()
+        .filter_map(|entry| {
+            let node = entry.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            let list = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            let cpus: Vec<usize> = list
+                .trim()
+                .split(',')
+                .filter_map(|range| {
+                    let (first, last) = range.split_once('-').unwrap_or((range, range));
+                    Some(first.parse().ok()?..=last.parse().ok()?)
+                })
+                .flatten()
+                .collect();
+            (!cpus.is_empty()).then_some((node, cpus))
+        })
+        .collect();
+
+    nodes.sort();
+    nodes
+}
+
+// Restricts the calling thread to `cpus`; false if the kernel refused.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn pin_to(cpus: &[usize]) -> bool {
+    // A cpu_set_t: 1024 bits.
+    let mut mask = [0u64; 16];
+
+    for &cpu in cpus.iter().filter(|&&cpu| cpu < 1024) {
+        mask[cpu / 64] |= 1 << (cpu % 64);
+    }
+
+    unsafe { sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) == 0 }
+}
+
+// Runs `op` on THREAD_COUNT threads dealt round-robin over `nodes`, each
+// pinned to its node's CPUs, for PINNED_RUN. Returns each node's count.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn run_pinned(nodes: &[(usize, Vec<usize>)], op: impl Fn(usize) + Sync) -> Vec<usize> {
+    let stop = AtomicBool::new(false);
+    let counts: Vec<AtomicUsize> = nodes.iter().map(|_| AtomicUsize::new(0)).collect();
+
+    thread::scope(|scope| {
+        for t in 0..THREAD_COUNT {
+            let slot = t % nodes.len();
+            let (node, cpus) = &nodes[slot];
+            let (op, stop, counts) = (&op, &stop, &counts);
+
+            scope.spawn(move || {
+                assert!(pin_to(cpus), "could not pin a thread to node {}", node);
+                let mut done = 0;
+
+                while !stop.load(Ordering::Relaxed) {
+                    op(*node);
+                    done += 1;
+                }
+
+                counts[slot].fetch_add(done, Ordering::Relaxed);
+            });
+        }
+
+        thread::sleep(PINNED_RUN);
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    counts.into_iter().map(AtomicUsize::into_inner).collect()
+}
+
+// The pinned half of `bench_cohort`. Every cohort acquisition must also go
+// through the cohort of the node its thread is pinned to.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn bench_cohort_pinned(nodes: &[(usize, Vec<usize>)]) {
+    let spin = SpinLock::new(0usize);
+    let cohort = CohortLock::new(0usize);
+
+    let spin_counts = run_pinned(nodes, |_| spin.with_lock(|data| *data += 1));
+    let cohort_counts = run_pinned(nodes, |node| {
+        let mut guard = cohort.lock();
+        assert_eq!(guard.node(), node % cohort.nodes(), "locked through another node's cohort");
+        *guard += 1;
+    });
+
+    assert_eq!(spin.with_lock(|data| *data), spin_counts.iter().sum::<usize>());
+    assert_eq!(*cohort.lock(), cohort_counts.iter().sum::<usize>());
+
+    let millis = PINNED_RUN.as_millis() as usize;
+    for (name, counts) in [("SpinLock", &spin_counts), ("CohortLock", &cohort_counts)] {
+        let per_node: Vec<String> = nodes
+            .iter()
+            .zip(counts.iter())
+            .map(|((node, _), count)| format!("node {} {}/ms", node, count / millis))
+            .collect();
+        println!("{} pinned: {}, total {}/ms", name, per_node.join(", "), counts.iter().sum::<usize>() / millis);
+    }
+}
+
+// Read-mostly traffic for hardware lock elision: readers check that the
+// slots still add up while writers move units between them, through every
+// unlock path and a nested second lock, so both elided sections and the
+// fallback to real acquisitions are exercised. The totals must come out
+// right whether or not the CPU has RTM.
+fn bench_elision() {
+    #[cfg(target_arch = "x86_64")]
+    let rtm = std::is_x86_feature_detected!("rtm");
+    #[cfg(not(target_arch = "x86_64"))]
+    let rtm = false;
+
+    let lock_ = Arc::new(SpinLock::new([100u64; 8]));
+    let moves = Arc::new(SpinLock::new(0u64));
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for t in 0..8 {
+        let lock_ = Arc::clone(&lock_);
+        let moves = Arc::clone(&moves);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..(JOB_COUNT / 10).max(1) {
+                if i % 16 == 0 {
+                    let mut guard = lock_.lock();
+                    let (from, to) = ((t + i) % 8, (t + i + 1) % 8);
+
+                    if guard[from] > 0 {
+                        guard[from] -= 1;
+                        guard[to] += 1;
+                        *moves.lock() += 1;
+                    }
+
+                    match i % 64 {
+                        0 => SpinLockGuard::unlock_fair(guard),
+                        16 => SpinLockGuard::bump(&mut guard),
+                        _ => drop(guard),
+                    }
+                } else if i % 5 == 0 {
+                    if let Ok(guard) = lock_.try_lock() {
+                        assert_eq!(guard.iter().sum::<u64>(), 800, "try_lock saw a torn move");
+                    }
+                } else {
+                    let guard = lock_.lock();
+                    assert_eq!(guard.iter().sum::<u64>(), 800, "reader saw a torn move");
+                }
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(lock_.lock().iter().sum::<u64>(), 800);
+    println!(
+        "elision (RTM {}): {} moves, totals intact, {}",
+        if rtm { "available" } else { "not available" },
+        *moves.lock(),
+        unix_timestamp() - start
+    );
+}
+
+// Smoke test for the slow path's waiting: every section is long enough that
+// the other threads end up in the contended loop, and every other release is
+// a fair handoff, which a waiter has to notice without the flag changing. On
+// aarch64 the waits are `wfe`, elsewhere yields.
+fn bench_wait() {
+    let lock_ = Arc::new(SpinLock::new(0u64));
+    let jobs = (JOB_COUNT / 1000).max(1);
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for _ in 0..4 {
+        let lock_ = Arc::clone(&lock_);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..jobs {
+                let mut guard = lock_.lock();
+                let mut scratch = *guard;
+                hold_briefly(&mut scratch);
+                *guard += 1;
+
+                if i % 2 == 0 {
+                    SpinLockGuard::unlock_fair(guard);
+                }
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(*lock_.lock(), 4 * jobs as u64);
+    println!(
+        "contended waits ({}): {} acquisitions, {}",
+        if cfg!(target_arch = "aarch64") { "wfe" } else { "yield" },
+        4 * jobs,
+        unix_timestamp() - start
+    );
+}
+
+// The layout `stress_shared` places in a shared mapping; zero-filled, the
+// lock starts out unlocked.
+#[repr(C)]
+struct SharedCounter {
+    lock_: RawSharedSpinLock,
+    value: u64,
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn fork() -> i32;
+    fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    fn _exit(status: i32) -> !;
+}
+
+// Increments the shared counter `jobs` times under its lock, reading and
+// writing it in separate steps so that a missing exclusion loses updates.
+fn increment_shared(counter: *mut SharedCounter, jobs: usize) {
+    let lock_ = unsafe { RawSharedSpinLock::from_raw(std::ptr::addr_of_mut!((*counter).lock_)) };
+
+    for _ in 0..jobs {
+        lock_.with_lock(|| unsafe {
+            let value = std::ptr::read_volatile(std::ptr::addr_of!((*counter).value));
+            std::hint::spin_loop();
+            std::ptr::write_volatile(std::ptr::addr_of_mut!((*counter).value), value + 1);
+        });
+    }
+}
+
+// A parent and a forked child increment a counter in an anonymous
+// MAP_SHARED page, so the lock is all that keeps the two processes apart.
+#[cfg(target_os = "linux")]
+fn stress_shared(jobs: usize) -> u64 {
+    const PROT_READ_WRITE: i32 = 0x1 | 0x2;
+    const MAP_SHARED_ANONYMOUS: i32 = 0x01 | 0x20;
+
+    let page = unsafe {
+        mmap(std::ptr::null_mut(), 4096, PROT_READ_WRITE, MAP_SHARED_ANONYMOUS, -1, 0)
+    };
+    assert!(page as isize != -1, "mmap failed");
+    let counter = page as *mut SharedCounter;
+
+    let child = unsafe { fork() };
+    assert!(child >= 0, "fork failed");
+
+    if child == 0 {
+        increment_shared(counter, jobs);
+        unsafe { _exit(0) };
+    }
+
+    increment_shared(counter, jobs);
+
+    let mut status = 0;
+    assert_eq!(unsafe { waitpid(child, &mut status, 0) }, child);
+    assert_eq!(status, 0, "child process failed");
+
+    unsafe { (*counter).value }
+}
+
+// Elsewhere, two threads share a zeroed heap buffer instead.
+#[cfg(not(target_os = "linux"))]
+fn stress_shared(jobs: usize) -> u64 {
+    struct SendPtr(*mut SharedCounter);
+    unsafe impl Send for SendPtr {}
+
+    let mut buffer = vec![0u64; 2];
+    let counter = buffer.as_mut_ptr() as *mut SharedCounter;
+    let other = SendPtr(counter);
+    let thread = thread::spawn(move || {
+        let other = other;
+        increment_shared(other.0, jobs)
+    });
+
+    increment_shared(counter, jobs);
+    thread.join().unwrap();
+
+    unsafe { (*counter).value }
+}
+
+// A forked child takes the robust lock and exits without releasing it; the
+// parent must get the lock back, flagged as recovered.
+#[cfg(target_os = "linux")]
+fn robust_dead_child() {
+    const PROT_READ_WRITE: i32 = 0x1 | 0x2;
+    const MAP_SHARED_ANONYMOUS: i32 = 0x01 | 0x20;
+
+    let page = unsafe {
+        mmap(std::ptr::null_mut(), 4096, PROT_READ_WRITE, MAP_SHARED_ANONYMOUS, -1, 0)
+    };
+    assert!(page as isize != -1, "mmap failed");
+    let lock_ = unsafe { RobustSharedSpinLock::from_raw(page as *mut RobustSharedSpinLock) };
+
+    let child = unsafe { fork() };
+    assert!(child >= 0, "fork failed");
+
+    if child == 0 {
+        std::mem::forget(lock_.lock());
+        unsafe { _exit(0) };
+    }
+
+    let mut status = 0;
+    assert_eq!(unsafe { waitpid(child, &mut status, 0) }, child);
+    assert_eq!(lock_.owner(), Some(child as u32));
+
+    let guard = lock_.lock();
+    assert!(guard.recovered(), "lock held by a dead process was not recovered");
+    drop(guard);
+    assert!(!lock_.lock().recovered());
+    println!("RobustSharedSpinLock: recovered from exited child {}", child);
+}
+
+fn bench_shared() {
+    let jobs = (JOB_COUNT / 10).max(1);
+    let start = unix_timestamp();
+    let value = stress_shared(jobs);
+
+    assert_eq!(value, 2 * jobs as u64, "lost updates across processes");
+    println!("RawSharedSpinLock: {} increments from two processes, {}", value, unix_timestamp() - start);
+
+    #[cfg(target_os = "linux")]
+    robust_dead_child();
+
+    // A bogus owner written straight into the word: no process has a PID
+    // this large.
+    let mut word = 0x7fff_fff0u32;
+    let lock_ = unsafe { RobustSharedSpinLock::from_raw(&mut word as *mut u32 as *mut RobustSharedSpinLock) };
+    #[cfg(unix)]
+    let guard = lock_.lock();
+    #[cfg(not(unix))]
+    let guard = lock_.lock_as(1, Duration::from_millis(10), |_| false);
+    assert!(guard.recovered(), "lock held by a bogus owner was not recovered");
+    drop(guard);
+    assert_eq!(lock_.owner(), None);
+
+    // Owner tokens: two recoverers racing for a dead token, only one wins the
+    // takeover and the other then waits for it normally.
+    let lock_ = Arc::new(RobustSharedSpinLock::new());
+    let dead = lock_.try_lock_as(99).unwrap();
+    std::mem::forget(dead);
+    let recovered = Arc::new(AtomicUsize::new(0));
+    let mut vec = Vec::new();
+
+    for owner in [1, 2] {
+        let lock_ = Arc::clone(&lock_);
+        let recovered = Arc::clone(&recovered);
+
+        vec.push(thread::spawn(move || {
+            let guard = lock_.lock_as(owner, Duration::ZERO, |holder| holder != 99);
+
+            if guard.recovered() {
+                recovered.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(recovered.load(Ordering::Relaxed), 1, "both recoverers took over the lock");
+    println!("RobustSharedSpinLock: bogus owners recovered exactly once");
+}
+
+// Nested acquisitions from several threads: every lock below is taken inside
+// another, so with the `critical-section` feature each release must leave
+// its section in reverse order of entry.
+fn bench_critical_section() {
+    let table = Arc::new(SpinLock::vec_from_iter((0..8).map(|_| 0u64)));
+    let jobs = (JOB_COUNT / 1000).max(1);
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for t in 0..4 {
+        let table = Arc::clone(&table);
+
+        vec.push(thread::spawn(move || {
+            for i in 0..jobs {
+                let (a, b) = ((t + i) % 8, (t + i + 3) % 8);
+                table[a].with_two_locks(&table[b], |x, y| {
+                    *x += 1;
+                    *y += 1;
+                });
+
+                let mut guards = SpinLock::lock_many(&table, &[b, a, (a + 5) % 8]);
+                guards.iter_mut().for_each(|(_, value)| *value += 1);
+                drop(guards);
+
+                // Hand-nested guards, taken in index order and released fair
+                // in reverse.
+                let outer = table[a.min(b)].lock();
+                let mut inner = table[a.max(b)].lock();
+                *inner += 1;
+                SpinLockGuard::unlock_fair(inner);
+                SpinLockGuard::unlock_fair(outer);
+                table[b].swap(&table[(b + 1) % 8]);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    let total: u64 = table.iter().map(|lock_| *lock_.lock()).sum();
+    assert_eq!(total, 4 * jobs as u64 * 6);
+    println!(
+        "nested acquisitions ({}): {} rounds, {}",
+        if cfg!(feature = "critical-section") { "critical sections" } else { "plain" },
+        4 * jobs,
+        unix_timestamp() - start
+    );
+}
+
+// Round-trips nested locks through serde_json, snapshots a pair a writer
+// keeps equal while it mutates it, and checks that a poisoned lock refuses to
+// serialize.
+#[cfg(feature = "serde")]
+fn bench_serde() {
+    type Tree = Vec<(String, SpinLock<Vec<SpinLock<(u64, u64)>>>)>;
+
+    let tree: Tree = vec![
+        ("empty".to_string(), SpinLock::new(Vec::new())),
+        ("pairs".to_string(), SpinLock::new(vec![SpinLock::new((1, 2)), SpinLock::new((3, 4))])),
+    ];
+    let json = serde_json::to_string(&tree).unwrap();
+    let back: Tree = serde_json::from_str(&json).unwrap();
+    assert!(tree == back, "round trip changed the tree: {}", json);
+    assert!(!back[1].1.is_poisoned() && back[1].1.try_lock().is_ok());
+
+    let pair = Arc::new(SpinLock::new((0u64, 0u64)));
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (pair, stop) = (Arc::clone(&pair), Arc::clone(&stop));
+
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                pair.with_lock(|(a, b)| {
+                    *a += 1;
+                    let mut scratch = *a;
+                    hold_briefly(&mut scratch);
+                    *b += 1;
+                });
+            }
+        })
+    };
+    let snapshots = (JOB_COUNT / 1000).max(1);
+
+    for _ in 0..snapshots {
+        let (a, b): (u64, u64) = serde_json::from_str(&serde_json::to_string(&*pair).unwrap()).unwrap();
+        assert_eq!(a, b, "snapshot caught a write halfway");
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    let poisoned = SpinLock::new(0u64);
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = poisoned.lock();
+        panic!("poisoning the lock on purpose");
+    }));
+    std::panic::set_hook(hook);
+    assert!(serde_json::to_string(&poisoned).is_err(), "poisoned lock serialized");
+    println!("serde: round trip ok, {} consistent snapshots, poisoned lock refused", snapshots);
+}
+
+// Takes every kind of guard (and the raw locks) in turn and checks that the
+// thread's held-lock count follows, then that `assert_no_locks_held` panics
+// naming the locks still held.
+#[cfg(feature = "held_locks")]
+fn held_locks() {
+    use spin_lock::{
+        assert_no_locks_held, held_lock_count, PackedSpinLockU16, PackedSpinLockU8,
+        RobustSharedSpinLock, SpinLockU8,
+    };
+
+    fn holding<G>(name: &str, expected: usize, guard: G) {
+        assert_eq!(held_lock_count(), expected, "while holding {}", name);
+        drop(guard);
+        assert_eq!(held_lock_count(), 0, "after dropping {}", name);
+    }
+
+    let spin = SpinLock::new((1u32, 2u32));
+    holding("SpinLockGuard", 1, spin.lock());
+    holding("MappedSpinLockGuard", 1, SpinLockGuard::map(spin.lock(), |pair| &mut pair.0));
+    let mut guard = spin.lock();
+    SpinLockGuard::unlocked(&mut guard, || assert_eq!(held_lock_count(), 0));
+    holding("SpinLockGuard after unlocked", 1, guard);
+    let table = SpinLock::array_of::<4>(0u32);
+    holding("LockManyGuard", 3, SpinLock::lock_many(&table, &[0, 2, 3]));
+    assert_eq!(spin.with_lock(|_| held_lock_count()), 1);
+
+    let rw = SpinRwLock::new(0u32);
+    let first = rw.read();
+    let second = rw.read();
+    assert_eq!(held_lock_count(), 2);
+    drop(first);
+    holding("second SpinRwLockReadGuard", 1, second);
+    let writer = rw.write();
+    holding("downgraded SpinRwLockWriteGuard", 1, spin_lock::SpinRwLockWriteGuard::downgrade(writer));
+    let bravo = BravoRwLock::new(0u32);
+    holding("BravoRwLockReadGuard", 1, bravo.read());
+    holding("BravoRwLockWriteGuard", 1, bravo.write());
+    holding("BravoRwLockReadGuard after a write", 1, bravo.read());
+
+    let priority = PrioritySpinLock::new(0u32);
+    holding("PrioritySpinLockGuard", 1, priority.lock());
+    holding("priority PrioritySpinLockGuard", 1, priority.lock_priority());
+    let cohort = CohortLock::new(0u32);
+    holding("CohortLockGuard", 1, cohort.lock());
+    let stripes = LockStripe::<4>::new();
+    holding("StripeGuard", 1, stripes.lock_for(&7u32));
+    holding("StripeAllGuard", 4, stripes.lock_all());
+    let small = PackedSpinLockU8::new(1);
+    holding("PackedSpinLockU8Guard", 1, small.lock());
+    let wide = PackedSpinLockU16::new(1);
+    holding("PackedSpinLockU16Guard", 1, wide.lock());
+    let byte = SpinLockU8::new(0u32);
+    assert_eq!(byte.with_lock(|_| held_lock_count()), 1);
+    let robust = RobustSharedSpinLock::new();
+    holding("RobustSharedSpinLockGuard", 1, robust.try_lock_as(7).unwrap());
+
+    let raw = RawSpinLock::new();
+    raw.lock();
+    assert_eq!(held_lock_count(), 1);
+    unsafe { raw.unlock() };
+    let shared = RawSharedSpinLock::new();
+    assert_eq!(shared.with_lock(held_lock_count), 1);
+    assert_no_locks_held();
+
+    let config = SpinLock::new_named("config", 0u32);
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let report = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _config = config.lock();
+        let _reader = rw.read();
+        assert_no_locks_held();
+    }))
+    .unwrap_err();
+    std::panic::set_hook(hook);
+    let report = report.downcast::<String>().unwrap();
+    assert!(report.contains(" 2 spin lock(s): "), "{}", report);
+    assert!(report.contains("'config'"), "{}", report);
+    assert!(report.contains(&format!("{:#x}", &rw as *const SpinRwLock<u32> as usize)), "{}", report);
+    assert_eq!(held_lock_count(), 0);
+    println!("held_locks: every guard tracked; {}", report);
+}
+
+// A sampler reads a counter with `try_with_lock` while two workers keep
+// the lock busy part of the time, skipping rather than waiting whenever it is held. Then the
+// single-threaded cases: held and free, a panicking closure, and
+// `try_with_lock_for` with and without the lock coming free in time.
+fn bench_sampler() {
+    let counter = Arc::new(SpinLock::new(0u64));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = unix_timestamp();
+
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            let counter = Arc::clone(&counter);
+            let stop = Arc::clone(&stop);
+
+            thread::spawn(move || {
+                let mut scratch = 0;
+
+                while !stop.load(Ordering::Relaxed) {
+                    let mut guard = counter.lock();
+                    *guard += 1;
+                    hold_briefly(&mut scratch);
+                    drop(guard);
+                    hold_briefly(&mut scratch);
+                }
+            })
+        })
+        .collect();
+
+    let (mut taken, mut skipped, mut scratch) = (0, 0, 0);
+
+    for _ in 0..(JOB_COUNT / 100).max(1) {
+        match counter.try_with_lock(|count| *count) {
+            Some(_) => taken += 1,
+            None => skipped += 1,
+        }
+        hold_briefly(&mut scratch);
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    workers.into_iter().for_each(|worker| worker.join().unwrap());
+
+    let guard = counter.lock();
+    assert_eq!(counter.try_with_lock(|count| *count), None);
+    drop(guard);
+    assert!(counter.try_with_lock(|count| *count).is_some());
+
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        counter.try_with_lock(|_| panic!("panicking inside try_with_lock on purpose"))
+    }));
+    std::panic::set_hook(hook);
+    assert!(counter.is_poisoned());
+    assert!(counter.try_with_lock(|_| ()).is_some(), "the panic left the lock held");
+
+    let guard = counter.lock();
+    let waiter = {
+        let counter = Arc::clone(&counter);
+
+        thread::spawn(move || {
+            let short = counter.try_with_lock_for(Duration::from_millis(5), |_| ());
+            let long = counter.try_with_lock_for(Duration::from_secs(5), |_| ());
+            (short, long)
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    drop(guard);
+    assert_eq!(waiter.join().unwrap(), (None, Some(())));
+    println!(
+        "try_with_lock: {} samples taken, {} skipped, {}",
+        taken,
+        skipped,
+        unix_timestamp() - start
+    );
+}
+
+// Lanes of one generation, counted while alive so the end of the run can
+// check that every replaced value was freed exactly once.
+static LIVE_LANES: AtomicUsize = AtomicUsize::new(0);
+
+struct Lanes([u64; 8]);
+
+impl Lanes {
+    fn new(generation: u64) -> Lanes {
+        LIVE_LANES.fetch_add(1, Ordering::Relaxed);
+        Lanes([generation; 8])
+    }
+
+    fn generation(&self) -> u64 {
+        let generation = self.0[0];
+        assert!(self.0.iter().all(|&lane| lane == generation), "torn value {:?}", self.0);
+        generation
+    }
+}
+
+impl Drop for Lanes {
+    fn drop(&mut self) {
+        LIVE_LANES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Four readers spin on `RcuCell::read` while two writers publish 5,000 new
+// generations each, one through `update` and one through a
+// `compare_and_swap` retry loop. Every snapshot must be a whole generation,
+// no newer than the next one read, and no update may be lost or leaked.
+fn bench_rcu() {
+    let cell = Arc::new(RcuCell::new(Lanes::new(0)));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = unix_timestamp();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let cell = Arc::clone(&cell);
+            let stop = Arc::clone(&stop);
+
+            thread::spawn(move || {
+                let (mut last, mut reads) = (0, 0u64);
+
+                while !stop.load(Ordering::Relaxed) {
+                    let generation = cell.read().generation();
+                    assert!(generation >= last, "went back from {} to {}", last, generation);
+                    last = generation;
+                    reads += 1;
+                }
+
+                reads
+            })
+        })
+        .collect();
+
+    let updater = {
+        let cell = Arc::clone(&cell);
+
+        thread::spawn(move || {
+            for _ in 0..5000 {
+                cell.update(|lanes| Lanes::new(lanes.generation() + 1));
+            }
+        })
+    };
+
+    let mut retries = 0;
+
+    for _ in 0..5000 {
+        let mut current = cell.read();
+
+        while let Err(lanes) = cell.compare_and_swap(&current, Lanes::new(current.generation() + 1)) {
+            drop(lanes);
+            retries += 1;
+            current = cell.read();
+        }
+    }
+
+    updater.join().unwrap();
+    stop.store(true, Ordering::Relaxed);
+    let reads: u64 = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+
+    let snapshot = cell.read();
+    assert_eq!(snapshot.generation(), 10000);
+    cell.replace(Lanes::new(10001));
+    assert_eq!(snapshot.generation(), 10000, "a held snapshot changed");
+    drop(snapshot);
+    assert_eq!(LIVE_LANES.load(Ordering::Relaxed), 1);
+    drop(cell);
+    assert_eq!(LIVE_LANES.load(Ordering::Relaxed), 0);
+    println!(
+        "rcu: {} reads, 10000 updates, {} compare_and_swap retries, {}",
+        reads,
+        retries,
+        unix_timestamp() - start
+    );
+}
+
+// A holder steps through a batch calling `bump` after every step until a
+// thread waiting for the lock has had a turn, which must come inside one of
+// the bumps. Then times `bump` with no one waiting, where it only reads the
+// waiter count.
+fn bench_bump() {
+    let lock_ = Arc::new(SpinLock::new((0u64, None)));
+    let mut guard = lock_.lock();
+    assert!(std::ptr::eq(SpinLockGuard::lock_ref(&guard), &*lock_));
+
+    let waiter = {
+        let lock_ = Arc::clone(&lock_);
+
+        thread::spawn(move || {
+            let mut guard = lock_.lock();
+            guard.1 = Some(guard.0);
+        })
+    };
+
+    let (mut scratch, start) = (0, Instant::now());
+
+    while guard.1.is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5), "the waiter never got the lock mid-batch");
+        guard.0 += 1;
+        hold_briefly(&mut scratch);
+        SpinLockGuard::bump(&mut guard);
+    }
+
+    let (entered, steps) = (guard.1.unwrap(), guard.0);
+    assert_eq!(entered, steps, "the waiter did not run inside a bump");
+    drop(guard);
+    waiter.join().unwrap();
+
+    let mut guard = lock_.lock();
+    let start = Instant::now();
+
+    for _ in 0..JOB_COUNT {
+        SpinLockGuard::bump(&mut guard);
+    }
+
+    let per_bump = start.elapsed().as_nanos() as f64 / JOB_COUNT as f64;
+    drop(guard);
+    println!("bump: waiter entered after step {}, uncontended bump {:.2} ns", entered, per_bump);
+}
+
+// A guard taken on this thread is written through and dropped on another
+// one, after which the lock must be free again. With `held_locks` the
+// process-wide count must follow the guard across the handoff.
+#[cfg(feature = "send_guard")]
+fn send_guard() {
+    let lock_ = SpinLock::new(0u32);
+    let guard = lock_.lock();
+    #[cfg(feature = "held_locks")]
+    assert_eq!(spin_lock::held_lock_count(), 1);
+
+    thread::scope(|scope| {
+        scope
+            .spawn(move || {
+                let mut guard = guard;
+                *guard += 1;
+                #[cfg(feature = "held_locks")]
+                assert_eq!(spin_lock::held_lock_count(), 1);
+            })
+            .join()
+            .unwrap();
+    });
+
+    assert!(lock_.try_lock().is_ok(), "the sent guard did not release the lock");
+    #[cfg(feature = "held_locks")]
+    spin_lock::assert_no_locks_held();
+    assert_eq!(lock_.into_inner(), 1);
+    println!("send_guard: a guard moved to another thread and released there ok");
+}
+
+// Two writers keep every lane of an array equal, writing them one at a time
+// under the lock and now and then yielding halfway, while four readers take
+// `read_snapshot`s and check that none is torn and that each reader's
+// snapshots never go back. The array is 4 KiB so that, even on one CPU,
+// readers are often preempted mid-copy and a write lands under the copy.
+// Then checks that a snapshot taken while the lock is held waits for the
+// holder, and times uncontended snapshots of a pair against `load`.
+#[cfg(feature = "seqlock")]
+fn snapshot() {
+    let lock_ = Arc::new(SpinLock::new([0u64; 512]));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = unix_timestamp();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let lock_ = Arc::clone(&lock_);
+            let stop = Arc::clone(&stop);
+
+            thread::spawn(move || {
+                let (mut last, mut reads) = (0, 0u64);
+
+                while !stop.load(Ordering::Relaxed) {
+                    let lanes = lock_.read_snapshot();
+                    let torn = lanes.iter().position(|&lane| lane != lanes[0]);
+                    assert!(torn.is_none(), "torn snapshot: lane {:?} differs from {}", torn, lanes[0]);
+                    assert!(lanes[0] >= last, "went back from {} to {}", last, lanes[0]);
+                    last = lanes[0];
+                    reads += 1;
+                }
+
+                reads
+            })
+        })
+        .collect();
+
+    let writers: Vec<_> = (0..2)
+        .map(|_| {
+            let lock_ = Arc::clone(&lock_);
+
+            thread::spawn(move || {
+                for write in 0..20000 {
+                    let mut guard = lock_.lock();
+                    let next = guard[0] + 1;
+
+                    for (index, lane) in guard.iter_mut().enumerate() {
+                        // Lets readers run in the middle of a write, even on
+                        // one CPU.
+                        if write % 100 == 0 && index == 8 {
+                            thread::yield_now();
+                        }
+
+                        *std::hint::black_box(lane) = next;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    let reads: u64 = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+    assert_eq!(lock_.read_snapshot(), [40000; 512]);
+
+    let guard = lock_.lock();
+    let waiter = {
+        let lock_ = Arc::clone(&lock_);
+        thread::spawn(move || lock_.read_snapshot()[0])
+    };
+    thread::sleep(Duration::from_millis(20));
+    let mut guard = guard;
+    guard.fill(40001);
+    drop(guard);
+    assert_eq!(waiter.join().unwrap(), 40001, "a snapshot read past the holder");
+
+    let pair = SpinLock::new((1u64, 2u64));
+    let timed = |name, read: &dyn Fn() -> (u64, u64)| {
+        let began = Instant::now();
+
+        for _ in 0..1_000_000 {
+            std::hint::black_box(read());
+        }
+
+        println!("snapshot: uncontended {} {:.1} ns", name, began.elapsed().as_nanos() as f64 / 1e6);
+    };
+    timed("read_snapshot", &|| pair.read_snapshot());
+    timed("load", &|| pair.load());
+    println!("snapshot: {} untorn snapshots across 40000 writes, {}", reads, unix_timestamp() - start);
+}
+
+// What a C caller keeps next to its handle: data of its own that the lock
+// protects by convention only.
+#[cfg(feature = "ffi")]
+struct CShared {
+    lock: *mut spin_lock::CSpinLock,
+    counter: UnsafeCell<u64>,
+}
+
+#[cfg(feature = "ffi")]
+unsafe impl Sync for CShared {}
+
+// Drives the C interface only through raw handles and status codes, as C
+// code linked against the library would: the result of each call on a free,
+// held and null handle, then four threads incrementing a counter that only
+// the handle protects.
+#[cfg(feature = "ffi")]
+fn ffi() {
+    use spin_lock::{
+        spinlock_free, spinlock_lock, spinlock_new, spinlock_try_lock, spinlock_unlock,
+        SPINLOCK_BUSY, SPINLOCK_NOT_LOCKED, SPINLOCK_NULL, SPINLOCK_OK,
+    };
+
+    let shared = CShared { lock: spinlock_new(), counter: UnsafeCell::new(0) };
+    assert!(!shared.lock.is_null());
+
+    unsafe {
+        assert_eq!(spinlock_try_lock(shared.lock), SPINLOCK_OK);
+        assert_eq!(spinlock_try_lock(shared.lock), SPINLOCK_BUSY);
+        assert_eq!(spinlock_unlock(shared.lock), SPINLOCK_OK);
+        assert_eq!(spinlock_unlock(shared.lock), SPINLOCK_NOT_LOCKED);
+        assert_eq!(spinlock_lock(std::ptr::null()), SPINLOCK_NULL);
+        assert_eq!(spinlock_try_lock(std::ptr::null()), SPINLOCK_NULL);
+        assert_eq!(spinlock_unlock(std::ptr::null()), SPINLOCK_NULL);
+        spinlock_free(std::ptr::null_mut());
+    }
+
+    let start = unix_timestamp();
+
+    thread::scope(|scope| {
+        // Borrowed whole, since a closure would capture the fields apart.
+        let shared = &shared;
+
+        for _ in 0..4 {
+            scope.spawn(move || {
+                for _ in 0..JOB_COUNT / 4 {
+                    unsafe {
+                        assert_eq!(spinlock_lock(shared.lock), SPINLOCK_OK);
+                        *shared.counter.get() += 1;
+                        assert_eq!(spinlock_unlock(shared.lock), SPINLOCK_OK);
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(shared.counter.into_inner(), (JOB_COUNT / 4 * 4) as u64);
+    unsafe { spinlock_free(shared.lock) };
+    println!("ffi: {} increments through C handles, {}", JOB_COUNT / 4 * 4, unix_timestamp() - start);
+}
+
+// Two tasks on a single-worker runtime take turns on one lock, each holding
+// the guard across an `.await` and yielding after releasing it. With the
+// blocking `lock` the waiting task would spin on the only worker and the
+// holder could never run again; `lock_async_tokio` must let both finish.
+// Then one task holds the lock across a 20 ms sleep, long enough for the
+// other's backoff to reach its sleeps.
+#[cfg(feature = "tokio")]
+fn tokio_lock() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    let lock_ = SpinLock::new((0u32, 0u32, Vec::new()));
+    let start = unix_timestamp();
+
+    let task = |id: u8| {
+        let lock_ = &lock_;
+
+        async move {
+            for _ in 0..1000 {
+                let mut guard = lock_.lock_async_tokio().await;
+                let (first, second, order) = &mut *guard;
+                *(if id == 0 { first } else { second }) += 1;
+                order.push(id);
+                tokio::task::yield_now().await;
+                drop(guard);
+                tokio::task::yield_now().await;
+            }
+        }
+    };
+
+    runtime.block_on(async {
+        tokio::time::timeout(Duration::from_secs(10), async { tokio::join!(task(0), task(1)) })
+            .await
+            .expect("two tasks on one worker did not both finish");
+    });
+
+    let (first, second, order) = lock_.into_inner();
+    assert_eq!((first, second), (1000, 1000));
+    let turns = order.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    assert!(turns > 100, "the tasks only took {} turns", turns);
+
+    let lock_ = SpinLock::new(0u32);
+    let waited = runtime.block_on(async {
+        let holder = async {
+            let mut guard = lock_.lock_async_tokio().await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            *guard += 1;
+        };
+        let waiter = async {
+            tokio::task::yield_now().await;
+            let asked = Instant::now();
+            let guard = lock_.lock_async_tokio().await;
+            assert_eq!(*guard, 1, "the waiter got in during the hold");
+            asked.elapsed()
+        };
+        tokio::join!(holder, waiter).1
+    });
+
+    println!(
+        "tokio: 2 tasks x 1000 acquisitions on one worker, {} turns; waited {:?} out a 20 ms hold, {}",
+        turns,
+        waited,
+        unix_timestamp() - start
+    );
+}
+
+// A phase fits in one byte with no padding, so it may live in the word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Phase {
+    Idle,
+    Running,
+    Done,
+}
+
+unsafe impl spin_lock::AtomicPayload for Phase {}
+
+// `AtomicSpinCell` over a `u32`, an `f64` and a fieldless enum: each
+// operation's return value, the bitwise float comparison, a flag published
+// with explicit orderings, and relaxed counting from several threads.
+fn atomic_cell() {
+    let count = AtomicSpinCell::new(7u32);
+    assert_eq!(count.swap(8), 7);
+    assert_eq!(count.compare_exchange(7, 9), Err(8));
+    assert_eq!(count.compare_exchange(8, 9), Ok(8));
+    assert_eq!(count.update(|value| value * 2), 9);
+    assert_eq!(count.into_inner(), 18);
+
+    let float = AtomicSpinCell::new(0.0f64);
+    assert_eq!(float.compare_exchange(-0.0, 1.0), Err(0.0), "0.0 and -0.0 compared equal");
+    float.store(f64::NAN);
+    assert!(float.compare_exchange(f64::NAN, 2.5).unwrap().is_nan(), "a NaN did not match itself");
+    assert_eq!(float.update(|value| value + 0.25), 2.5);
+    assert_eq!(float.load(), 2.75);
+
+    let phase = AtomicSpinCell::new(Phase::Idle);
+    assert_eq!(phase.compare_exchange(Phase::Running, Phase::Done), Err(Phase::Idle));
+    assert_eq!(phase.swap(Phase::Running), Phase::Idle);
+    assert_eq!(format!("{:?}", phase), "AtomicSpinCell { value: Running }");
+
+    // A `Release` store of the phase publishes the plain write made before it.
+    let result = AtomicSpinCell::new(0u64);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            result.store_with(42, Ordering::Relaxed);
+            phase.store_with(Phase::Done, Ordering::Release);
+        });
+
+        while phase.load_with(Ordering::Acquire) != Phase::Done {
+            std::hint::spin_loop();
+        }
+
+        assert_eq!(result.load_with(Ordering::Relaxed), 42);
+    });
+
+    let hits = AtomicSpinCell::new(0usize);
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..10000 {
+                    let mut seen = hits.load_with(Ordering::Relaxed);
+
+                    while let Err(actual) =
+                        hits.compare_exchange_with(seen, seen + 1, Ordering::Relaxed, Ordering::Relaxed)
+                    {
+                        seen = actual;
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(hits.swap_with(0, Ordering::Relaxed), 40000);
+    println!("cell: u32, f64 and enum payloads ok, 40000 relaxed increments");
+}
+
+// One value on each side of the cutoff in a `static`, which shows that
+// `new` is `const` on both paths.
+#[cfg(not(loom))]
+static NARROW: AtomicCellCompat<[u8; 8]> = AtomicCellCompat::new([1; 8]);
+#[cfg(not(loom))]
+static WIDE: AtomicCellCompat<u128> = AtomicCellCompat::new(1);
+
+// Runs every `AtomicCellCompat` operation on a `T`, where `a` and `b`
+// differ, and checks which path it took.
+fn check_compat<T: AtomicPayload + PartialEq + fmt::Debug>(a: T, b: T, lock_free: bool) {
+    assert_eq!(AtomicCellCompat::<T>::is_lock_free(), lock_free, "{}", std::any::type_name::<T>());
+
+    let cell = AtomicCellCompat::new(a);
+    assert_eq!(cell.load(), a);
+    assert_eq!(cell.swap(b), a);
+    assert_eq!(cell.compare_exchange(a, a), Err(b));
+    assert_eq!(cell.compare_exchange(b, a), Ok(b));
+    cell.store(b);
+    assert_eq!(cell.update(|_| a), b);
+    assert_eq!(cell.with_lock(|value| std::mem::replace(value, b)), a);
+    assert_eq!(cell.into_inner(), b);
+}
+
+// Exercises `AtomicCellCompat` on 1-, 2-, 4-, 8- and 16-byte payloads and on
+// 3- and 12-byte arrays, then has four threads count through a lock-free
+// `u32` and a fallback `[u64; 2]` while a reader checks that it never sees
+// the two halves of the wide value disagree.
+fn cell_compat() {
+    check_compat(1u8, 2, true);
+    check_compat(1u16, 2, true);
+    check_compat(1.5f32, -1.5, true);
+    check_compat(1u64, u64::MAX, true);
+    check_compat([1u8, 2, 3], [3, 2, 1], true);
+    check_compat([1u32, 2, 3], [3, 2, 1], false);
+    check_compat(1u128, u128::MAX, false);
+    check_compat([1u64, 2], [2, 1], false);
+
+    let nan = AtomicCellCompat::new([f64::NAN, 0.0]);
+    assert!(nan.compare_exchange([f64::NAN, -0.0], [1.0, 1.0]).is_err(), "0.0 and -0.0 compared equal");
+    assert!(nan.compare_exchange([f64::NAN, 0.0], [1.0, 1.0]).is_ok(), "a NaN did not match itself");
+
+    #[cfg(not(loom))]
+    {
+        assert_eq!(NARROW.swap([2; 8]), [1; 8]);
+        assert_eq!(WIDE.update(|value| value << 64), 1);
+        assert_eq!(WIDE.load(), 1 << 64);
+    }
+
+    let narrow = AtomicCellCompat::new(0u32);
+    let wide = AtomicCellCompat::new([0u64; 2]);
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut reads = 0;
+
+            while !done.load(Ordering::Acquire) || reads == 0 {
+                let [low, high] = wide.load();
+                assert_eq!(low, high, "a torn fallback load");
+                reads += 1;
+            }
+        });
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    for _ in 0..10000 {
+                        narrow.update(|value| value + 1);
+                        wide.with_lock(|[low, high]| {
+                            *low += 1;
+                            *high += 1;
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        done.store(true, Ordering::Release);
+    });
+
+    assert_eq!(narrow.load(), 40000);
+    assert_eq!(wide.load(), [40000; 2]);
+    assert_eq!(format!("{:?}", narrow), "AtomicCellCompat { value: 40000, lock_free: true }");
+    println!("cell_compat: 1- to 16-byte payloads ok, 40000 increments on each path");
+}
+
+#[cfg(all(feature = "priority_ceiling", target_os = "linux"))]
+extern "C" {
+    fn pthread_self() -> usize;
+    fn pthread_getschedparam(thread: usize, policy: *mut i32, param: *mut i32) -> i32;
+    fn pthread_setschedparam(thread: usize, policy: i32, param: *const i32) -> i32;
+}
+
+// The calling thread's policy and priority (`SCHED_OTHER` is 0, `SCHED_FIFO`
+// 1 and `SCHED_RR` 2 on Linux).
+#[cfg(all(feature = "priority_ceiling", target_os = "linux"))]
+fn schedule() -> (i32, i32) {
+    let (mut policy, mut priority) = (0, 0);
+    assert_eq!(unsafe { pthread_getschedparam(pthread_self(), &mut policy, &mut priority) }, 0);
+    (policy, priority)
+}
+
+#[cfg(all(feature = "priority_ceiling", target_os = "linux"))]
+static CEILING_WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+// Checks the priority bookkeeping of `CeilingSpinLock` on a fresh thread:
+// nested locks released out of order, a lock below the priority in force,
+// a failed `try_lock`, a `SCHED_RR` thread and a ceiling the kernel rejects,
+// which must warn once and then lock plainly. Without permission to use
+// realtime priorities it checks instead that the locks still work and that
+// the thread's priority never moves.
+#[cfg(all(feature = "priority_ceiling", target_os = "linux"))]
+fn ceiling() {
+    use spin_lock::CeilingSpinLock;
+
+    spin_lock::set_ceiling_warning_hook(Some(|_| {
+        CEILING_WARNINGS.fetch_add(1, Ordering::Relaxed);
+    }));
+
+    thread::spawn(|| {
+        let base = schedule();
+        let (a, b, c) = (CeilingSpinLock::new(1, 30), CeilingSpinLock::new(2, 20), CeilingSpinLock::new(3, 40));
+
+        let guard_a = a.lock();
+
+        if schedule() == base {
+            assert_eq!(CEILING_WARNINGS.load(Ordering::Relaxed), 1, "a failed raise was not reported");
+            let guard_c = c.lock();
+            assert_eq!((*guard_a, *guard_c, schedule()), (1, 3, base));
+            drop((guard_a, guard_c));
+            println!("ceiling: realtime priorities unavailable, locked without raising");
+            return;
+        }
+
+        assert_eq!(schedule(), (1, 30));
+        let guard_b = b.lock();
+        assert_eq!(schedule(), (1, 30), "a lower ceiling changed the priority");
+        let guard_c = c.lock();
+        assert_eq!(schedule(), (1, 40));
+        drop(guard_a);
+        assert_eq!(schedule(), (1, 40), "releasing a lower ceiling changed the priority");
+        drop(guard_c);
+        assert_eq!(schedule(), (1, 20), "the priority did not fall to the ceiling still held");
+        drop(guard_b);
+        assert_eq!(schedule(), base);
+
+        let guard_a = a.lock();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let own = schedule();
+                assert!(a.try_lock().is_none());
+                assert_eq!(schedule(), own, "a failed try_lock kept the ceiling");
+            });
+        });
+        drop(guard_a);
+
+        assert_eq!(unsafe { pthread_setschedparam(pthread_self(), 2, &10) }, 0);
+        assert_eq!(*CeilingSpinLock::new(4, 5).lock(), 4);
+        assert_eq!(schedule(), (2, 10), "a ceiling below SCHED_RR 10 changed the priority");
+        a.with_lock(|value| *value += 1);
+        assert_eq!(schedule(), (2, 10), "SCHED_RR 10 was not restored");
+        assert_eq!(unsafe { pthread_setschedparam(pthread_self(), base.0, &base.1) }, 0);
+
+        let rejected = CeilingSpinLock::new(5, 1000);
+        assert_eq!(*rejected.lock(), 5);
+        assert_eq!(rejected.with_lock(|value| *value), 5);
+        assert_eq!(CEILING_WARNINGS.load(Ordering::Relaxed), 1, "not warned exactly once");
+        let guard_c = c.lock();
+        assert_eq!(schedule(), (1, 40), "a rejected ceiling turned raising off");
+        drop(guard_c);
+        assert_eq!(schedule(), base);
+        println!("ceiling: nested raises and restores ok, rejected ceiling warned once");
+    })
+    .join()
+    .unwrap();
+}
+
+// A producer pushes 50 values, 10 ms apart, each followed by a read-only
+// hold, while a consumer follows along with `wait_for_change`. The consumer
+// must account for every version, find exactly `version` values under each
+// guard, and leave the process mostly idle while it waits.
+#[cfg(all(feature = "versioned", not(loom)))]
+fn versioned() {
+    const MUTATIONS: u64 = 50;
+
+    let lock_ = SpinLock::new(Vec::new());
+    let before = cpu_time();
+    let start = Instant::now();
+    let (wakeups, observed) = thread::scope(|scope| {
+        let consumer = scope.spawn(|| {
+            let (mut last_seen, mut wakeups) = (0, 0);
+
+            while last_seen < MUTATIONS {
+                let (guard, version) = lock_.wait_for_change(last_seen);
+                assert!(version > last_seen);
+                assert_eq!(guard.len() as u64, version, "the version does not match the data");
+                last_seen = version;
+                wakeups += 1;
+            }
+
+            (wakeups, last_seen)
+        });
+
+        for value in 0..MUTATIONS {
+            thread::sleep(Duration::from_millis(10));
+            lock_.lock().push(value);
+            assert_eq!(lock_.lock().len() as u64, value + 1);
+        }
+
+        consumer.join().unwrap()
+    });
+    let elapsed = start.elapsed();
+
+    assert_eq!(observed, MUTATIONS);
+    assert_eq!(lock_.version(), MUTATIONS, "a read-only hold bumped the version");
+
+    if let (Some(before), Some(after)) = (before, cpu_time()) {
+        assert!(after - before < elapsed / 2, "waiting used {:?} of CPU in {:?}", after - before, elapsed);
+    }
+
+    println!(
+        "versioned: {} versions seen in {} wakeups over {:?}, {:?} of CPU",
+        observed,
+        wakeups,
+        elapsed,
+        cpu_time().zip(before).map(|(after, before)| after - before)
+    );
+}
+
+// Records every `modify_logged` call on a lock through `on_modify`, then
+// checks the before and after values, that four threads' modifications reach
+// the callback in order, that a replaced callback goes quiet and that a
+// panicking callback poisons the lock.
+#[cfg(feature = "audit")]
+fn audit() {
+    use std::sync::Mutex;
+
+    let lock_ = SpinLock::new(vec![1u32]);
+    assert_eq!(lock_.modify_logged(|data| data.pop()), Some(1), "without a callback");
+
+    let trail = Arc::new(Mutex::new(Vec::new()));
+    let record = Arc::clone(&trail);
+    lock_.on_modify(move |before: &Vec<u32>, after: &Vec<u32>| {
+        record.lock().unwrap().push((before.clone(), after.clone()))
+    });
+
+    assert_eq!(lock_.modify_logged(|data| { data.push(7); data.len() }), 1);
+    lock_.modify_logged(|data| data[0] *= 6);
+    lock_.with_lock(|data| data.push(0));
+    assert_eq!(
+        *trail.lock().unwrap(),
+        [(vec![], vec![7]), (vec![7], vec![42])],
+        "with_lock was reported or a snapshot is wrong"
+    );
+
+    let counter = SpinLock::new(0u64);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let record = Arc::clone(&seen);
+    counter.on_modify(move |before, after| {
+        assert_eq!(*after, before + 1);
+        record.lock().unwrap().push(*after);
+    });
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..1000 {
+                    counter.modify_logged(|value| *value += 1);
+                }
+            });
+        }
+    });
+    assert!(seen.lock().unwrap().iter().copied().eq(1..=4000), "modifications reported out of order");
+
+    lock_.on_modify(|_, _| panic!("audit sink failed"));
+    trail.lock().unwrap().clear();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lock_.modify_logged(|data| data.clear())));
+    std::panic::set_hook(hook);
+    assert!(panicked.is_err() && lock_.is_poisoned());
+    assert!(lock_.try_with_lock(|data| data.is_empty()).is_some(), "the lock stayed held");
+    assert!(trail.lock().unwrap().is_empty(), "a replaced callback still ran");
+    println!("audit: before and after values ok, 4000 modifications reported in order");
+}
+
+// Installs a callback that records every contention event of one lock, then
+// checks that uncontended and timed-out acquisitions report nothing, that a
+// waiter kept out for 20 ms reports one event that yielded and slept, that
+// a `Strategy::Busy` waiter reports neither, that contended threads report
+// at most one event per acquisition, and that a replaced callback goes quiet.
+#[cfg(feature = "contention_hook")]
+fn on_contention() {
+    use spin_lock::ContentionEvent;
+    use std::sync::Mutex;
+
+    let start = unix_timestamp();
+    let events = Arc::new(Mutex::new(Vec::<ContentionEvent>::new()));
+    let record = |lock_: &SpinLock<u64>| {
+        let events = Arc::clone(&events);
+        lock_.on_contention(move |event| events.lock().unwrap().push(event));
+    };
+    let held_for = |lock_: &SpinLock<u64>, hold: Duration| {
+        thread::scope(|scope| {
+            let guard = lock_.lock();
+            let waiter = scope.spawn(|| *lock_.lock() += 1);
+            thread::sleep(hold);
+            drop(guard);
+            waiter.join().unwrap();
+        });
+    };
+
+    let lock_ = SpinLock::new(0u64);
+    record(&lock_);
+
+    for _ in 0..1000 {
+        *lock_.lock() += 1;
+    }
+
+    let guard = lock_.lock();
+    thread::scope(|scope| {
+        scope.spawn(|| assert!(lock_.try_lock_for(Duration::from_millis(5)).is_err()));
+    });
+    drop(guard);
+    assert!(events.lock().unwrap().is_empty(), "reported without a wait ending in the lock");
+
+    held_for(&lock_, Duration::from_millis(20));
+    let event = events.lock().unwrap().pop().expect("a 20 ms wait was not reported");
+    assert_eq!(event.lock_addr, &lock_ as *const SpinLock<u64> as usize);
+    assert!(event.spins > 1 && event.yielded && event.slept, "{:?}", event);
+    assert!(event.waited >= Duration::from_millis(15), "{:?}", event);
+
+    let busy = SpinLock::with_strategy(0u64, Strategy::Busy);
+    record(&busy);
+    held_for(&busy, Duration::from_millis(5));
+    let event = events.lock().unwrap().pop().expect("a busy wait was not reported");
+    assert!(!event.yielded && !event.slept, "{:?}", event);
+
+    let acquisitions = 4 * 2000;
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..2000 {
+                    let mut guard = lock_.lock();
+                    *guard += 1;
+                    hold_briefly(&mut guard);
+                }
+            });
+        }
+    });
+    let contended = std::mem::take(&mut *events.lock().unwrap()).len();
+    assert!(contended > 0 && contended <= acquisitions, "{} events", contended);
+
+    lock_.on_contention(|_| {});
+    held_for(&lock_, Duration::from_millis(5));
+    assert!(events.lock().unwrap().is_empty(), "a replaced callback still ran");
+    println!(
+        "on_contention: {} of {} acquisitions by 4 threads reported, plus the held and busy waits, {}",
+        contended,
+        acquisitions,
+        unix_timestamp() - start
+    );
+}
+
+// Eight threads re-taking one lock back to back, so a releaser is always
+// ready to win the lock again; prints the longest single wait any thread saw
+// without and with `anti_starvation`, and checks the latter stays bounded.
+fn bench_starvation() {
+    let rounds = (JOB_COUNT / 200).max(1);
+    let fair = SpinLockConfig { anti_starvation: true, ..SpinLockConfig::DEFAULT };
+
+    for (name, config) in [("default", SpinLockConfig::DEFAULT), ("anti_starvation", fair)] {
+        let lock_ = Arc::new(SpinLock::with_config(0u64, config));
+        let start = unix_timestamp();
+        let mut vec = Vec::new();
+
+        for _ in 0..8 {
+            let lock_ = Arc::clone(&lock_);
+
+            vec.push(thread::spawn(move || {
+                let mut longest = Duration::ZERO;
+
+                for _ in 0..rounds {
+                    let asked = Instant::now();
+                    let mut guard = lock_.lock();
+                    longest = longest.max(asked.elapsed());
+                    let mut scratch = *guard;
+                    hold_briefly(&mut scratch);
+                    *guard += 1;
+                }
+
+                longest
+            }));
+        }
+
+        let longest = vec.drain(..).map(|thread| thread.join().unwrap()).max().unwrap();
+        assert_eq!(*lock_.lock(), 8 * rounds as u64);
+        println!(
+            "starvation ({}): longest wait {:?}, {}",
+            name,
+            longest,
+            unix_timestamp() - start
+        );
+
+        if config.anti_starvation {
+            assert!(longest < Duration::from_secs(1), "a waiter starved for {:?}", longest);
+        }
+    }
+}
+
+// A consumer posts each request under the lock and waits in
+// `unlock_and_wait_until` for the producer, which only polls the lock, to
+// mark it done.
+fn bench_handoff() {
+    let rounds = (JOB_COUNT / 1000).max(1) as u64;
+    let queue = Arc::new(SpinLock::new((0u64, 0u64)));
+    let start = unix_timestamp();
+
+    let producer = {
+        let queue = Arc::clone(&queue);
+
+        thread::spawn(move || {
+            let mut backoff = spin_lock::SpinWait::new();
+
+            loop {
+                let mut guard = queue.lock();
+
+                if guard.0 > guard.1 {
+                    guard.1 = guard.0;
+                    backoff.reset();
+                }
+
+                if guard.1 == rounds {
+                    break;
+                }
+
+                drop(guard);
+                backoff.spin();
+            }
+        })
+    };
+
+    for round in 1..=rounds {
+        let mut guard = queue.lock();
+        assert_eq!(guard.1, round - 1);
+        guard.0 = round;
+        let guard = SpinLockGuard::unlock_and_wait_until(guard, |&(_, done)| done >= round);
+        assert_eq!(guard.1, round, "consumer woke before its request was served");
+    }
+
+    producer.join().unwrap();
+    println!("unlock_and_wait_until: {} handoffs, {}", rounds, unix_timestamp() - start);
+}
+
+// A producer hands numbered jobs to a consumer through one `SpinSlot`,
+// putting the next only once the last was taken, so the consumer must see
+// every job exactly once and in order.
+fn bench_slot() {
+    let rounds = (JOB_COUNT / 100).max(1) as u64;
+    let slot = Arc::new(SpinSlot::new());
+    let start = unix_timestamp();
+
+    let consumer = {
+        let slot = Arc::clone(&slot);
+
+        thread::spawn(move || {
+            for round in 1..=rounds {
+                assert_eq!(slot.wait_take(), round, "a job was lost or reordered");
+            }
+        })
+    };
+
+    for round in 1..=rounds {
+        let mut backoff = spin_lock::SpinWait::new();
+
+        while slot.is_some() {
+            backoff.spin();
+        }
+
+        assert_eq!(slot.put(round), None);
+    }
+
+    consumer.join().unwrap();
+    assert!(!slot.is_some());
+
+    assert_eq!(slot.put(7), None);
+    assert_eq!(slot.put(8), Some(7));
+    assert_eq!(slot.get_cloned(), Some(8));
+    assert_eq!(slot.take_if(|&job| job > 8), None);
+    assert_eq!(slot.take_if(|&job| job == 8), Some(8));
+    assert_eq!(slot.take(), None);
+    println!("SpinSlot: {} jobs handed over, {}", rounds, unix_timestamp() - start);
+}
+
+// Every guard over a value, printed and compared directly: each must show
+// and compare as the data it guards.
+fn guard_fmt() {
+    fn check<G: fmt::Debug + fmt::Display + PartialEq<u32>>(name: &str, guard: G) {
+        assert_eq!(format!("{:?}", guard), "42", "{} Debug", name);
+        assert_eq!(guard.to_string(), "42", "{} Display", name);
+        assert_eq!(guard, 42, "{} PartialEq", name);
+        assert!(guard != 7, "{} PartialEq", name);
+    }
+
+    let spin = SpinLock::new(42u32);
+    check("SpinLockGuard", spin.lock());
+    let pair = SpinLock::new((42u32, "mapped"));
+    check("MappedSpinLockGuard", SpinLockGuard::map(pair.lock(), |pair| &mut pair.0));
+    let rw = SpinRwLock::new(42u32);
+    check("SpinRwLockReadGuard", rw.read());
+    check("SpinRwLockWriteGuard", rw.write());
+    let bravo = BravoRwLock::new(42u32);
+    check("BravoRwLockReadGuard", bravo.read());
+    check("BravoRwLockWriteGuard", bravo.write());
+    let priority = PrioritySpinLock::new(42u32);
+    check("PrioritySpinLockGuard", priority.lock());
+    let cohort = CohortLock::new(42u32);
+    check("CohortLockGuard", cohort.lock());
+
+    let names = SpinLock::new(vec!["a", "b"]);
+    assert_eq!(format!("{:?}", names.lock()), r#"["a", "b"]"#);
+    assert_eq!(*names.lock(), vec!["a", "b"]);
+    println!("guard_fmt: Debug, Display and PartialEq forward to the data for all 8 guards");
+}
+
+// Threads count through `compare_replace` retry loops over a (count, last
+// writer) pair, as they would with a compare-and-swap: read, build, and retry
+// when another thread got there first. No increment may be lost, and a
+// mismatch must hand back the rejected value.
+fn bench_compare_replace() {
+    let rounds = (JOB_COUNT / 10).max(1) as u64;
+    let counter = Arc::new(SpinLock::new((0u64, 0usize)));
+    let retries = Arc::new(AtomicUsize::new(0));
+    let start = unix_timestamp();
+    let mut vec = Vec::new();
+
+    for id in 1..=4 {
+        let counter = Arc::clone(&counter);
+        let retries = Arc::clone(&retries);
+
+        vec.push(thread::spawn(move || {
+            for _ in 0..rounds {
+                let mut current = counter.load();
+
+                while let Err(rejected) = counter.compare_replace(&current, (current.0 + 1, id)) {
+                    assert_eq!(rejected, (current.0 + 1, id));
+                    retries.fetch_add(1, Ordering::Relaxed);
+                    current = counter.load();
+                }
+            }
+        }));
+    }
+
+    for thread in vec {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(counter.load().0, 4 * rounds);
+
+    let lock_ = SpinLock::new(String::from("old"));
+    assert_eq!(lock_.compare_replace(&String::from("other"), String::from("new")), Err(String::from("new")));
+    assert_eq!(lock_.get_cloned(), "old");
+    assert_eq!(lock_.compare_replace(&String::from("old"), String::from("new")), Ok(()));
+    assert_eq!(lock_.get_cloned(), "new");
+    println!(
+        "compare_replace: {} increments, {} retries, {}",
+        4 * rounds,
+        retries.load(Ordering::Relaxed),
+        unix_timestamp() - start
+    );
+}
+
+// Four producers and four consumers share an 8-slot `SpinQueue`, so pushes
+// regularly find it full and pops find it empty. Every item must come out
+// exactly once, and each consumer must see any one producer's items in the
+// order they were pushed.
+fn bench_queue() {
+    let per_producer = (JOB_COUNT / 40).max(1);
+    let total = 4 * per_producer;
+    let queue = Arc::new(SpinQueue::with_capacity(8));
+    let start = unix_timestamp();
+
+    let producers: Vec<_> = (0..4)
+        .map(|producer| {
+            let queue = Arc::clone(&queue);
+
+            thread::spawn(move || {
+                for item in producer * per_producer..(producer + 1) * per_producer {
+                    queue.push(item);
+                }
+            })
+        })
+        .collect();
+
+    let consumers: Vec<_> = (0..4)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+
+            thread::spawn(move || {
+                let mut last = [None; 4];
+                let mut taken = Vec::with_capacity(per_producer);
+
+                for _ in 0..per_producer {
+                    let item = queue.pop();
+                    let producer = item / per_producer;
+                    assert!(last[producer] < Some(item), "items from one producer came out of order");
+                    last[producer] = Some(item);
+                    taken.push(item);
+                }
+
+                taken
+            })
+        })
+        .collect();
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+
+    let mut seen = vec![false; total];
+
+    for consumer in consumers {
+        for item in consumer.join().unwrap() {
+            assert!(!seen[item], "item {} came out twice", item);
+            seen[item] = true;
+        }
+    }
+
+    assert!(seen.iter().all(|&seen| seen), "an item was lost");
+    assert!(queue.is_empty());
+
+    assert_eq!(queue.try_pop(), None);
+    (0..8).for_each(|item| queue.try_push(item).unwrap());
+    assert_eq!(queue.try_push(8), Err(8));
+    assert_eq!(queue.len(), 8);
+    assert_eq!(queue.try_pop(), Some(0));
+    println!("SpinQueue: {} items through 4 producers and 4 consumers, {}", total, unix_timestamp() - start);
+}
+
+// A `WaitStrategy` double that only records what the backoff asks of it,
+// and releases `lock` (held by the same thread) from its `release_on`th
+// sleep, so a contended `lock_with` can run on one thread and to a script.
+struct ScriptedWait<'a> {
+    lock: Option<&'a RawSpinLock>,
+    release_on: usize,
+    spins: usize,
+    yields: Vec<usize>,
+    sleeps: Vec<Duration>,
+}
+
+impl<'a> ScriptedWait<'a> {
+    fn new(lock: Option<&'a RawSpinLock>, release_on: usize) -> ScriptedWait<'a> {
+        ScriptedWait {
+            lock,
+            release_on,
+            spins: 0,
+            yields: vec![0],
+            sleeps: Vec::new(),
+        }
+    }
+}
+
+impl WaitStrategy for ScriptedWait<'_> {
+    fn spin(&mut self) {
+        self.spins += 1;
+    }
+
+    fn yield_now(&mut self) {
+        *self.yields.last_mut().unwrap() += 1;
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.sleeps.push(duration);
+        self.yields.push(0);
+
+        if self.sleeps.len() == self.release_on {
+            unsafe { self.lock.unwrap().unlock() };
+        }
+    }
+}
+
+// Checks the backoff schedule through `ScriptedWait` instead of the clock:
+// 25..75 yields between sleeps, each sleep within the jitter of a base that
+// doubles from `min_sleep` up to `max_sleep`, and `spin_no_yield` doubling
+// its hints up to 64. Then a contended `lock_with` that the double releases
+// on its third sleep, all on one thread.
+fn scripted_wait() {
+    let config = SpinLockConfig {
+        min_sleep: Duration::from_micros(100),
+        max_sleep: Duration::from_micros(800),
+        ..SpinLockConfig::DEFAULT
+    };
+    let mut backoff = spin_lock::SpinWait::with_strategy(config, ScriptedWait::new(None, 0));
+    let mut steps = 0;
+
+    while backoff.strategy().sleeps.len() < 6 {
+        let slept = backoff.strategy().sleeps.len();
+        assert_eq!(backoff.spin(), backoff.strategy().sleeps.len() == slept);
+        steps += 1;
+    }
+
+    let scripted = backoff.strategy();
+    assert_eq!(scripted.yields.iter().sum::<usize>(), steps);
+
+    for &yields in &scripted.yields[..6] {
+        assert!((25..75).contains(&yields), "{} yields between sleeps", yields);
+    }
+
+    for (step, &sleep) in scripted.sleeps.iter().enumerate() {
+        let base = (100u64 << step).min(800);
+        assert!(
+            (base / 2..=base * 3 / 2).contains(&(sleep.as_micros() as u64)),
+            "sleep {} lasted {:?}, base {} µs",
+            step,
+            sleep,
+            base
+        );
+    }
+
+    (0..8).for_each(|_| backoff.spin_no_yield());
+    assert_eq!(backoff.strategy().spins, 1 + 2 + 4 + 8 + 16 + 32 + 64 + 64);
+
+    let lock_ = RawSpinLock::new();
+    lock_.lock();
+    let mut backoff = spin_lock::SpinWait::with_strategy(config, ScriptedWait::new(Some(&lock_), 3));
+    lock_.lock_with(&mut backoff);
+    assert_eq!(backoff.strategy().sleeps.len(), 3);
+    assert!(!lock_.try_lock(), "lock_with returned without the lock");
+    unsafe { lock_.unlock() };
+
+    let data = SpinLock::new(5u32);
+    let mut backoff = spin_lock::SpinWait::with_strategy(config, ScriptedWait::new(None, 0));
+    *data.lock_with(&mut backoff) += 1;
+    assert_eq!((backoff.strategy().yields[0], data.into_inner()), (0, 6));
+
+    // `Busy` only spins and `Yield` only yields; neither ever sleeps.
+    for (strategy, spins, yields) in [(Strategy::Busy, 200, 0), (Strategy::Yield, 0, 200)] {
+        let config = SpinLockConfig { strategy, ..SpinLockConfig::DEFAULT };
+        let mut backoff = spin_lock::SpinWait::with_strategy(config, ScriptedWait::new(None, 0));
+        assert!((0..200).all(|_| backoff.spin()));
+        let scripted = backoff.strategy();
+        assert_eq!((scripted.spins, scripted.yields[0], scripted.sleeps.len()), (spins, yields, 0));
+    }
+
+    assert_eq!(Strategy::DEFAULT, Strategy::OsWait);
+    assert_eq!(RawSpinLock::with_strategy(Strategy::Busy).config().strategy, Strategy::Busy);
+
+    let busy = Arc::new(SpinLock::with_strategy(0u32, Strategy::Busy));
+    let guard = busy.lock();
+    let waiter = {
+        let busy = Arc::clone(&busy);
+        thread::spawn(move || *busy.lock() += 1)
+    };
+    thread::sleep(Duration::from_millis(5));
+    drop(guard);
+    waiter.join().unwrap();
+    assert_eq!(*busy.lock(), 1);
+
+    // `wait_until` backs off with the lock's own `Busy` strategy.
+    let setter = {
+        let busy = Arc::clone(&busy);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            busy.set(2);
+        })
+    };
+    assert_eq!(busy.wait_until(|&n| (n == 2).then_some(n)), 2);
+    setter.join().unwrap();
+    println!("scripted_wait: backoff schedule, strategies and a scripted contended lock_with ok");
+}
+
+struct Cursor {
+    buf: Vec<u8>,
+    position: usize,
+}
+
+// Splits a guard over `Cursor` into its buffer and its position, reads one
+// while advancing the other, and drops the halves in both orders: the lock
+// must stay held until the second half goes. Then a three-way split of a
+// half, and a panic while both halves are alive, which must release and
+// poison the lock exactly once.
+fn split_map() {
+    let lock_ = SpinLock::new(Cursor { buf: b"split".to_vec(), position: 0 });
+
+    for first_half_first in [true, false] {
+        let guard = lock_.lock();
+        let (buf, mut position) =
+            SpinLockGuard::split_map(guard, |cursor| (&mut cursor.buf, &mut cursor.position));
+
+        while *position < buf.len() {
+            assert_eq!(buf[*position], b"split"[*position]);
+            *position += 1;
+        }
+
+        if first_half_first {
+            drop(buf);
+            assert!(lock_.try_lock().is_err(), "the lock was released with a half still alive");
+            drop(position);
+        } else {
+            drop(position);
+            assert!(lock_.try_lock().is_err(), "the lock was released with a half still alive");
+            drop(buf);
+        }
+
+        let mut guard = lock_.try_lock().expect("the lock outlived both halves");
+        assert_eq!(guard.position, 5);
+        guard.position = 0;
+    }
+
+    let mut guard = lock_.lock();
+    guard.position = 0;
+    let (buf, position) =
+        SpinLockGuard::split_map(guard, |cursor| (&mut cursor.buf, &mut cursor.position));
+    let (mut head, tail) =
+        spin_lock::MappedSpinLockGuard::split_map(buf, |buf| buf.split_at_mut(2));
+    head.copy_from_slice(&tail[..2]);
+    drop(head);
+    drop(tail);
+    assert!(lock_.try_lock().is_err(), "released with one of three guards alive");
+    drop(position);
+    assert_eq!(lock_.lock().buf, b"lilit");
+
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let (_buf, mut position) =
+            SpinLockGuard::split_map(lock_.lock(), |cursor| (&mut cursor.buf, &mut cursor.position));
+        *position = 9;
+        panic!("panicking with both halves alive on purpose");
+    }));
+    std::panic::set_hook(hook);
+    assert!(lock_.is_poisoned());
+    match lock_.try_lock() {
+        Err(spin_lock::TryLockError::Poisoned(poisoned)) => assert_eq!(poisoned.into_inner().position, 9),
+        _ => panic!("the panic did not leave the lock free and poisoned"),
+    }
+    println!("split_map: halves released in both orders, three-way split and panic ok");
+}
+
+// Single-threaded lock/unlock with no thread, sleep or clock, so it also runs
+// where those are missing (wasm32 without the atomics proposal, under a wasm
+// runtime's `main`).
+fn smoke() {
+    let lock_ = SpinLock::new(0u32);
+
+    {
+        let mut guard = lock_.lock();
+        *guard += 1;
+        assert!(lock_.try_lock().is_err());
+    }
+
+    let mut guard = lock_.try_lock().unwrap();
+    *guard += 1;
+    SpinLockGuard::unlock_fair(guard);
+    lock_.with_lock(|value| *value += 1);
+    lock_.with_lock_dyn(&mut |value| *value += 1);
+    assert_eq!(lock_.into_inner(), 4);
+
+    // `lock_checked` refuses a second acquisition by its holder, and any
+    // release forgets the owner, so the next `lock_checked` succeeds.
+    let checked = SpinLock::new(0u32);
+    let guard = checked.lock_checked().unwrap();
+    assert!(checked.lock_checked().is_err(), "lock_checked() let its holder in again");
+    drop(guard);
+    SpinLockGuard::unlock_fair(checked.lock_checked().unwrap());
+    *checked.lock_checked().expect("an unlock_fair kept the owner") += 1;
+    assert_eq!(checked.into_inner(), 1);
+
+    // A buffer filled through `as_mut_ptr` while held, as a C callee would.
+    let buffer = SpinLock::new([0u8; 4]);
+    let guard = buffer.lock();
+    unsafe { buffer.as_mut_ptr().cast::<u8>().write_bytes(7, 4) };
+    drop(guard);
+    assert_eq!(buffer.into_inner(), [7; 4]);
+
+    // Teardown of a shared lock only succeeds through the last reference.
+    let shared = SpinLock::new(vec![1, 2]).into_shared();
+    let other = Arc::clone(&shared);
+    let shared = spin_lock::try_unwrap_inner(shared).expect_err("unwrapped a lock with two references");
+    drop(other);
+    assert_eq!(spin_lock::try_unwrap_inner(shared).unwrap(), [1, 2]);
+
+    // A lock hashes as its value, so locks work as set keys. Clippy flags the
+    // interior mutability: these keys are never written through a guard.
+    let hasher = std::collections::hash_map::RandomState::new();
+    assert_eq!(hasher.hash_one(SpinLock::new("key")), hasher.hash_one("key"));
+    #[allow(clippy::mutable_key_type)]
+    let keys: std::collections::HashSet<_> = (0..4).map(SpinLock::new).collect();
+    assert!(keys.contains(&SpinLock::new(3)) && !keys.contains(&SpinLock::new(4)));
+
+    // A comparison that panics must release both locks, or relocking
+    // either would spin forever.
+    #[cfg(panic = "unwind")]
+    {
+        struct PanicsOnEq;
+
+        impl PartialEq for PanicsOnEq {
+            fn eq(&self, _: &PanicsOnEq) -> bool {
+                panic!("comparing on purpose");
+            }
+        }
+
+        let (a, b) = (SpinLock::new(PanicsOnEq), SpinLock::new(PanicsOnEq));
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| a == b)).is_err());
+        std::panic::set_hook(hook);
+        assert!(a.is_poisoned() && b.is_poisoned());
+        drop((a.lock(), b.lock()));
+    }
+    println!("smoke: single-threaded lock/unlock ok");
+}
+
+// The locks `bench_compare` runs every scenario against, all used through
+// their guards as user code would.
+trait CompareLock: Default + Send + Sync + 'static {
+    fn increment(&self, section: Duration);
+}
+
+impl CompareLock for SpinLock<u64> {
+    fn increment(&self, section: Duration) {
+        let mut guard = self.lock();
+        *guard += 1;
+        busy_for(section);
+    }
+}
+
+impl CompareLock for std::sync::Mutex<u64> {
+    fn increment(&self, section: Duration) {
+        let mut guard = self.lock().unwrap();
+        *guard += 1;
+        busy_for(section);
+    }
+}
+
+fn busy_for(section: Duration) {
+    if section > Duration::ZERO {
+        let start = Instant::now();
+
+        while start.elapsed() < section {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// Nanoseconds per increment with `threads` threads sharing one lock.
+fn contended_ns<L: CompareLock>(threads: usize, per_thread: usize, section: Duration) -> f64 {
+    let lock_ = Arc::new(L::default());
+    let start = Instant::now();
+    let mut vec = Vec::new();
+
+    for _ in 0..threads {
+        let lock_ = Arc::clone(&lock_);
+        vec.push(thread::spawn(move || {
+            for _ in 0..per_thread {
+                lock_.increment(section);
+            }
+        }));
+    }
+
+    for thread in vec.drain(..) {
+        thread.join().unwrap();
+    }
+
+    start.elapsed().as_nanos() as f64 / (threads * per_thread) as f64
+}
+
+// SpinLock against std::sync::Mutex: uncontended pairs through the guard and
+// through `with_lock`, then 2, 8 and 32 threads with an empty and with a
+// 1 µs critical section.
+fn bench_compare() {
+    let pairs = JOB_COUNT * 10;
+    let spin = SpinLock::new(0u64);
+    let mutex = std::sync::Mutex::new(0u64);
+    let timed = |f: &dyn Fn()| {
+        let start = Instant::now();
+
+        for _ in 0..pairs {
+            f();
+        }
+
+        start.elapsed().as_nanos() as f64 / pairs as f64
+    };
+
+    let spin_guard = timed(&|| spin.increment(Duration::ZERO));
+    let mutex_guard = timed(&|| mutex.increment(Duration::ZERO));
+    println!("uncontended guard: SpinLock {:.1}ns std Mutex {:.1}ns", spin_guard, mutex_guard);
+
+    let spin_closure = timed(&|| spin.with_lock(|value| *value += 1));
+    let mutex_closure = timed(&|| *mutex.lock().unwrap() += 1);
+    println!("uncontended with_lock: SpinLock {:.1}ns std Mutex {:.1}ns", spin_closure, mutex_closure);
+
+    for section in [Duration::ZERO, Duration::from_micros(1)] {
+        for threads in [2, 8, 32] {
+            let per_thread = if section.is_zero() { JOB_COUNT / 10 } else { JOB_COUNT / 100 } / threads;
+            let per_thread = per_thread.max(1);
+            println!(
+                "{} threads, {:?} section: SpinLock {:.1}ns std Mutex {:.1}ns",
+                threads,
+                section,
+                contended_ns::<SpinLock<u64>>(threads, per_thread, section),
+                contended_ns::<std::sync::Mutex<u64>>(threads, per_thread, section)
+            );
+        }
+    }
+}
+
+// `replace` hands back the old value, and a self-`swap` returns instead of
+// locking the lock twice. Then two threads swap the same pair of locks in
+// opposite directions: both lock in address order whichever way round they
+// are called, so they must neither deadlock nor lose a value.
+fn replace_swap() {
+    let lock_ = SpinLock::new(vec![1]);
+    assert_eq!(lock_.replace(vec![2]), [1]);
+    lock_.swap(&lock_);
+    assert_eq!(lock_.get_cloned(), [2]);
+
+    let (a, b) = (SpinLock::new(1u64), SpinLock::new(2u64));
+    a.swap(&b);
+    assert_eq!((a.load(), b.load()), (2, 1));
+    let rounds = JOB_COUNT / 10;
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for _ in 0..rounds {
+                a.swap(&b);
+            }
+        });
+
+        for _ in 0..rounds {
+            b.swap(&a);
+        }
+    });
+
+    // An even number of swaps puts the values back.
+    assert_eq!((a.into_inner(), b.into_inner()), (2, 1));
+    println!("replace_swap: replace, self-swap and {} swaps in each direction ok", rounds);
+}
+
+// Holds the lock for a few microseconds of work, so that waiters really
+// queue up behind it.
+fn hold_briefly(data: &mut u64) {
+    for _ in 0..1000 {
+        *data = std::hint::black_box(data.wrapping_mul(31).wrapping_add(1));
+    }
+}
+
+// Average acquisition latency of one thread against background threads
+// hammering the same lock with `lock`, first taking the lock the same way
+// and then through `lock_priority`.
+fn bench_priority() {
+    let lock_ = Arc::new(PrioritySpinLock::new(0u64));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut vec = Vec::new();
+
+    for _ in 0..4 {
+        let lock_ = Arc::clone(&lock_);
+        let stop = Arc::clone(&stop);
+
+        vec.push(thread::spawn(move || {
+            let (mut total, mut count) = (Duration::ZERO, 0u32);
+
+            while !stop.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                let mut guard = lock_.lock();
+                total += start.elapsed();
+                count += 1;
+                hold_briefly(&mut guard);
+                drop(guard);
+                thread::yield_now();
+            }
+
+            total / count.max(1)
+        }));
+    }
+
+    let measure = |priority: bool| {
+        let (mut total, mut count) = (Duration::ZERO, 0u32);
+
+        for _ in 0..(JOB_COUNT / 100).max(1) {
+            let start = Instant::now();
+            let mut guard = if priority { lock_.lock_priority() } else { lock_.lock() };
+            total += start.elapsed();
+            count += 1;
+            hold_briefly(&mut guard);
+            drop(guard);
+            thread::yield_now();
+        }
+
+        total / count
+    };
+
+    let normal = measure(false);
+    let priority = measure(true);
+    stop.store(true, Ordering::Relaxed);
+
+    let background: Vec<Duration> = vec.drain(..).map(|thread| thread.join().unwrap()).collect();
+
+    println!("PrioritySpinLock lock(): {:?} average acquisition", normal);
+    println!("PrioritySpinLock lock_priority(): {:?} average acquisition", priority);
+    println!("PrioritySpinLock background threads: {:?} average acquisition", background);
+}
+
+// User plus system CPU time of the whole process, from /proc (Linux only).
+// Assumes the usual 100 clock ticks per second.
+#[cfg(not(loom))]
+fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the parenthesized command name; utime and stime are the
+    // 12th and 13th of those.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let ticks = fields.next()?.parse::<u64>().ok()? + fields.next()?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(ticks * 10))
+}
+
+// One thread holds the lock for a second while the others wait for it. The
+// CPU time burned meanwhile compares the sleep backoff with the `futex`
+// feature, where waiters park in the kernel until the unlock wakes them.
+#[cfg(not(loom))]
+fn bench_long_hold() {
+    let capped = SpinLockConfig { max_sleep: Duration::from_millis(20), ..SpinLockConfig::DEFAULT };
+
+    for (name, config) in [("default", SpinLockConfig::DEFAULT), ("max_sleep 20ms", capped)] {
+        let lock_ = Arc::new(SpinLock::with_config(0, config));
+        let hold = Duration::from_secs(1);
+        let mut vec = Vec::new();
+
+        // Only the initial one-second hold should be reported.
+        #[cfg(feature = "timed")]
+        lock_.on_long_hold(Duration::from_millis(100), |long_hold| {
+            println!("long hold: {:?} (threshold {:?})", long_hold.held, long_hold.threshold)
+        });
+
+        let guard = lock_.lock();
+        let start = Instant::now();
+        let cpu_start = cpu_time();
+
+        for _ in 0..THREAD_COUNT / 4 {
+            let lock_ = Arc::clone(&lock_);
+            vec.push(thread::spawn(move || lock_.with_lock(|data| *data += 1)));
+        }
+
+        thread::sleep(hold);
+        drop(guard);
+
+        for thread in vec.drain(..) {
+            thread.join().unwrap();
+        }
+
+        let cpu = match (cpu_start, cpu_time()) {
+            (Some(before), Some(after)) => format!("{:?}", after - before),
+            _ => "n/a".to_string(),
+        };
+
+        println!(
+            "SpinLock long hold ({}): {} waiters, {:?} elapsed, {} CPU",
+            name,
+            *lock_.lock(),
+            start.elapsed(),
+            cpu
+        );
+    }
+}
+
+// Built with `--cfg loom`, the binary model-checks the lock instead of
+// benchmarking it.
+#[cfg(loom)]
+mod loom_model {
+    use loom::cell::UnsafeCell;
+    use loom::sync::atomic::{AtomicUsize, Ordering};
+    use loom::sync::Arc;
+    use loom::thread;
+    use spin_lock::{
+        AtomicSpinCell, BravoRwLock, CohortLock, LockStripe, RawSpinLock, RcuCell, SpinLock,
+        SpinRwLock, SpinRwLockUpgradableGuard, SpinRwLockWriteGuard,
+    };
+    use std::time::Duration;
+
+    fn with_lock_increments() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinLock::new(0));
+
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let lock_ = Arc::clone(&lock_);
+                    thread::spawn(move || lock_.with_lock(|data| *data += 1))
+                })
+                .collect();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            assert_eq!(lock_.with_lock(|data| *data), 2);
+        });
+    }
+
+    fn try_lock_against_lock() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinLock::new(0));
+
+            let other = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || lock_.with_lock(|data| *data += 1))
+            };
+
+            let acquired = lock_
+                .with_lock_for(Duration::ZERO, |data| *data += 1)
+                .is_ok();
+            other.join().unwrap();
+
+            assert_eq!(lock_.with_lock(|data| *data), 1 + acquired as i32);
+        });
+    }
+
+    fn unlock_publishes_writes() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinLock::new((0, 0)));
+
+            let writer = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || {
+                    lock_.with_lock(|data| {
+                        data.0 = 1;
+                        data.1 = 1;
+                    })
+                })
+            };
+
+            let seen = lock_.with_lock(|data| *data);
+            assert!(seen == (0, 0) || seen == (1, 1));
+            writer.join().unwrap();
+            assert_eq!(lock_.with_lock(|data| *data), (1, 1));
+        });
+    }
+
+    fn downgrade_excludes_writers() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinRwLock::new(0));
+            let mut guard = lock_.write();
+
+            let writer = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || *lock_.write() = 2)
+            };
+
+            *guard = 1;
+            let guard = SpinRwLockWriteGuard::downgrade(guard);
+            assert_eq!(*guard, 1);
+            drop(guard);
+
+            writer.join().unwrap();
+            assert_eq!(*lock_.read(), 2);
+        });
+    }
+
+    fn upgrade_waits_for_reader() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinRwLock::new(0));
+            let reader = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || {
+                    let guard = lock_.read();
+                    let first = *guard;
+                    thread::yield_now();
+                    assert_eq!(*guard, first, "a writer got in under a reader");
+                    first
+                })
+            };
+
+            let guard = lock_.upgradeable_read();
+            let mut guard = SpinRwLockUpgradableGuard::upgrade(guard);
+            *guard = 1;
+            drop(guard);
+
+            assert!(matches!(reader.join().unwrap(), 0 | 1));
+            assert_eq!(*lock_.read(), 1);
+        });
+    }
+
+    fn eq_against_mutation() {
+        loom::model(|| {
+            let a = Arc::new(SpinLock::new(0));
+            let b = Arc::new(SpinLock::new(0));
+
+            let writer = {
+                let a = Arc::clone(&a);
+                thread::spawn(move || a.with_lock(|data| *data += 1))
+            };
+
+            let same = Arc::clone(&a);
+            assert!(*a == *same);
+            let equal = *a == *b;
+            writer.join().unwrap();
+
+            assert!(equal || a.with_lock(|data| *data) == 1);
+            assert!(*a != *b);
+        });
+    }
+
+    fn swap_against_swap() {
+        loom::model(|| {
+            let a = Arc::new(SpinLock::new(1));
+            let b = Arc::new(SpinLock::new(2));
+
+            let other = {
+                let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+                thread::spawn(move || b.swap(&a))
+            };
+
+            a.swap(&b);
+            a.swap(&Arc::clone(&a));
+            other.join().unwrap();
+
+            assert_eq!((a.get_cloned(), b.get_cloned()), (1, 2));
+            assert_eq!(a.replace(3), 1);
+            assert_eq!(a.take(), 3);
+            b.set(4);
+            assert_eq!((a.get_cloned(), b.get_cloned()), (0, 4));
+        });
+    }
+
+    fn update_against_update() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinLock::new(0));
+
+            let other = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || lock_.update_and_fetch(|data| *data += 1))
+            };
+
+            let old = lock_.fetch_and_update(|data| *data += 1);
+            let new = other.join().unwrap();
+
+            assert!((old, new) == (0, 2) || (old, new) == (1, 1));
+            assert_eq!(lock_.get_cloned(), 2);
+        });
+    }
+
+    fn cell_update_against_update() {
+        loom::model(|| {
+            let cell = Arc::new(AtomicSpinCell::new(0u64));
+
+            let other = {
+                let cell = Arc::clone(&cell);
+                thread::spawn(move || cell.update(|value| value + 1))
+            };
+
+            let old = cell.update(|value| value + 1);
+            let other_old = other.join().unwrap();
+
+            assert!((old, other_old) == (0, 1) || (old, other_old) == (1, 0));
+            assert_eq!(cell.load(), 2);
+        });
+    }
+
+    fn two_locks_opposite_order() {
+        loom::model(|| {
+            let a = Arc::new(SpinLock::new(0));
+            let b = Arc::new(SpinLock::new(0));
+
+            let other = {
+                let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+                thread::spawn(move || b.with_two_locks(&a, |b, a| *a += *b + 1))
+            };
+
+            a.with_two_locks(&b, |_, b| *b += 1);
+            other.join().unwrap();
+
+            let sums = (a.get_cloned(), b.get_cloned());
+            assert!(sums == (1, 1) || sums == (2, 1));
+        });
+    }
+
+    fn wait_until_sees_store() {
+        loom::model(|| {
+            let lock_ = Arc::new(SpinLock::new(None));
+
+            let producer = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || lock_.set(Some(7)))
+            };
+
+            assert_eq!(lock_.wait_until(|data| *data), 7);
+            producer.join().unwrap();
+        });
+    }
+
+    struct CountDrops(Arc<AtomicUsize>);
+
+    impl Drop for CountDrops {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn drops_payload_once() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let lock = Arc::new(SpinLock::new(CountDrops(Arc::clone(&drops))));
+
+            let other = {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || lock.with_lock(|_| ()))
+            };
+
+            lock.with_lock(|_| ());
+            other.join().unwrap();
+
+            assert_eq!(drops.load(Ordering::SeqCst), 0);
+            drop(lock);
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    fn lock_all_against_lock_for() {
+        loom::model(|| {
+            let stripe = Arc::new(LockStripe::<2>::new());
+
+            let other = {
+                let stripe = Arc::clone(&stripe);
+                thread::spawn(move || {
+                    for index in [1, 0] {
+                        let guard = stripe.lock_index(index);
+                        assert_eq!(guard.index(), index);
+                    }
+                })
+            };
+
+            drop(stripe.lock_all());
+            other.join().unwrap();
+
+            let all = stripe.lock_all();
+            assert!(stripe.try_lock_index(0).is_none() && stripe.try_lock_index(1).is_none());
+            drop(all);
+        });
+    }
+
+    fn lock_many_overlapping_subsets() {
+        loom::model(|| {
+            let buckets: Arc<[SpinLock<usize>]> =
+                Arc::from(vec![SpinLock::new(0), SpinLock::new(0), SpinLock::new(0)]);
+
+            let other = {
+                let buckets = Arc::clone(&buckets);
+                thread::spawn(move || {
+                    for (_, bucket) in SpinLock::lock_many(&buckets, &[2, 0, 2]).iter_mut() {
+                        *bucket += 1;
+                    }
+                })
+            };
+
+            for (_, bucket) in SpinLock::lock_many(&buckets, &[0, 1, 2]).iter_mut() {
+                *bucket += 1;
+            }
+
+            other.join().unwrap();
+
+            let all = SpinLock::lock_many(&buckets, &[0, 1, 2]);
+            assert_eq!(all.iter().map(|(_, bucket)| *bucket).collect::<Vec<_>>(), [2, 1, 2]);
+        });
+    }
+
+    fn bravo_writer_waits_for_slot_readers() {
+        loom::model(|| {
+            let lock_ = Arc::new(BravoRwLock::new((0, 0)));
+
+            let reader = {
+                let lock_ = Arc::clone(&lock_);
+                thread::spawn(move || {
+                    let guard = lock_.read();
+                    assert_eq!(guard.0, guard.1);
+                })
+            };
+
+            {
+                let mut guard = lock_.write();
+                guard.0 += 1;
+                guard.1 += 1;
+            }
+
+            reader.join().unwrap();
+            assert_eq!(*lock_.read(), (1, 1));
+        });
+    }
+
+    fn cohort_handoff_keeps_exclusion() {
+        loom::model(|| {
+            let lock_ = Arc::new(CohortLock::with_nodes(2, 0));
+
+            let local: Vec<_> = (0..2)
+                .map(|_| {
+                    let lock_ = Arc::clone(&lock_);
+                    thread::spawn(move || *lock_.lock_on_node(0) += 1)
+                })
+                .collect();
+
+            *lock_.lock_on_node(1) += 1;
+
+            for thread in local {
+                thread.join().unwrap();
+            }
+
+            assert_eq!(*lock_.lock_on_node(1), 3);
+        });
+    }
+
+    fn unsized_payloads() {
+        loom::model(|| {
+            let slice: Arc<SpinLock<[i32]>> = Arc::new(SpinLock::new([0, 0]));
+            let counter = Arc::new(SpinLock::new(0));
+            let bump: Arc<SpinLock<dyn FnMut() + Send + Sync>> = {
+                let counter = Arc::clone(&counter);
+                Arc::new(SpinLock::new(move || counter.update(|data| *data += 1)))
+            };
+
+            let other = {
+                let (slice, bump) = (Arc::clone(&slice), Arc::clone(&bump));
+                thread::spawn(move || {
+                    slice.with_lock(|data| data[0] += 1);
+                    bump.with_lock(|f| f());
+                })
+            };
+
+            slice.with_lock(|data| data[1] += 1);
+            bump.with_lock(|f| f());
+            other.join().unwrap();
+
+            assert_eq!(slice.with_lock(|data| data.to_vec()), [1, 1]);
+            assert_eq!(counter.get_cloned(), 2);
+        });
+    }
+
+    struct External {
+        lock_: RawSpinLock,
+        value: UnsafeCell<i32>,
+    }
+
+    unsafe impl Sync for External {}
+
+    fn raw_lock_guards_external_data() {
+        loom::model(|| {
+            let shared = Arc::new(External {
+                lock_: RawSpinLock::new(),
+                value: UnsafeCell::new(0),
+            });
+
+            let increment = |shared: &External| {
+                shared.lock_.lock();
+                shared.value.with_mut(|value| unsafe { *value += 1 });
+                unsafe { shared.lock_.unlock() };
+            };
+
+            let other = {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || increment(&shared))
+            };
+
+            increment(&shared);
+            other.join().unwrap();
+
+            assert!(!shared.lock_.is_locked());
+            assert_eq!(shared.value.with(|value| unsafe { *value }), 2);
+        });
+    }
+
+    // Waiters that back off far enough mark the word contended; a release
+    // clears it, and a fair one hands the lock on without clearing it, so
+    // once both waiters are done the word must be back to free.
+    fn contended_word_clears_on_release() {
+        loom::model(|| {
+            let lock_ = Arc::new(RawSpinLock::new());
+            lock_.lock();
+
+            let waiters: Vec<_> = (0..2)
+                .map(|_| {
+                    let lock_ = Arc::clone(&lock_);
+                    thread::spawn(move || {
+                        lock_.lock();
+                        unsafe { lock_.unlock_fair() };
+                    })
+                })
+                .collect();
+
+            unsafe { lock_.unlock_fair() };
+
+            for waiter in waiters {
+                waiter.join().unwrap();
+            }
+
+            assert!(!lock_.is_locked() && !lock_.is_contended());
+        });
+    }
+
+    fn rcu_read_against_replace() {
+        loom::model(|| {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let cell = Arc::new(RcuCell::new((0, CountDrops(Arc::clone(&drops)))));
+
+            let reader = {
+                let cell = Arc::clone(&cell);
+                thread::spawn(move || cell.read().0)
+            };
+
+            let old = cell.replace((1, CountDrops(Arc::clone(&drops))));
+            assert_eq!(old.0, 0);
+            drop(old);
+
+            assert!(reader.join().unwrap() <= 1);
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+            drop(cell);
+            assert_eq!(drops.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    pub fn run() {
+        with_lock_increments();
+        try_lock_against_lock();
+        unlock_publishes_writes();
+        downgrade_excludes_writers();
+        upgrade_waits_for_reader();
+        eq_against_mutation();
+        swap_against_swap();
+        update_against_update();
+        cell_update_against_update();
+        two_locks_opposite_order();
+        wait_until_sees_store();
+        unsized_payloads();
+        raw_lock_guards_external_data();
+        contended_word_clears_on_release();
+        drops_payload_once();
+        lock_all_against_lock_for();
+        lock_many_overlapping_subsets();
+        bravo_writer_waits_for_slot_readers();
+        cohort_handoff_keeps_exclusion();
+        rcu_read_against_replace();
+    }
+}
+
+#[cfg(loom)]
+fn main() {
+    loom_model::run();
+}
+
+#[cfg(not(loom))]
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("contention") => bench_contention_patterns(),
+        Some("backoff") => bench_backoff(),
+        Some("max_attempts") => bench_max_attempts(),
+        Some("long_hold") => bench_long_hold(),
+        Some("uncontended") => bench_uncontended(),
+        Some("priority") => bench_priority(),
+        Some("stripes") => bench_stripes(),
+        Some("bravo") => bench_bravo(),
+        Some("cohort") => bench_cohort(),
+        Some("elision") => bench_elision(),
+        Some("wait") => bench_wait(),
+        Some("ttas") => bench_ttas(),
+        Some("shared") => bench_shared(),
+        Some("critical_section") => bench_critical_section(),
+        Some("smoke") => smoke(),
+        Some("handoff") => bench_handoff(),
+        Some("compare") => bench_compare(),
+        Some("replace_swap") => replace_swap(),
+        Some("starvation") => bench_starvation(),
+        Some("slot") => bench_slot(),
+        Some("guard_fmt") => guard_fmt(),
+        Some("compare_replace") => bench_compare_replace(),
+        Some("queue") => bench_queue(),
+        Some("scripted_wait") => scripted_wait(),
+        Some("sampler") => bench_sampler(),
+        Some("rcu") => bench_rcu(),
+        Some("bump") => bench_bump(),
+        Some("split_map") => split_map(),
+        Some("cell") => atomic_cell(),
+        Some("cell_compat") => cell_compat(),
+        Some("tsan") => tsan(),
+        Some("miri") => miri(),
+        Some("cancellable") => cancellable(),
+        Some("lock_word") => lock_word(),
+        Some("macro") => lock_macro(),
+        Some("spin_compat") => spin_compat(),
+        Some("lock_trait") => lock_trait(),
+        Some("acquire") => acquire(),
+        #[cfg(feature = "serde")]
+        Some("serde") => bench_serde(),
+        #[cfg(feature = "held_locks")]
+        Some("held_locks") => held_locks(),
+        #[cfg(feature = "send_guard")]
+        Some("send_guard") => send_guard(),
+        #[cfg(feature = "ffi")]
+        Some("ffi") => ffi(),
+        #[cfg(feature = "contention_hook")]
+        Some("on_contention") => on_contention(),
+        #[cfg(feature = "audit")]
+        Some("audit") => audit(),
+        #[cfg(all(feature = "versioned", not(loom)))]
+        Some("versioned") => versioned(),
+        #[cfg(all(feature = "priority_ceiling", target_os = "linux"))]
+        Some("ceiling") => ceiling(),
+        #[cfg(feature = "tokio")]
+        Some("tokio") => tokio_lock(),
+        #[cfg(feature = "seqlock")]
+        Some("snapshot") => snapshot(),
+        _ => bench_counter(),
+    }
 }
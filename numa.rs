@@ -0,0 +1,92 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::cell::Cell;
+use std::ffi::{c_long, c_uint};
+use std::ptr;
+use std::sync::OnceLock;
+
+#[cfg(target_arch = "x86_64")]
+const SYS_GETCPU: c_long = 309;
+#[cfg(any(
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64"
+))]
+const SYS_GETCPU: c_long = 168;
+#[cfg(target_arch = "x86")]
+const SYS_GETCPU: c_long = 318;
+#[cfg(target_arch = "arm")]
+const SYS_GETCPU: c_long = 345;
+
+// A thread re-reads its node after this many lookups, to follow migrations
+// without a syscall on every acquisition.
+const NODE_REFRESH_INTERVAL: u32 = 256;
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+thread_local! {
+    // (node, lookups left before the next getcpu)
+    static CURRENT_NODE: Cell<(usize, u32)> = const { Cell::new((0, 0)) };
+}
+
+// Number of NUMA nodes the kernel may bring online, from
+// /sys/devices/system/node/possible ("0", "0-1", "0-3,6"...). 1 if unknown.
+pub(crate) fn node_count() -> usize {
+    static NODE_COUNT: OnceLock<usize> = OnceLock::new();
+
+    *NODE_COUNT.get_or_init(|| {
+        std::fs::read_to_string("/sys/devices/system/node/possible")
+            .ok()
+            .and_then(|possible| {
+                possible
+                    .trim()
+                    .rsplit([',', '-'])
+                    .next()
+                    .and_then(|last| last.parse::<usize>().ok())
+            })
+            .map_or(1, |last| last + 1)
+    })
+}
+
+// The node the calling thread ran on at its last refresh; 0 if `getcpu`
+// fails.
+pub(crate) fn current_node() -> usize {
+    CURRENT_NODE.with(|current| {
+        let (node, left) = current.get();
+
+        if left > 0 {
+            current.set((node, left - 1));
+            return node;
+        }
+
+        let (mut cpu, mut node): (c_uint, c_uint) = (0, 0);
+        let result = unsafe {
+            syscall(
+                SYS_GETCPU,
+                &mut cpu as *mut c_uint,
+                &mut node as *mut c_uint,
+                ptr::null_mut::<u8>(),
+            )
+        };
+        let node = if result == 0 { node as usize } else { 0 };
+
+        current.set((node, NODE_REFRESH_INTERVAL));
+        node
+    })
+}
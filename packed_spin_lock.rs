@@ -0,0 +1,183 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(feature = "held_locks")]
+use crate::held_locks;
+use crate::sync::{AtomicU16, AtomicU8, Ordering, UnsafeCell};
+use crate::{wait_while, GuardMarker};
+use std::marker::PhantomData;
+
+/// A spin lock whose state is a single byte placed in front of `T`, for
+/// large arrays of fine-grained locks. Unlike `SpinLock`, the footprint never
+/// grows with enabled features: `size_of::<SpinLockU8<()>>()` is exactly 1.
+#[repr(C)]
+pub struct SpinLockU8<T> {
+    lock_: AtomicU8,
+    data: UnsafeCell<T>,
+}
+
+#[cfg(not(loom))]
+const _: () = assert!(std::mem::size_of::<SpinLockU8<()>>() == 1);
+
+unsafe impl<T> Sync for SpinLockU8<T> where T: Send {}
+
+impl<T> SpinLockU8<T> {
+    pub fn new(data: T) -> SpinLockU8<T> {
+        SpinLockU8 {
+            lock_: AtomicU8::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) {
+        while self
+            .lock_
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            wait_while(|| self.lock_.load(Ordering::Relaxed) != 0);
+        }
+
+        #[cfg(feature = "held_locks")]
+        held_locks::acquired(self as *const SpinLockU8<T> as usize, None);
+    }
+
+    pub fn unlock(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::released(self as *const SpinLockU8<T> as usize);
+
+        if cfg!(debug_assertions) {
+            let was_locked = self
+                .lock_
+                .compare_exchange(1, 0, Ordering::Release, Ordering::Relaxed)
+                .is_ok();
+            assert!(was_locked, "unlock() called on a SpinLockU8 that is not locked");
+        } else {
+            self.lock_.store(0, Ordering::Release);
+        }
+    }
+
+    /// Releases the lock even if `f` panics. There is no poison flag to set;
+    /// that would not fit in the byte.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        struct Unlock<'a, T>(&'a SpinLockU8<T>);
+
+        impl<T> Drop for Unlock<'_, T> {
+            fn drop(&mut self) {
+                self.0.unlock();
+            }
+        }
+
+        self.lock();
+        let _unlock = Unlock(self);
+        #[cfg(not(loom))]
+        let result = unsafe { f(&mut *self.data.get()) };
+        #[cfg(loom)]
+        let result = self.data.with_mut(|ptr| unsafe { f(&mut *ptr) });
+        result
+    }
+}
+
+macro_rules! packed_spin_lock {
+    ($name:ident, $guard:ident, $atomic:ident, $int:ty) => {
+        /// A lock packed into the high bit of an integer whose remaining bits
+        /// carry the protected payload, so lock and data share one word.
+        pub struct $name {
+            word: $atomic,
+        }
+
+        pub struct $guard<'a> {
+            lock: &'a $name,
+            value: $int,
+            _marker: GuardMarker,
+        }
+
+        // The guard holds a copy of the payload, so sharing it shares an
+        // integer.
+        unsafe impl Sync for $guard<'_> {}
+
+        impl $name {
+            const LOCK_BIT: $int = 1 << (<$int>::BITS - 1);
+            pub const MAX_VALUE: $int = !Self::LOCK_BIT;
+
+            /// Panics if `value` uses the high bit reserved for the lock.
+            pub fn new(value: $int) -> $name {
+                assert!(value <= Self::MAX_VALUE, "payload overlaps the lock bit");
+
+                $name {
+                    word: $atomic::new(value),
+                }
+            }
+
+            pub fn lock(&self) -> $guard<'_> {
+                loop {
+                    let current = self.word.load(Ordering::Relaxed);
+
+                    if current & Self::LOCK_BIT == 0
+                        && self
+                            .word
+                            .compare_exchange(
+                                current,
+                                current | Self::LOCK_BIT,
+                                Ordering::Acquire,
+                                Ordering::Relaxed,
+                            )
+                            .is_ok()
+                    {
+                        #[cfg(feature = "held_locks")]
+                        held_locks::acquired(self as *const $name as usize, None);
+
+                        return $guard {
+                            lock: self,
+                            value: current,
+                            _marker: PhantomData,
+                        };
+                    }
+
+                    wait_while(|| self.word.load(Ordering::Relaxed) & Self::LOCK_BIT != 0);
+                }
+            }
+
+            /// Reads the payload without taking the lock.
+            pub fn load(&self) -> $int {
+                self.word.load(Ordering::Acquire) & Self::MAX_VALUE
+            }
+        }
+
+        impl $guard<'_> {
+            pub fn get(&self) -> $int {
+                self.value
+            }
+
+            /// Panics if `value` uses the high bit reserved for the lock.
+            pub fn set(&mut self, value: $int) {
+                assert!(value <= $name::MAX_VALUE, "payload overlaps the lock bit");
+                self.value = value;
+            }
+        }
+
+        impl Drop for $guard<'_> {
+            fn drop(&mut self) {
+                #[cfg(feature = "held_locks")]
+                held_locks::released(self.lock as *const $name as usize);
+                self.lock.word.store(self.value, Ordering::Release);
+            }
+        }
+    };
+}
+
+packed_spin_lock!(PackedSpinLockU8, PackedSpinLockU8Guard, AtomicU8, u8);
+packed_spin_lock!(PackedSpinLockU16, PackedSpinLockU16Guard, AtomicU16, u16);
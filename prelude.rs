@@ -0,0 +1,29 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! The types most code needs, for a single glob import:
+//!
+//! ```
+//! use spin_lock::prelude::*;
+//!
+//! let counter = SpinLock::new(0).into_shared();
+//! *counter.lock() += 1;
+//! ```
+pub use crate::{
+    Lock, MappedSpinLockGuard, RwLockLike, SpinLock, SpinLockGuard, SpinRwLock,
+    SpinRwLockReadGuard, SpinRwLockUpgradableGuard, SpinRwLockWriteGuard, TimeoutError,
+    TryLockError, TryLockResult,
+};
@@ -0,0 +1,394 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{SpinLock, SpinLockGuard, TryLockError};
+use std::cell::RefCell;
+use std::ffi::c_int;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SCHED_FIFO: c_int = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SCHED_RR: c_int = 2;
+#[cfg(target_vendor = "apple")]
+const SCHED_FIFO: c_int = 4;
+#[cfg(target_vendor = "apple")]
+const SCHED_RR: c_int = 2;
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+const SCHED_FIFO: c_int = 1;
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+const SCHED_RR: c_int = 3;
+
+const EPERM: c_int = 1;
+
+#[repr(C)]
+struct SchedParam {
+    sched_priority: c_int,
+    #[cfg(target_vendor = "apple")]
+    _opaque: [u8; 4],
+}
+
+// `pthread_t` is an `unsigned long` on Linux and a pointer on the BSDs and
+// Apple platforms, pointer-sized either way.
+type Pthread = usize;
+
+extern "C" {
+    fn pthread_self() -> Pthread;
+    fn pthread_getschedparam(thread: Pthread, policy: *mut c_int, param: *mut SchedParam) -> c_int;
+    fn pthread_setschedparam(thread: Pthread, policy: c_int, param: *const SchedParam) -> c_int;
+}
+
+// Set once raising has failed for lack of permission; every later
+// acquisition then locks without touching the scheduler.
+static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+static WARNED: AtomicBool = AtomicBool::new(false);
+static WARNING_HOOK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// A failed attempt to run at a lock's ceiling, passed to the hook installed
+/// with `set_ceiling_warning_hook`.
+#[derive(Debug)]
+pub struct CeilingError {
+    /// The ceiling the thread was to be raised to.
+    pub ceiling: i32,
+    /// What `pthread_setschedparam` returned: `PermissionDenied` without
+    /// `CAP_SYS_NICE` or an `RLIMIT_RTPRIO` that admits the ceiling, or
+    /// `InvalidInput` for a ceiling outside the `SCHED_FIFO` range.
+    pub error: io::Error,
+}
+
+impl fmt::Display for CeilingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot raise to priority ceiling {}: {}",
+            self.ceiling, self.error
+        )
+    }
+}
+
+impl std::error::Error for CeilingError {}
+
+/// Installs (or with `None`, removes) the hook told about the first failure
+/// to raise a thread to a `CeilingSpinLock`'s ceiling. It is called once per
+/// process, so install it before the first acquisition. A failure for lack
+/// of permission also turns raising off for the rest of the process, and
+/// every `CeilingSpinLock` then locks like a plain `SpinLock`.
+pub fn set_ceiling_warning_hook(hook: Option<fn(&CeilingError)>) {
+    let ptr = hook.map_or(std::ptr::null_mut(), |hook| hook as *mut ());
+    WARNING_HOOK.store(ptr, Ordering::Release);
+}
+
+#[cold]
+fn warn(ceiling: c_int, error: c_int) {
+    if error == EPERM {
+        UNAVAILABLE.store(true, Ordering::Relaxed);
+    }
+
+    let ptr = WARNING_HOOK.load(Ordering::Acquire);
+
+    if ptr.is_null() || WARNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let hook = unsafe { std::mem::transmute::<*mut (), fn(&CeilingError)>(ptr) };
+
+    hook(&CeilingError {
+        ceiling,
+        error: io::Error::from_raw_os_error(error),
+    });
+}
+
+#[derive(Clone, Copy)]
+struct Schedule {
+    policy: c_int,
+    priority: c_int,
+}
+
+impl Schedule {
+    // Where the schedule stands against a ceiling: its priority under a
+    // realtime policy, and below every ceiling under any other.
+    fn rank(self) -> c_int {
+        if self.policy == SCHED_FIFO || self.policy == SCHED_RR {
+            self.priority
+        } else {
+            0
+        }
+    }
+
+    fn current() -> Option<Schedule> {
+        let mut policy = 0;
+        let mut param = SchedParam {
+            sched_priority: 0,
+            #[cfg(target_vendor = "apple")]
+            _opaque: [0; 4],
+        };
+
+        let error = unsafe { pthread_getschedparam(pthread_self(), &mut policy, &mut param) };
+        (error == 0).then_some(Schedule {
+            policy,
+            priority: param.sched_priority,
+        })
+    }
+
+    // Switches the calling thread to this schedule, reporting a failure
+    // with `ceiling` as the priority that was wanted.
+    fn apply(self, ceiling: c_int) -> bool {
+        let param = SchedParam {
+            sched_priority: self.priority,
+            #[cfg(target_vendor = "apple")]
+            _opaque: [0; 4],
+        };
+
+        match unsafe { pthread_setschedparam(pthread_self(), self.policy, &param) } {
+            0 => true,
+            error => {
+                warn(ceiling, error);
+                false
+            }
+        }
+    }
+}
+
+// One thread's ceiling bookkeeping. Locks may be released in any order, so
+// the priority in force is recomputed from the ceilings still held.
+struct Ceilings {
+    // The ceiling of every `CeilingSpinLock` the thread holds or is waiting
+    // for, in acquisition order.
+    held: Vec<c_int>,
+    // The thread's own schedule, read at the outermost acquisition.
+    base: Schedule,
+    // The ceiling the thread runs at, or `None` while it runs on `base`.
+    raised: Option<c_int>,
+}
+
+thread_local! {
+    static CEILINGS: RefCell<Ceilings> = const {
+        RefCell::new(Ceilings {
+            held: Vec::new(),
+            base: Schedule { policy: 0, priority: 0 },
+            raised: None,
+        })
+    };
+}
+
+impl Ceilings {
+    fn enter(&mut self, ceiling: c_int) {
+        if self.held.is_empty() {
+            self.base = Schedule::current().unwrap_or(Schedule {
+                policy: 0,
+                priority: 0,
+            });
+        }
+
+        self.held.push(ceiling);
+
+        // The only scheduler call on the way in, and only for a ceiling
+        // above the priority the thread already runs at.
+        let in_force = self.raised.unwrap_or(self.base.rank());
+
+        if ceiling > in_force && !UNAVAILABLE.load(Ordering::Relaxed) {
+            let raise = Schedule {
+                policy: SCHED_FIFO,
+                priority: ceiling,
+            };
+
+            if raise.apply(ceiling) {
+                self.raised = Some(ceiling);
+            }
+        }
+    }
+
+    fn leave(&mut self, ceiling: c_int) {
+        if let Some(index) = self.held.iter().rposition(|&held| held == ceiling) {
+            self.held.remove(index);
+        }
+
+        let Some(raised) = self.raised else {
+            return;
+        };
+
+        // The highest ceiling still held decides; if none beats the thread's
+        // own priority, it goes back to that.
+        match self
+            .held
+            .iter()
+            .copied()
+            .filter(|&held| held > self.base.rank())
+            .max()
+        {
+            Some(highest) if highest >= raised => {}
+            Some(highest) => {
+                let lower = Schedule {
+                    policy: SCHED_FIFO,
+                    priority: highest,
+                };
+
+                if lower.apply(highest) {
+                    self.raised = Some(highest);
+                }
+            }
+            None => {
+                self.base.apply(raised);
+                self.raised = None;
+            }
+        }
+    }
+}
+
+// The calling thread's stake in one ceiling, from before the acquisition
+// until after the release. Bound to the thread's bookkeeping, so it never
+// moves to another thread, whatever `send_guard` says.
+struct Raise {
+    ceiling: c_int,
+    _marker: PhantomData<*const ()>,
+}
+
+impl Raise {
+    fn enter(ceiling: c_int) -> Raise {
+        CEILINGS.with_borrow_mut(|ceilings| ceilings.enter(ceiling));
+
+        Raise {
+            ceiling,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Drop for Raise {
+    fn drop(&mut self) {
+        CEILINGS.with_borrow_mut(|ceilings| ceilings.leave(self.ceiling));
+    }
+}
+
+/// A spin lock with a priority ceiling, for data shared with realtime
+/// threads (`priority_ceiling` feature, unix only).
+///
+/// Taking the lock first raises the calling thread to `SCHED_FIFO` at the
+/// ceiling, and releasing it restores the thread's own policy and priority,
+/// so a normal-priority holder cannot be preempted by medium-priority
+/// threads while a realtime thread waits for it. The ceiling should be at
+/// least the priority of the highest thread that takes the lock. A thread
+/// already at or above the ceiling, for instance because it holds a lock
+/// with a higher one, makes no scheduler calls. Nested locks may be released
+/// in any order: the thread runs at the highest ceiling it still holds.
+///
+/// Raising needs `CAP_SYS_NICE` or an `RLIMIT_RTPRIO` that admits the
+/// ceiling. Without them the lock works as a plain `SpinLock`, after telling
+/// the hook installed with `set_ceiling_warning_hook` once.
+pub struct CeilingSpinLock<T> {
+    ceiling: c_int,
+    inner: SpinLock<T>,
+}
+
+impl<T> CeilingSpinLock<T> {
+    /// A lock raising its holders to `ceiling`, a `SCHED_FIFO` priority
+    /// (1 to 99 on Linux).
+    pub fn new(data: T, ceiling: i32) -> CeilingSpinLock<T> {
+        CeilingSpinLock {
+            ceiling,
+            inner: SpinLock::new(data),
+        }
+    }
+
+    pub fn ceiling(&self) -> i32 {
+        self.ceiling
+    }
+
+    /// Raises the thread to the ceiling and then waits for the lock, so the
+    /// wait already runs at the ceiling too. Poisoning is ignored, as by
+    /// `SpinLock::lock`.
+    pub fn lock(&self) -> CeilingSpinLockGuard<'_, T> {
+        let raise = Raise::enter(self.ceiling);
+
+        CeilingSpinLockGuard {
+            guard: self.inner.lock(),
+            _raise: raise,
+        }
+    }
+
+    /// Like `lock`, with a single attempt. A failed attempt restores the
+    /// thread's priority before returning.
+    pub fn try_lock(&self) -> Option<CeilingSpinLockGuard<'_, T>> {
+        let raise = Raise::enter(self.ceiling);
+
+        let guard = match self.inner.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(_) => return None,
+        };
+
+        Some(CeilingSpinLockGuard {
+            guard,
+            _raise: raise,
+        })
+    }
+
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CeilingSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CeilingSpinLock")
+            .field("ceiling", &self.ceiling)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+// Fields drop in order: the lock is released before the priority drops, so
+// the holder finishes the release at the ceiling.
+pub struct CeilingSpinLockGuard<'a, T> {
+    guard: SpinLockGuard<'a, T>,
+    _raise: Raise,
+}
+
+unsafe impl<T> Sync for CeilingSpinLockGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for CeilingSpinLockGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CeilingSpinLockGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+forward_to_data!(CeilingSpinLockGuard<T>);
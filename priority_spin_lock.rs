@@ -0,0 +1,168 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::sync::{spin_loop, thread, AtomicUsize, Ordering, UnsafeCell};
+use crate::{wait_while, GuardMarker, RawSpinLock};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+// Busy-spins a priority waiter makes before yielding once; yielding still
+// matters when the holder shares the waiter's CPU.
+const PRIORITY_SPINS: usize = 100;
+
+/// A spin lock that biases contention toward designated threads.
+///
+/// While any `lock_priority` caller is waiting, `lock` callers stay in the
+/// yield/sleep backoff instead of competing for the lock, and the priority
+/// waiter busy-spins, yielding now and then but never sleeping, so that it
+/// notices the release first. This is a strong
+/// statistical bias, not a guarantee: a normal caller that already passed
+/// the check can still win the next acquisition.
+pub struct PrioritySpinLock<T> {
+    raw_: RawSpinLock,
+    // Number of `lock_priority` callers waiting for the lock.
+    priority_waiters_: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for PrioritySpinLock<T> where T: Send {}
+
+impl<T> PrioritySpinLock<T> {
+    pub fn new(data: T) -> PrioritySpinLock<T> {
+        PrioritySpinLock {
+            raw_: RawSpinLock::new(),
+            priority_waiters_: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires the lock as a background thread, holding back while a
+    /// priority waiter is present.
+    pub fn lock(&self) -> PrioritySpinLockGuard<'_, T> {
+        loop {
+            wait_while(|| {
+                self.priority_waiters_.load(Ordering::Relaxed) != 0 || self.raw_.is_locked()
+            });
+
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+    }
+
+    /// Acquires the lock ahead of `lock` callers.
+    pub fn lock_priority(&self) -> PrioritySpinLockGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        self.priority_waiters_.fetch_add(1, Ordering::Relaxed);
+
+        let mut spins = 0;
+
+        while !self.raw_.try_lock() {
+            while self.raw_.is_locked() {
+                if spins < PRIORITY_SPINS {
+                    spin_loop();
+                    spins += 1;
+                } else {
+                    thread::yield_now();
+                    spins = 0;
+                }
+            }
+        }
+
+        self.priority_waiters_.fetch_sub(1, Ordering::Relaxed);
+        self.guard()
+    }
+
+    pub fn try_lock(&self) -> Option<PrioritySpinLockGuard<'_, T>> {
+        self.raw_.try_lock().then(|| self.guard())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw_.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data_ptr() }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    fn guard(&self) -> PrioritySpinLockGuard<'_, T> {
+        PrioritySpinLockGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    #[cfg(loom)]
+    fn data_ptr(&self) -> *mut T {
+        self.data.with_mut(|ptr| ptr)
+    }
+}
+
+impl<T: Default> Default for PrioritySpinLock<T> {
+    fn default() -> PrioritySpinLock<T> {
+        PrioritySpinLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for PrioritySpinLock<T> {
+    fn from(data: T) -> PrioritySpinLock<T> {
+        PrioritySpinLock::new(data)
+    }
+}
+
+pub struct PrioritySpinLockGuard<'a, T> {
+    lock: &'a PrioritySpinLock<T>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for PrioritySpinLockGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for PrioritySpinLockGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
+
+impl<T> DerefMut for PrioritySpinLockGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data_ptr() }
+    }
+}
+
+forward_to_data!(PrioritySpinLockGuard<T>);
+
+impl<T> Drop for PrioritySpinLockGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.lock.raw_.unlock() }
+    }
+}
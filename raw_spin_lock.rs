@@ -0,0 +1,1468 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(feature = "deadlock_detection")]
+use crate::deadlock_detection;
+#[cfg(feature = "held_locks")]
+use crate::held_locks;
+#[cfg(all(feature = "elision", target_arch = "x86_64", not(loom)))]
+use crate::elision;
+#[cfg(all(feature = "futex", target_os = "linux", not(loom)))]
+use crate::futex;
+use crate::sync::{self, AtomicU8, AtomicUsize, Ordering};
+#[cfg(all(target_arch = "aarch64", not(loom)))]
+use crate::wfe;
+#[cfg(all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom)))]
+use crate::wasm_wait as futex;
+use crate::{SpinLockConfig, SpinWait, Strategy, WaitStrategy, WouldDeadlock};
+#[cfg(feature = "tracing")]
+use crate::ContentionTrace;
+#[cfg(feature = "contention_hook")]
+use crate::{sync::AtomicPtr, ContentionEvent, ContentionWatch};
+#[cfg(feature = "stats")]
+use crate::{LockStats, StatsCounters};
+#[cfg(feature = "timed")]
+use crate::{hold_clock, sync::AtomicU64, LongHold};
+#[cfg(all(feature = "versioned", not(feature = "timed")))]
+use crate::sync::AtomicU64;
+#[cfg(feature = "versioned")]
+use crate::sync::AtomicBool;
+use std::time::{Duration, Instant};
+
+// A callback installed by `on_contention`. Replaced callbacks stay linked
+// behind the current one until the lock drops, since a waiter may still be
+// running one when it is replaced.
+#[cfg(feature = "contention_hook")]
+struct ContentionCallback {
+    callback: Box<dyn Fn(ContentionEvent) + Send + Sync>,
+    replaced: *mut ContentionCallback,
+}
+
+// The lock word. LOCKED_BIT is set while the lock is held. CONTENDED_BIT is
+// only ever set alongside it: by a waiter about to sleep or park, and by a
+// slow-path acquisition that leaves other waiters registered. OWNED_BIT is
+// set alongside it by a `lock_checked` holder once its token is in `owner_`.
+// A release clears all three, so with `futex` or `wasm_wait` the unlock
+// learns from the word it swaps out whether anyone needs waking, and no
+// unlock has to look at `owner_` to forget a `lock_checked` owner.
+const LOCKED_BIT: u8 = 1;
+const CONTENDED_BIT: u8 = 1 << 1;
+const OWNED_BIT: u8 = 1 << 2;
+
+// The fair handoff bit lives in `waiters_` rather than the lock word: a
+// waiter has to claim a handoff in the same step that deregisters it, or a
+// handoff could be made to a waiter that has just given up.
+const FAIR_HANDOFF_BIT: usize = 1 << (usize::BITS - 1);
+
+// Set in `owner_` by the one waiter currently starving under
+// `anti_starvation`; thread tokens never reach it. Only the unlocks of locks
+// with `anti_starvation` load it.
+const STARVING_BIT: usize = 1 << (usize::BITS - 1);
+// Lost races for a free lock after which an `anti_starvation` waiter claims
+// the starving slot.
+const STARVATION_LOSSES: usize = 16;
+
+// With the `futex` feature, a waiter parks in the kernel instead of sleeping
+// and is woken by the next unlock; with `wasm_wait` it parks the same way in
+// `memory.atomic.wait32`. The timeout bounds how far a timed
+// acquisition can overshoot its deadline while parked.
+#[cfg(any(
+    all(feature = "futex", target_os = "linux", not(loom)),
+    all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+))]
+const FUTEX_WAIT_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Bounds for the adaptive spin budget: the budget is twice the running
+// estimate of spins needed to acquire, clamped to this range.
+#[cfg(feature = "adaptive")]
+const ADAPTIVE_MIN_SPINS: usize = 4;
+#[cfg(feature = "adaptive")]
+const ADAPTIVE_MAX_SPINS: usize = 1024;
+
+/// The lock state machine without any data: the fast path, the spin, yield
+/// and sleep slow path, fair handoff and the feature-gated bookkeeping.
+/// `SpinLock<T>` is this plus a poison flag and the protected value; on its
+/// own it guards data that cannot live inside the lock.
+pub struct RawSpinLock {
+    // LOCKED_BIT, plus CONTENDED_BIT while waiters may be parked.
+    state_: AtomicU8,
+    // Number of threads in the slow path, plus FAIR_HANDOFF_BIT while an
+    // `unlock_fair` handoff is waiting to be claimed.
+    waiters_: AtomicUsize,
+    // Token of the last thread to take the lock through `lock_checked`, or
+    // 0, which only names the holder while OWNED_BIT is set. Plus
+    // STARVING_BIT while a starving waiter is owed the lock.
+    owner_: AtomicUsize,
+    // Bumped by every release; guards remember it to catch use after unlock.
+    #[cfg(all(feature = "guard_generation", debug_assertions))]
+    generation_: AtomicUsize,
+    // Bumped by every release, under the lock, so a `read_snapshot` that
+    // finds it unchanged knows no hold began and ended during its copy.
+    #[cfg(feature = "seqlock")]
+    seq_: AtomicUsize,
+    // Set by a holder that took mutable access to the data; the release
+    // clears it and bumps `version_`, the count of such holds.
+    #[cfg(feature = "versioned")]
+    dirty_: AtomicBool,
+    #[cfg(feature = "versioned")]
+    version_: AtomicU64,
+    #[cfg(feature = "stats")]
+    stats_: StatsCounters,
+    #[cfg(any(feature = "tracing", feature = "held_locks"))]
+    name_: Option<&'static str>,
+    #[cfg(feature = "adaptive")]
+    spin_estimate_: AtomicUsize,
+    #[cfg(feature = "contention_hook")]
+    contention_callback_: AtomicPtr<ContentionCallback>,
+    // Set by `on_long_hold`: the threshold in nanoseconds (u64::MAX while
+    // unset) and the callback, plus the `hold_clock` time the current hold
+    // began, or 0 when it was not timed.
+    #[cfg(feature = "timed")]
+    hold_threshold_: AtomicU64,
+    #[cfg(feature = "timed")]
+    hold_callback_: std::sync::atomic::AtomicPtr<()>,
+    #[cfg(feature = "timed")]
+    acquired_at_: AtomicU64,
+    // The `SpinLockConfig` sleep bounds in microseconds, kept as two words
+    // rather than two `Duration`s to keep the lock small.
+    min_sleep_us_: u32,
+    max_sleep_us_: u32,
+    anti_starvation_: bool,
+    strategy_: Strategy,
+    // Bumped by every unlock that finds waiters; parked waiters futex-wait
+    // on it, since the kernel needs a 32-bit word.
+    #[cfg(any(
+        all(feature = "futex", target_os = "linux", not(loom)),
+        all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+    ))]
+    epoch_: std::sync::atomic::AtomicU32,
+    // The interrupt state the holder's critical section restores on unlock;
+    // only the holder reads or writes it.
+    #[cfg(feature = "critical-section")]
+    critical_section_: std::cell::UnsafeCell<CriticalSection>,
+}
+
+// The critical section is only touched by the thread holding the lock.
+#[cfg(feature = "critical-section")]
+unsafe impl Sync for RawSpinLock {}
+
+impl RawSpinLock {
+    const_unless_loom! {
+        pub fn new() -> RawSpinLock {
+            RawSpinLock::with_name(None)
+        }
+    }
+
+    const_unless_loom! {
+        /// A lock whose waiters sleep on the schedule `config` describes.
+        pub fn with_config(config: SpinLockConfig) -> RawSpinLock {
+            RawSpinLock::build(None, config)
+        }
+    }
+
+    const_unless_loom! {
+        /// A lock whose waiters back off with `strategy` on the default
+        /// sleep schedule.
+        pub fn with_strategy(strategy: Strategy) -> RawSpinLock {
+            RawSpinLock::build(None, SpinLockConfig { strategy, ..SpinLockConfig::DEFAULT })
+        }
+    }
+
+    pub(crate) fn new_named(name: &'static str) -> RawSpinLock {
+        RawSpinLock::with_name(Some(name))
+    }
+
+    // A fresh, unlocked lock that keeps this one's name and configuration.
+    pub(crate) fn new_like(&self) -> RawSpinLock {
+        #[cfg(any(feature = "tracing", feature = "held_locks"))]
+        let name = self.name_;
+        #[cfg(not(any(feature = "tracing", feature = "held_locks")))]
+        let name = None;
+        RawSpinLock::build(name, self.config())
+    }
+
+    const_unless_loom! {
+        fn with_name(name: Option<&'static str>) -> RawSpinLock {
+            RawSpinLock::build(name, SpinLockConfig::DEFAULT)
+        }
+    }
+
+    // Struct-update syntax is not available on a type with `Drop`, so every
+    // constructor goes through here.
+    const_unless_loom! {
+        #[cfg_attr(not(any(feature = "tracing", feature = "held_locks")), allow(unused_variables))]
+        fn build(name: Option<&'static str>, config: SpinLockConfig) -> RawSpinLock {
+            RawSpinLock {
+                state_: AtomicU8::new(0),
+                waiters_: AtomicUsize::new(0),
+                owner_: AtomicUsize::new(0),
+                #[cfg(all(feature = "guard_generation", debug_assertions))]
+                generation_: AtomicUsize::new(0),
+                #[cfg(feature = "seqlock")]
+                seq_: AtomicUsize::new(0),
+                #[cfg(feature = "versioned")]
+                dirty_: AtomicBool::new(false),
+                #[cfg(feature = "versioned")]
+                version_: AtomicU64::new(0),
+                #[cfg(feature = "stats")]
+                stats_: StatsCounters::new(),
+                #[cfg(any(feature = "tracing", feature = "held_locks"))]
+                name_: name,
+                #[cfg(feature = "adaptive")]
+                spin_estimate_: AtomicUsize::new(0),
+                #[cfg(feature = "contention_hook")]
+                contention_callback_: AtomicPtr::new(std::ptr::null_mut()),
+                #[cfg(feature = "timed")]
+                hold_threshold_: AtomicU64::new(u64::MAX),
+                #[cfg(feature = "timed")]
+                hold_callback_: std::sync::atomic::AtomicPtr::new(std::ptr::null_mut()),
+                #[cfg(feature = "timed")]
+                acquired_at_: AtomicU64::new(0),
+                min_sleep_us_: saturating_micros(config.min_sleep),
+                max_sleep_us_: saturating_micros(config.max_sleep),
+                anti_starvation_: config.anti_starvation,
+                strategy_: config.strategy,
+                #[cfg(any(
+                    all(feature = "futex", target_os = "linux", not(loom)),
+                    all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+                ))]
+                epoch_: std::sync::atomic::AtomicU32::new(0),
+                #[cfg(feature = "critical-section")]
+                critical_section_: std::cell::UnsafeCell::new(CriticalSection::NONE),
+            }
+        }
+    }
+
+    /// The sleep schedule the lock was built with, at microsecond precision.
+    pub fn config(&self) -> SpinLockConfig {
+        SpinLockConfig {
+            min_sleep: Duration::from_micros(self.min_sleep_us_ as u64),
+            max_sleep: Duration::from_micros(self.max_sleep_us_ as u64),
+            anti_starvation: self.anti_starvation_,
+            strategy: self.strategy_,
+        }
+    }
+
+    // The uncontended fast path is this single weak CAS; everything else is
+    // behind the cold call. With optimizations on, an uncontended
+    // `lock()`/`unlock()` pair compiles to one `lock cmpxchg`, a test of the
+    // `anti_starvation` flag and a plain byte store (verified on x86_64
+    // by inspecting the `rustc -O --emit asm` output for a caller in another
+    // crate). With the `elision` feature on x86_64 a hardware transaction is
+    // tried first, and the CAS only runs once it has been given up on.
+    #[inline]
+    pub fn lock(&self) {
+        let critical_section = CriticalSection::enter();
+        self.acquire();
+        self.keep_critical_section(critical_section);
+        self.note_held();
+    }
+
+    #[inline]
+    fn acquire(&self) {
+        #[cfg(all(feature = "elision", target_arch = "x86_64", not(loom)))]
+        if elision::begin(&self.state_) {
+            self.record_acquisition();
+            return;
+        }
+
+        if self
+            .state_
+            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.record_acquisition();
+        } else {
+            self.lock_slow();
+        }
+    }
+
+    // `lock` without a critical section of its own, for a lock that is only
+    // taken inside another lock's critical section and may be released by a
+    // different thread (`CohortLock`'s global lock); release it with
+    // `unlock_fair_nested`.
+    pub(crate) fn lock_nested(&self) {
+        self.acquire();
+    }
+
+    // The `try_lock` counterpart of `lock_nested`.
+    pub(crate) fn try_lock_nested(&self) -> bool {
+        let acquired = self
+            .state_
+            .compare_exchange(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.record_acquisition();
+        }
+
+        acquired
+    }
+
+    /// Like `lock`, but fails with `WouldDeadlock` instead of spinning forever
+    /// when the calling thread already holds the lock through an earlier
+    /// `lock_checked`. Acquisitions made any other way are not recorded, and
+    /// an `unlocked` or `bump` round trip forgets the owner. With
+    /// `send_guard`, the owner stays the thread that took the lock even after
+    /// its guard has moved on.
+    pub fn lock_checked(&self) -> Result<(), WouldDeadlock> {
+        let token = thread_token();
+
+        // A holder sets OWNED_BIT only after storing its token, so under the
+        // bit `owner_` names the holder. Only this thread ever stores its own
+        // token, so seeing it there means this thread holds the lock.
+        if self.state_.load(Ordering::Acquire) & OWNED_BIT != 0
+            && self.owner_.load(Ordering::Relaxed) & !STARVING_BIT == token
+        {
+            return Err(WouldDeadlock);
+        }
+
+        // Keeps STARVING_BIT, which a waiter may be setting meanwhile.
+        self.lock();
+        let _ = self.owner_.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |owner| {
+            Some(owner & STARVING_BIT | token)
+        });
+        self.state_.fetch_or(OWNED_BIT, Ordering::Release);
+        Ok(())
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lock_slow(&self) {
+        self.wait_contended(&mut self.backoff(), |_| false);
+    }
+
+    /// Like `lock`, but a contended wait backs off through `backoff` instead
+    /// of the lock's own schedule and waits, so a test can script its spins,
+    /// yields and sleeps with a `WaitStrategy` double (one that releases the
+    /// lock from its `sleep`, say) and count them afterwards.
+    pub fn lock_with<W: WaitStrategy>(&self, backoff: &mut SpinWait<W>) {
+        let critical_section = CriticalSection::enter();
+
+        if self
+            .state_
+            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.record_acquisition();
+        } else {
+            self.wait_contended(backoff, |_| false);
+        }
+
+        self.keep_critical_section(critical_section);
+        self.note_held();
+    }
+
+    /// Makes a single acquisition attempt. A strong CAS, so it never fails
+    /// spuriously on an unlocked lock.
+    #[inline(always)]
+    pub fn try_lock(&self) -> bool {
+        let critical_section = CriticalSection::enter();
+        let acquired = self
+            .state_
+            .compare_exchange(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.record_acquisition();
+            self.keep_critical_section(critical_section);
+            self.note_held();
+        } else {
+            critical_section.leave();
+        }
+
+        acquired
+    }
+
+    /// Retries for up to `spins` iterations of `spin_loop`, never yielding or
+    /// sleeping, so it is safe where the thread must not be descheduled.
+    pub fn try_lock_n(&self, spins: usize) -> bool {
+        if self.try_lock() {
+            return true;
+        }
+
+        for _ in 0..spins {
+            sync::spin_loop();
+
+            if !self.is_locked() && self.try_lock() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Spins, yields and sleeps until the lock is acquired or `timeout` has
+    /// elapsed. A timeout too large to represent as a deadline blocks.
+    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => {
+                self.lock();
+                true
+            }
+        }
+    }
+
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.try_lock() || self.lock_contended(|_| Instant::now() >= deadline)
+    }
+
+    /// Spins, yields and sleeps until the lock is acquired or `cancel` reads
+    /// `true`. The flag is checked after every failed attempt, so a waiter
+    /// gives up within one backoff sleep of it being set; one attempt is
+    /// made even if it was already set.
+    pub fn lock_cancellable(&self, cancel: &std::sync::atomic::AtomicBool) -> bool {
+        self.try_lock() || self.lock_contended(|_| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Whether the lock is held at this instant. Only a hint: it may change
+    /// as soon as it has been read. Inside a critical section entered by
+    /// hardware lock elision the lock reads as free.
+    pub fn is_locked(&self) -> bool {
+        self.state_.load(Ordering::Relaxed) & LOCKED_BIT != 0
+    }
+
+    /// Whether a waiter has backed off as far as sleeping (or, with `futex`,
+    /// parking) since the lock was taken, or the holder took it from other
+    /// waiters. Cleared by every release, and only a hint, like `is_locked`.
+    pub fn is_contended(&self) -> bool {
+        self.state_.load(Ordering::Relaxed) & CONTENDED_BIT != 0
+    }
+
+    // Thin address identifying the lock, for lock ordering and diagnostics.
+    pub(crate) fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // Slow path for the timed and bounded acquisitions; returns whether the
+    // lock was taken. `lock` enters the wait from its own critical section.
+    pub(crate) fn lock_contended(&self, expired: impl FnMut(&WaitProgress) -> bool) -> bool {
+        let critical_section = CriticalSection::enter();
+        let acquired = self.wait_contended(&mut self.backoff(), expired);
+
+        if acquired {
+            self.keep_critical_section(critical_section);
+            self.note_held();
+        } else {
+            critical_section.leave();
+        }
+
+        acquired
+    }
+
+    // The wait shared by every blocking acquisition. `expired` is asked
+    // after each look at the flag in the wait loop whether to give up, and
+    // sees the wait's progress so far; the last progress it saw is the
+    // wait's final account when it gives up.
+    #[cold]
+    #[inline(never)]
+    fn wait_contended<W: WaitStrategy>(
+        &self,
+        backoff: &mut SpinWait<W>,
+        mut expired: impl FnMut(&WaitProgress) -> bool,
+    ) -> bool {
+        let mut progress = WaitProgress {
+            attempts: 1,
+            slept: 0,
+            saw_unlocked: false,
+        };
+        let mut losses = 0;
+        let mut starving = false;
+        #[cfg(feature = "contention_hook")]
+        let mut backed_off = false;
+
+        #[cfg(feature = "tracing")]
+        let mut trace = ContentionTrace::begin(self.name_, self.addr());
+        #[cfg(feature = "contention_hook")]
+        let mut watch = ContentionWatch::begin(self.addr());
+        #[cfg(feature = "deadlock_detection")]
+        let mut deadlock_watch = deadlock_detection::Watch::begin(self.addr());
+
+        #[cfg(feature = "adaptive")]
+        let mut spins = match self.adaptive_spin() {
+            Ok(spins) => {
+                self.adaptive_update(spins);
+                self.record_contended_acquisition();
+                #[cfg(feature = "tracing")]
+                trace.acquired();
+                #[cfg(feature = "contention_hook")]
+                self.report_contention(|| ContentionEvent {
+                    lock_addr: self.addr(),
+                    spins: progress.attempts + spins,
+                    yielded: false,
+                    slept: false,
+                    waited: watch.waited(),
+                });
+                return true;
+            }
+            Err(budget) => {
+                progress.attempts += budget;
+                budget
+            }
+        };
+
+        self.waiters_.fetch_add(1, Ordering::Relaxed);
+
+        // Test-and-test-and-set: waiters only read the flag, which keeps the
+        // cache line shared among them, and try exactly one CAS each time it
+        // reads clear. A lost CAS goes back to reading rather than straight
+        // into another CAS, so a release sets off one round of write attempts
+        // instead of a storm of them.
+        // While another waiter is starving, the rest treat a free lock as
+        // held and leave it to that waiter.
+        let acquired = 'wait: loop {
+            while self.is_locked() || (!starving && self.someone_starving()) {
+                if self.claim_handoff(starving) {
+                    break 'wait true;
+                }
+
+                #[cfg(feature = "stats")]
+                StatsCounters::bump(&self.stats_.spin_iterations);
+                #[cfg(feature = "tracing")]
+                trace.spin();
+                #[cfg(feature = "contention_hook")]
+                watch.spin();
+                #[cfg(feature = "deadlock_detection")]
+                deadlock_watch.spin();
+                #[cfg(feature = "adaptive")]
+                {
+                    spins += 1;
+                }
+                progress.attempts += 1;
+
+                if expired(&progress) {
+                    break 'wait self.leave_waiters(starving);
+                }
+
+                #[cfg(feature = "contention_hook")]
+                {
+                    backed_off = true;
+                }
+
+                if starving {
+                    backoff.relax();
+                } else if !backoff.spin() {
+                    progress.slept += 1;
+                    #[cfg(feature = "stats")]
+                    StatsCounters::bump(&self.stats_.sleeps);
+                    #[cfg(feature = "tracing")]
+                    trace.sleep();
+                }
+            }
+
+            progress.saw_unlocked = true;
+
+            if self
+                .state_
+                .compare_exchange(0, self.locked_state(1), Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.waiters_.fetch_sub(1, Ordering::Relaxed);
+                break true;
+            }
+
+            progress.attempts += 1;
+            losses += 1;
+
+            if self.anti_starvation_ && !starving && losses >= STARVATION_LOSSES {
+                starving = self.claim_starving();
+            }
+
+            if expired(&progress) {
+                break 'wait self.leave_waiters(starving);
+            }
+        };
+
+        if starving {
+            self.owner_.fetch_and(!STARVING_BIT, Ordering::Relaxed);
+        }
+
+        if acquired {
+            #[cfg(feature = "adaptive")]
+            self.adaptive_update(spins);
+            self.record_contended_acquisition();
+            #[cfg(feature = "tracing")]
+            trace.acquired();
+            #[cfg(feature = "contention_hook")]
+            self.report_contention(|| ContentionEvent {
+                lock_addr: self.addr(),
+                spins: progress.attempts,
+                yielded: backed_off && backoff.yields(),
+                slept: progress.slept > 0,
+                waited: watch.waited(),
+            });
+        } else {
+            #[cfg(feature = "stats")]
+            StatsCounters::bump(&self.stats_.timeouts);
+            #[cfg(feature = "tracing")]
+            trace.timed_out();
+        }
+
+        acquired
+    }
+
+    // The word a slow-path acquisition stores: CONTENDED_BIT as well if waiters
+    // other than the `registered` ones counted for the caller remain, so the
+    // release still wakes them after the waiter it woke has taken the lock.
+    fn locked_state(&self, registered: usize) -> u8 {
+        if self.waiters_.load(Ordering::Relaxed) & !FAIR_HANDOFF_BIT > registered {
+            LOCKED_BIT | CONTENDED_BIT
+        } else {
+            LOCKED_BIT
+        }
+    }
+
+    // Sets CONTENDED_BIT before a waiter sleeps or parks, unless the lock has
+    // been released meanwhile. Returns whether the lock was still held.
+    fn mark_contended(&self) -> bool {
+        self.state_
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                (state & LOCKED_BIT != 0).then_some(state | CONTENDED_BIT)
+            })
+            .is_ok()
+    }
+
+    // Takes a lock handed over by `unlock_fair`; LOCKED_BIT stays set across
+    // the handoff, so only a registered waiter can end up owning it.
+    // A handoff made while a waiter is starving is for that waiter alone.
+    fn claim_handoff(&self, starving: bool) -> bool {
+        let waiters = self.waiters_.load(Ordering::Relaxed);
+
+        waiters & FAIR_HANDOFF_BIT != 0
+            && (starving || !self.someone_starving())
+            && self
+                .waiters_
+                .compare_exchange(
+                    waiters,
+                    (waiters & !FAIR_HANDOFF_BIT) - 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    #[inline]
+    fn someone_starving(&self) -> bool {
+        self.owner_.load(Ordering::Relaxed) & STARVING_BIT != 0
+    }
+
+    // Takes the starving slot, unless another waiter already has it.
+    fn claim_starving(&self) -> bool {
+        self.owner_.fetch_or(STARVING_BIT, Ordering::Relaxed) & STARVING_BIT == 0
+    }
+
+    // Deregisters a waiter that is giving up. Returns true if a fair unlock
+    // handed it the lock in the meantime, in which case it now owns it.
+    fn leave_waiters(&self, starving: bool) -> bool {
+        loop {
+            if self.claim_handoff(starving) {
+                return true;
+            }
+
+            let waiters = self.waiters_.load(Ordering::Relaxed);
+
+            if waiters & FAIR_HANDOFF_BIT == 0
+                && self
+                    .waiters_
+                    .compare_exchange(waiters, waiters - 1, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return false;
+            }
+        }
+    }
+
+    // The slow path's backoff: the lock's schedule over its own waits.
+    fn backoff(&self) -> SpinWait<LockWait<'_>> {
+        SpinWait::with_strategy(self.config(), LockWait(self))
+    }
+
+    #[cfg(not(any(
+        all(feature = "futex", target_os = "linux", not(loom)),
+        all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+    )))]
+    fn park(&self, sleep: Duration) {
+        self.mark_contended();
+        sync::thread::sleep(sleep);
+    }
+
+    // Setting CONTENDED_BIT on a held lock makes the release that clears it
+    // wake a waiter, after bumping the epoch read here first. The fence pairs
+    // with the one in `hand_off`: either this waiter sees the handoff, or the
+    // handoff sees the bit. The unlock wakes a parked waiter, so the sleep
+    // schedule does not apply.
+    #[cfg(any(
+        all(feature = "futex", target_os = "linux", not(loom)),
+        all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+    ))]
+    fn park(&self, _: Duration) {
+        let epoch = self.epoch_.load(Ordering::Acquire);
+
+        if !self.mark_contended() {
+            return;
+        }
+
+        std::sync::atomic::fence(Ordering::SeqCst);
+
+        if self.waiters_.load(Ordering::Relaxed) & FAIR_HANDOFF_BIT == 0 {
+            futex::wait(&self.epoch_, epoch, FUTEX_WAIT_TIMEOUT);
+        }
+    }
+
+    #[cfg(any(
+        all(feature = "futex", target_os = "linux", not(loom)),
+        all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+    ))]
+    #[cold]
+    #[inline(never)]
+    fn wake_one_parked(&self) {
+        self.epoch_.fetch_add(1, Ordering::Release);
+        futex::wake_one(&self.epoch_);
+    }
+
+    // With `held_locks`, tracks the acquisitions and releases made by the
+    // calling thread. `lock_nested` ones are left out, since another thread
+    // may release them.
+    #[inline]
+    fn note_held(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::acquired(self.addr(), self.name_);
+    }
+
+    #[inline]
+    fn note_released(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::released(self.addr());
+    }
+
+    #[inline]
+    fn record_acquisition(&self) {
+        self.fence_snapshots();
+        #[cfg(feature = "timed")]
+        self.start_hold();
+        #[cfg(feature = "stats")]
+        StatsCounters::bump(&self.stats_.acquisitions);
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_detection::acquired(self.addr());
+    }
+
+    fn record_contended_acquisition(&self) {
+        self.fence_snapshots();
+        #[cfg(feature = "timed")]
+        self.start_hold();
+        #[cfg(feature = "stats")]
+        {
+            StatsCounters::bump(&self.stats_.acquisitions);
+            StatsCounters::bump(&self.stats_.contended_acquisitions);
+        }
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_detection::acquired(self.addr());
+    }
+
+    #[cfg(feature = "adaptive")]
+    fn adaptive_spin(&self) -> Result<usize, usize> {
+        let budget = (self.spin_estimate_.load(Ordering::Relaxed) * 2)
+            .clamp(ADAPTIVE_MIN_SPINS, ADAPTIVE_MAX_SPINS);
+
+        for spins in 0..budget {
+            if !self.is_locked()
+                && self
+                    .state_
+                    .compare_exchange(0, self.locked_state(0), Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return Ok(spins);
+            }
+
+            sync::spin_loop();
+        }
+
+        Err(budget)
+    }
+
+    // Moves the estimate 1/8 of the way toward the observed spin count.
+    #[cfg(feature = "adaptive")]
+    fn adaptive_update(&self, observed: usize) {
+        let estimate = self.spin_estimate_.load(Ordering::Relaxed);
+        let observed = observed.min(ADAPTIVE_MAX_SPINS);
+        self.spin_estimate_
+            .store((estimate * 7 + observed) / 8, Ordering::Relaxed);
+    }
+
+    /// Bit 0 of the `as_atomic` word, set while the lock is held.
+    #[cfg(feature = "unstable-raw")]
+    pub const LOCKED_BIT: u8 = LOCKED_BIT;
+    /// Bit 1 of the `as_atomic` word, set alongside `LOCKED_BIT` while
+    /// waiters may be parked.
+    #[cfg(feature = "unstable-raw")]
+    pub const CONTENDED_BIT: u8 = CONTENDED_BIT;
+    /// Bit 2 of the `as_atomic` word, set alongside `LOCKED_BIT` while the
+    /// lock is held through `lock_checked`.
+    #[cfg(feature = "unstable-raw")]
+    pub const OWNED_BIT: u8 = OWNED_BIT;
+
+    /// Returns the word the lock is built on: `LOCKED_BIT` is set while the
+    /// lock is held, `CONTENDED_BIT` alongside it while waiters may be
+    /// parked, and `OWNED_BIT` while the holder took it through
+    /// `lock_checked`. It is zero exactly when the lock is free. It is not a
+    /// bool: test whether the lock is held by masking `LOCKED_BIT`, not by
+    /// comparing with 1.
+    ///
+    /// # Safety
+    ///
+    /// The word is the whole of the mutual exclusion. Setting bit 0 without
+    /// going through the lock, clearing the word while another thread holds
+    /// the lock, or acquiring it with weaker than `Acquire` ordering lets
+    /// safe code race on the protected data. Clearing bit 1 of a held lock
+    /// can leave a `futex` waiter parked until its timeout. Waiter
+    /// bookkeeping (fair handoff, statistics, deadlock detection) does not
+    /// see accesses made through this reference.
+    #[cfg(feature = "unstable-raw")]
+    pub unsafe fn as_atomic(&self) -> &AtomicU8 {
+        &self.state_
+    }
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// The calling thread must hold the lock. Debug builds panic if it is not
+    /// locked at all, catching double unlocks.
+    #[inline]
+    pub unsafe fn unlock(&self) {
+        self.note_released();
+        self.release(self.take_critical_section());
+    }
+
+    // `unlock`, leaving `critical_section` once the flag is clear. A
+    // starving waiter is handed the lock instead, as by `unlock_fair`.
+    #[inline]
+    unsafe fn release(&self, critical_section: CriticalSection) {
+        let released = self.relinquish();
+
+        if released.starving && !self.elided() {
+            self.hand_off(critical_section, released);
+        } else {
+            self.clear_flag(critical_section, released);
+        }
+    }
+
+    #[inline]
+    unsafe fn clear_flag(&self, critical_section: CriticalSection, released: Released) {
+        #[cfg(all(feature = "elision", target_arch = "x86_64", not(loom)))]
+        if self.elided() {
+            // Nothing else clears the word before the commit.
+            self.forget_owner();
+            elision::end();
+            critical_section.leave();
+            self.after_release(released);
+            return;
+        }
+
+        // A parked waiter needs the swap's old word to be woken; without
+        // parking, the release is a plain store unless double unlocks are
+        // being caught.
+        #[cfg(any(
+            all(feature = "futex", target_os = "linux", not(loom)),
+            all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+        ))]
+        {
+            let state = self.state_.swap(0, Ordering::Release);
+            debug_assert!(
+                state & LOCKED_BIT != 0,
+                "unlock() called on a spin lock that is not locked"
+            );
+
+            if state & CONTENDED_BIT != 0 {
+                self.wake_one_parked();
+            }
+        }
+        #[cfg(not(any(
+            all(feature = "futex", target_os = "linux", not(loom)),
+            all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+        )))]
+        if cfg!(debug_assertions) {
+            let state = self.state_.swap(0, Ordering::Release);
+            assert!(
+                state & LOCKED_BIT != 0,
+                "unlock() called on a spin lock that is not locked"
+            );
+        } else {
+            self.state_.store(0, Ordering::Release);
+        }
+
+        critical_section.leave();
+        self.after_release(released);
+    }
+
+    // The holder's bookkeeping, done while the lock is still held: once the
+    // flag is cleared or handed over, the next holder owns these fields.
+    #[inline]
+    fn relinquish(&self) -> Released {
+        #[cfg(feature = "timed")]
+        let long_hold = self.end_hold();
+        let starving = self.anti_starvation_ && self.someone_starving();
+        self.bump_generation();
+        self.bump_seq();
+        self.bump_version();
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_detection::released(self.addr());
+
+        Released {
+            #[cfg(feature = "timed")]
+            long_hold,
+            starving,
+        }
+    }
+
+    #[inline]
+    #[cfg_attr(not(feature = "timed"), allow(unused_variables))]
+    fn after_release(&self, released: Released) {
+        #[cfg(feature = "timed")]
+        self.report_long_hold(released.long_hold);
+    }
+
+    /// Releases the lock like `unlock`, but if other threads are waiting the
+    /// lock is handed directly to one of them instead of being reopened, so the
+    /// releasing thread cannot immediately win it back.
+    ///
+    /// # Safety
+    ///
+    /// As for `unlock`.
+    pub unsafe fn unlock_fair(&self) {
+        self.note_released();
+        // Read before the handoff, after which the slot is the next holder's.
+        self.release_fair(self.take_critical_section());
+    }
+
+    // Releases a `lock_nested` acquisition fairly.
+    pub(crate) unsafe fn unlock_fair_nested(&self) {
+        self.release_fair(CriticalSection::NONE);
+    }
+
+    unsafe fn release_fair(&self, critical_section: CriticalSection) {
+        // An elided section never set the flag, so there is nothing to hand
+        // over; `release` commits the transaction.
+        if self.elided() {
+            self.release(critical_section);
+            return;
+        }
+
+        let released = self.relinquish();
+        self.hand_off(critical_section, released);
+    }
+
+    // Hands the lock to a waiter if there is one, leaving the word as it is,
+    // and clears it otherwise. CONTENDED_BIT stays set for the new holder even
+    // if no one else waits, which costs at most one needless wake.
+    unsafe fn hand_off(&self, critical_section: CriticalSection, released: Released) {
+        // Before the handoff, after which the word is the next holder's.
+        self.forget_owner();
+        let handed_off = self
+            .waiters_
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |waiters| {
+                (waiters != 0 && waiters & FAIR_HANDOFF_BIT == 0)
+                    .then_some(waiters | FAIR_HANDOFF_BIT)
+            })
+            .is_ok();
+
+        if handed_off {
+            // The flag stays set across a handoff, so waiters in `wfe` on it
+            // need an explicit event to notice.
+            #[cfg(all(target_arch = "aarch64", not(loom)))]
+            wfe::wake_all();
+            #[cfg(any(
+                all(feature = "futex", target_os = "linux", not(loom)),
+                all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom))
+            ))]
+            {
+                std::sync::atomic::fence(Ordering::SeqCst);
+
+                if self.is_contended() {
+                    self.wake_one_parked();
+                }
+            }
+            critical_section.leave();
+            self.after_release(released);
+        } else {
+            self.clear_flag(critical_section, released);
+        }
+    }
+
+    // Whether the critical section being released is a transaction started
+    // by `lock` rather than a real acquisition: the flag only reads clear to
+    // its holder when it was never set. A transaction for some other lock
+    // does not count, since this one's flag is then set.
+    #[inline]
+    pub(crate) fn elided(&self) -> bool {
+        #[cfg(all(feature = "elision", target_arch = "x86_64", not(loom)))]
+        {
+            !self.is_locked() && elision::active()
+        }
+        #[cfg(not(all(feature = "elision", target_arch = "x86_64", not(loom))))]
+        {
+            false
+        }
+    }
+
+    #[inline]
+    #[cfg_attr(not(feature = "critical-section"), allow(unused_variables))]
+    fn keep_critical_section(&self, critical_section: CriticalSection) {
+        #[cfg(feature = "critical-section")]
+        unsafe {
+            *self.critical_section_.get() = critical_section;
+        }
+    }
+
+    #[inline]
+    fn take_critical_section(&self) -> CriticalSection {
+        #[cfg(feature = "critical-section")]
+        {
+            unsafe { *self.critical_section_.get() }
+        }
+        #[cfg(not(feature = "critical-section"))]
+        {
+            CriticalSection {}
+        }
+    }
+
+    // Clears OWNED_BIT on the releases that keep the word: handoffs and
+    // elided sections. A load first, so only holders through `lock_checked`
+    // write it.
+    fn forget_owner(&self) {
+        if self.state_.load(Ordering::Relaxed) & OWNED_BIT != 0 {
+            self.state_.fetch_and(!OWNED_BIT, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn bump_generation(&self) {
+        #[cfg(all(feature = "guard_generation", debug_assertions))]
+        self.generation_.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Only the holder writes the counter, and the release of the flag
+    // publishes it, so a plain load and store are enough.
+    #[inline]
+    fn bump_seq(&self) {
+        #[cfg(feature = "seqlock")]
+        self.seq_.store(self.seq_.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+    }
+
+    // Notes that the holder took mutable access to the data, so the release
+    // counts a new version. A no-op without `versioned`.
+    #[inline]
+    pub(crate) fn mark_dirty(&self) {
+        #[cfg(feature = "versioned")]
+        self.dirty_.store(true, Ordering::Relaxed);
+    }
+
+    // Like the sequence count, both fields belong to the holder; a waiter
+    // reads the version only to decide when to take the lock.
+    #[inline]
+    fn bump_version(&self) {
+        #[cfg(feature = "versioned")]
+        if self.dirty_.load(Ordering::Relaxed) {
+            self.dirty_.store(false, Ordering::Relaxed);
+            self.version_.store(self.version_.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(feature = "versioned")]
+    pub(crate) fn version(&self) -> u64 {
+        self.version_.load(Ordering::Relaxed)
+    }
+
+    // The seqlock writer's fence: the holder's writes to the data cannot
+    // become visible before the flag it just set, so a snapshot that sees
+    // any of them also sees the lock held. A no-op without `seqlock`.
+    #[inline]
+    fn fence_snapshots(&self) {
+        #[cfg(feature = "seqlock")]
+        sync::fence(Ordering::Release);
+    }
+
+    // Opens an optimistic read: the release count, or `None` while the lock
+    // is held.
+    #[cfg(all(feature = "seqlock", not(loom)))]
+    #[inline]
+    pub(crate) fn snapshot_begin(&self) -> Option<usize> {
+        (self.state_.load(Ordering::Acquire) & LOCKED_BIT == 0).then(|| self.seq_.load(Ordering::Relaxed))
+    }
+
+    // Closes an optimistic read opened at `seq`: true if no hold overlapped
+    // the reads made since. Seeing a write of any such hold makes the
+    // fence see its acquisition, and then either the flag still set or the
+    // count moved on.
+    #[cfg(all(feature = "seqlock", not(loom)))]
+    #[inline]
+    pub(crate) fn snapshot_valid(&self, seq: usize) -> bool {
+        sync::fence(Ordering::Acquire);
+        self.state_.load(Ordering::Acquire) & LOCKED_BIT == 0 && self.seq_.load(Ordering::Relaxed) == seq
+    }
+
+    // The acquisition a guard is being created for. Only the holder reads
+    // or bumps the counter, so relaxed accesses are enough.
+    #[inline]
+    pub(crate) fn guard_generation(&self) -> GuardGeneration {
+        GuardGeneration {
+            #[cfg(all(feature = "guard_generation", debug_assertions))]
+            value: self.generation_.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    #[cfg_attr(
+        not(all(feature = "guard_generation", debug_assertions)),
+        allow(unused_variables)
+    )]
+    pub(crate) fn check_generation(&self, generation: GuardGeneration) {
+        #[cfg(all(feature = "guard_generation", debug_assertions))]
+        {
+            let current = self.generation_.load(Ordering::Relaxed);
+            assert!(
+                current == generation.value,
+                "spin lock guard used after its lock was released (acquired at generation {}, lock is at {})",
+                generation.value,
+                current
+            );
+        }
+    }
+
+    /// Lets a waiting thread run a critical section before re-acquiring:
+    /// a fair unlock followed by `lock`. Does nothing when no one is waiting.
+    ///
+    /// # Safety
+    ///
+    /// As for `unlock`.
+    #[inline]
+    pub unsafe fn bump(&self) {
+        if self.waiters_.load(Ordering::Relaxed) != 0 {
+            self.bump_contended();
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    unsafe fn bump_contended(&self) {
+        self.unlock_fair();
+        self.lock();
+    }
+
+    /// Calls `callback` once for every acquisition that had to wait, with the
+    /// lock already held; see `SpinLock::on_contention`. Replaces an earlier
+    /// callback.
+    #[cfg(feature = "contention_hook")]
+    pub fn on_contention(&self, callback: impl Fn(ContentionEvent) + Send + Sync + 'static) {
+        let installed = Box::into_raw(Box::new(ContentionCallback {
+            callback: Box::new(callback),
+            replaced: std::ptr::null_mut(),
+        }));
+        let mut current = self.contention_callback_.load(Ordering::Relaxed);
+
+        // Only the new callback's link is written, and only before it is
+        // published.
+        loop {
+            unsafe { (*installed).replaced = current };
+
+            match self.contention_callback_.compare_exchange_weak(
+                current,
+                installed,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    // Runs the `on_contention` callback, if there is one, on the event the
+    // wait built. A panic cannot unwind out of here: the lock is held and
+    // the caller has no guard yet to release it, so it aborts. Nothing is
+    // printed first; with the `tracing` feature an `error!` event names the
+    // lock.
+    #[cfg(feature = "contention_hook")]
+    fn report_contention(&self, event: impl FnOnce() -> ContentionEvent) {
+        let callback = self.contention_callback_.load(Ordering::Acquire);
+
+        if callback.is_null() {
+            return;
+        }
+
+        let callback = unsafe { &(*callback).callback };
+
+        if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(event()))).is_err() {
+            #[cfg(feature = "tracing")]
+            tracing::error!(
+                lock_name = self.name_,
+                lock_addr = self.addr(),
+                "on_contention callback panicked while the lock was held"
+            );
+
+            std::process::abort();
+        }
+    }
+
+    /// Calls `callback` after every release that ends a hold longer than
+    /// `threshold`. Replaces an earlier threshold and callback; holds that
+    /// began before the call are not timed.
+    #[cfg(feature = "timed")]
+    pub fn on_long_hold(&self, threshold: Duration, callback: fn(&LongHold)) {
+        // u64::MAX is reserved for "unset".
+        let threshold = u64::try_from(threshold.as_nanos())
+            .unwrap_or(u64::MAX)
+            .min(u64::MAX - 1);
+        self.hold_callback_
+            .store(callback as *mut (), std::sync::atomic::Ordering::Release);
+        // Released after the callback, so a holder that sees the threshold
+        // also sees the callback.
+        self.hold_threshold_.store(threshold, Ordering::Release);
+    }
+
+    // Only the holder touches `acquired_at_`, so relaxed accesses are enough;
+    // the lock itself orders one holder's store before the next one's. The
+    // threshold is loaded with `Acquire` to pair with `on_long_hold`.
+    #[cfg(feature = "timed")]
+    #[inline]
+    fn start_hold(&self) {
+        if self.hold_threshold_.load(Ordering::Acquire) != u64::MAX {
+            self.acquired_at_.store(hold_clock().max(1), Ordering::Relaxed);
+        }
+    }
+
+    // Measured before the release, reported after it, so the callback can
+    // take this lock again.
+    #[cfg(feature = "timed")]
+    #[inline]
+    fn end_hold(&self) -> Option<LongHold> {
+        let threshold = self.hold_threshold_.load(Ordering::Acquire);
+
+        if threshold == u64::MAX {
+            return None;
+        }
+
+        let started = self.acquired_at_.load(Ordering::Relaxed);
+
+        if started == 0 {
+            return None;
+        }
+
+        self.acquired_at_.store(0, Ordering::Relaxed);
+        let held = hold_clock().saturating_sub(started);
+
+        (held > threshold).then(|| LongHold {
+            lock_addr: self.addr(),
+            held: Duration::from_nanos(held),
+            threshold: Duration::from_nanos(threshold),
+        })
+    }
+
+    #[cfg(feature = "timed")]
+    #[inline]
+    fn report_long_hold(&self, long_hold: Option<LongHold>) {
+        if let Some(long_hold) = long_hold {
+            self.fire_long_hold(&long_hold);
+        }
+    }
+
+    #[cfg(feature = "timed")]
+    #[cold]
+    #[inline(never)]
+    fn fire_long_hold(&self, long_hold: &LongHold) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            lock_name = self.name_,
+            lock_addr = long_hold.lock_addr,
+            held_us = long_hold.held.as_micros() as u64,
+            threshold_us = long_hold.threshold.as_micros() as u64,
+            "spin lock held longer than its threshold"
+        );
+
+        let ptr = self
+            .hold_callback_
+            .load(std::sync::atomic::Ordering::Acquire);
+
+        if ptr.is_null() {
+            return;
+        }
+
+        // SAFETY: only `on_long_hold` stores a non-null pointer, cast from a
+        // `fn(&LongHold)`.
+        let callback = unsafe { std::mem::transmute::<*mut (), fn(&LongHold)>(ptr) };
+        callback(long_hold);
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.stats_.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats_.reset();
+    }
+}
+
+// The lock generation a guard was created at. Empty unless the
+// `guard_generation` feature is on in a debug build.
+#[derive(Clone, Copy)]
+pub(crate) struct GuardGeneration {
+    #[cfg(all(feature = "guard_generation", debug_assertions))]
+    value: usize,
+}
+
+// A contended wait so far. `attempts` counts looks at the flag: the
+// caller's fast-path CAS, every adaptive spin, every load that finds the lock
+// held and every CAS that loses the race. `slept` counts backoff steps that
+// slept or parked, and `saw_unlocked` is set once a load found the flag clear.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WaitProgress {
+    pub(crate) attempts: usize,
+    pub(crate) slept: u32,
+    pub(crate) saw_unlocked: bool,
+}
+
+// The waits of the lock's own slow path. On aarch64 the yields are
+// replaced by `wfe` waits on the flag, which cost no power or SMT issue
+// slots and end as soon as the holder's release store reaches the flag, and
+// with `futex` or `wasm_wait` the sleeps park until the next unlock.
+struct LockWait<'a>(&'a RawSpinLock);
+
+impl WaitStrategy for LockWait<'_> {
+    fn spin(&mut self) {
+        sync::spin_loop();
+    }
+
+    fn yield_now(&mut self) {
+        #[cfg(all(target_arch = "aarch64", not(loom)))]
+        wfe::wait_while_set(&self.0.state_);
+        #[cfg(not(all(target_arch = "aarch64", not(loom))))]
+        sync::thread::yield_now();
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.0.park(duration);
+    }
+}
+
+// What `relinquish` found: for reporting once the lock is released, and
+// whether a starving waiter is owed it.
+struct Released {
+    #[cfg(feature = "timed")]
+    long_hold: Option<LongHold>,
+    starving: bool,
+}
+
+// With the `critical-section` feature, every acquisition enters a critical
+// section (masking interrupts on single-core targets) before touching the
+// flag, and the release leaves it after clearing the flag, so an interrupt
+// handler can never spin on a lock held by the code it interrupted. Empty
+// without the feature.
+#[derive(Clone, Copy)]
+pub(crate) struct CriticalSection {
+    #[cfg(feature = "critical-section")]
+    restore: Option<critical_section::RestoreState>,
+}
+
+impl CriticalSection {
+    // Nothing to leave: the slot of an unlocked lock, and the release of a
+    // lock taken with `lock_nested`.
+    const NONE: CriticalSection = CriticalSection {
+        #[cfg(feature = "critical-section")]
+        restore: None,
+    };
+
+    #[inline]
+    fn enter() -> CriticalSection {
+        CriticalSection {
+            #[cfg(feature = "critical-section")]
+            restore: Some(unsafe { critical_section::acquire() }),
+        }
+    }
+
+    // Critical sections must be left in the reverse order they were entered,
+    // which is why locks taken together are released in reverse.
+    #[inline]
+    fn leave(self) {
+        #[cfg(feature = "critical-section")]
+        if let Some(restore) = self.restore {
+            unsafe { critical_section::release(restore) };
+        }
+    }
+}
+
+const fn saturating_micros(duration: Duration) -> u32 {
+    let micros = duration.as_micros();
+
+    if micros > u32::MAX as u128 {
+        u32::MAX
+    } else {
+        micros as u32
+    }
+}
+
+// A per-thread nonzero token, for `lock_checked` and for picking a
+// `BravoRwLock` reader slot. `ThreadId` has no stable integer form, and
+// unlike a thread-local's address a token is never reused.
+pub(crate) fn thread_token() -> usize {
+    static NEXT_TOKEN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
+
+    thread_local! {
+        static TOKEN: usize = NEXT_TOKEN.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    TOKEN.with(|token| *token)
+}
+
+impl Default for RawSpinLock {
+    fn default() -> RawSpinLock {
+        RawSpinLock::new()
+    }
+}
+
+// A lock that is dropped while held means a guard was leaked (or a raw
+// `lock()` never got its `unlock()`). Not checked while unwinding, where a
+// second panic would abort, nor in release builds. `SpinLock` has no `Drop`
+// of its own: its drop glue runs this check and then drops the data once.
+impl Drop for RawSpinLock {
+    fn drop(&mut self) {
+        debug_assert!(
+            std::thread::panicking() || !self.is_locked(),
+            "spin lock dropped while locked"
+        );
+
+        // No waiter is left to be running a callback.
+        #[cfg(feature = "contention_hook")]
+        {
+            let mut callback = self.contention_callback_.load(Ordering::Acquire);
+
+            while !callback.is_null() {
+                let installed = unsafe { Box::from_raw(callback) };
+                callback = installed.replaced;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for RawSpinLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawSpinLock")
+            .field("locked", &self.is_locked())
+            .finish()
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::sync::{AtomicPtr, AtomicUsize, Ordering};
+use crate::{wait_while, SpinLock};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// A read-copy-update cell: readers take `Arc` snapshots of the current
+/// value without ever waiting, and writers publish whole new values one at
+/// a time through an internal `SpinLock`. Suited to read-mostly data such
+/// as configuration, where a reader may hold an old snapshot while a new
+/// one is installed.
+///
+/// A reader announces itself in one of two counters, loads the pointer and
+/// takes its own reference. A writer swaps in the new pointer, then twice
+/// moves readers over to the other counter and waits for the one they left
+/// to drain, before it lets go of the cell's reference to the old value.
+/// New readers always land in the counter not being waited on, so a steady
+/// stream of them cannot hold a writer up.
+pub struct RcuCell<T> {
+    current: AtomicPtr<T>,
+    // Which of `readers` new readers count themselves in, by its low bit.
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writer: SpinLock<()>,
+    _owns: PhantomData<Arc<T>>,
+}
+
+impl<T> RcuCell<T> {
+    pub fn new(value: T) -> RcuCell<T> {
+        RcuCell {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: SpinLock::new(()),
+            _owns: PhantomData,
+        }
+    }
+
+    /// A snapshot of the current value. Never waits, not even on writers.
+    pub fn read(&self) -> Arc<T> {
+        let readers = &self.readers[self.epoch.load(Ordering::SeqCst) & 1];
+        readers.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.current.load(Ordering::SeqCst);
+
+        // SAFETY: `ptr` came from `Arc::into_raw`, and the cell's reference
+        // to it is kept until this reader leaves `readers`.
+        let snapshot = unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        };
+
+        // `SeqCst` like every other access to the counters. With a
+        // `Release` decrement Miri's weak memory emulation lets a writer
+        // miss a reader that still has the old pointer, and frees it under
+        // the reader; on x86_64 both compile to the same `lock xadd`.
+        readers.fetch_sub(1, Ordering::SeqCst);
+        snapshot
+    }
+
+    /// Publishes `value`, returning the value it replaced.
+    pub fn replace(&self, value: T) -> Arc<T> {
+        let value = Arc::new(value);
+        self.writer.with_lock(|_| self.publish(value))
+    }
+
+    /// Publishes `f`'s copy of the current value. Writers queue on the lock,
+    /// so no update is lost to a concurrent one; readers see the old value
+    /// until the new one is in place.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) {
+        self.writer.with_lock(|_| {
+            // SAFETY: only writers replace the pointer, and this one holds
+            // the lock, so the value stays alive for the closure.
+            let value = f(unsafe { &*self.current.load(Ordering::Acquire) });
+            drop(self.publish(Arc::new(value)));
+        })
+    }
+
+    /// Publishes `value` only if the cell still holds `current`, comparing
+    /// by pointer, and returns the replaced value. Otherwise hands `value`
+    /// back.
+    pub fn compare_and_swap(&self, current: &Arc<T>, value: T) -> Result<Arc<T>, T> {
+        self.writer.with_lock(|_| {
+            if std::ptr::eq(self.current.load(Ordering::Acquire), Arc::as_ptr(current)) {
+                Ok(self.publish(Arc::new(value)))
+            } else {
+                Err(value)
+            }
+        })
+    }
+
+    // Called with the writer lock held. Every reader that could have loaded
+    // the old pointer counted itself in before the swap, so once both
+    // counters have drained after it, each has its own reference.
+    fn publish(&self, value: Arc<T>) -> Arc<T> {
+        let old = self
+            .current
+            .swap(Arc::into_raw(value) as *mut T, Ordering::SeqCst);
+
+        for _ in 0..2 {
+            let left = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+            wait_while(|| self.readers[left].load(Ordering::SeqCst) != 0);
+        }
+
+        // SAFETY: the cell's reference, kept by `current` until the swap.
+        unsafe { Arc::from_raw(old) }
+    }
+
+    pub fn into_inner(self) -> Arc<T> {
+        self.read()
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // SAFETY: the cell's own reference; no reader can be running.
+        drop(unsafe { Arc::from_raw(self.current.load(Ordering::Relaxed)) });
+    }
+}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> RcuCell<T> {
+        RcuCell::new(T::default())
+    }
+}
+
+impl<T> From<T> for RcuCell<T> {
+    fn from(value: T) -> RcuCell<T> {
+        RcuCell::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RcuCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RcuCell")
+            .field("value", &self.read())
+            .finish()
+    }
+}
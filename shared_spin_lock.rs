@@ -0,0 +1,375 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(feature = "held_locks")]
+use crate::held_locks;
+use crate::{GuardMarker, SpinWait};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// A lock that works between processes over shared memory (`memfd`,
+/// `shm_open` or a `MAP_SHARED` mapping), as well as between threads.
+///
+/// The layout is part of the API: `#[repr(C)]`, one `u32` word, 4 bytes
+/// with 4-byte alignment, where 0 means unlocked and 1 locked. A zero-filled
+/// page therefore holds unlocked locks, and the layout will not change
+/// without a major version bump.
+///
+/// Everything the lock does lives in that word. There is no owner tracking
+/// keyed on thread identity, no statistics, no poisoning and no parking in
+/// the kernel or in std: waiters spin, yield and sleep with the usual
+/// `SpinWait` schedule, all of which works across processes. A process that
+/// dies while holding the lock leaves it locked.
+#[repr(C)]
+pub struct RawSharedSpinLock {
+    word: AtomicU32,
+}
+
+const _: () = assert!(std::mem::size_of::<RawSharedSpinLock>() == 4);
+const _: () = assert!(std::mem::align_of::<RawSharedSpinLock>() == 4);
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+
+impl RawSharedSpinLock {
+    pub const fn new() -> RawSharedSpinLock {
+        RawSharedSpinLock {
+            word: AtomicU32::new(UNLOCKED),
+        }
+    }
+
+    /// Views caller-provided memory, such as a slot in a shared mapping, as
+    /// a lock.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be non-null, 4-byte aligned and valid for reads and writes
+    /// for `'a`, and the 4 bytes must hold 0 or 1: zero them (or write a
+    /// `RawSharedSpinLock::new()`) before the first use. For `'a`, the memory
+    /// must only be accessed through locks created this way.
+    pub unsafe fn from_raw<'a>(ptr: *mut RawSharedSpinLock) -> &'a RawSharedSpinLock {
+        &*ptr
+    }
+
+    pub fn lock(&self) {
+        let mut backoff = SpinWait::new();
+
+        // Read until the word looks free, then try once.
+        while !self.try_lock() {
+            while self.is_locked() {
+                backoff.spin();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        let acquired = self
+            .word
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        #[cfg(feature = "held_locks")]
+        if acquired {
+            held_locks::acquired(self as *const RawSharedSpinLock as usize, None);
+        }
+
+        acquired
+    }
+
+    /// Spins, yields and sleeps until the lock is acquired or `timeout` has
+    /// elapsed.
+    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        let mut backoff = SpinWait::new();
+
+        loop {
+            if self.try_lock() {
+                return true;
+            }
+
+            while self.is_locked() {
+                if start.elapsed() >= timeout {
+                    return false;
+                }
+
+                backoff.spin();
+            }
+        }
+    }
+
+    /// Whether the lock is held at this instant, by any process. Only a hint.
+    pub fn is_locked(&self) -> bool {
+        self.word.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// The calling thread must hold the lock. Debug builds panic if it is not
+    /// locked at all, catching double unlocks.
+    pub unsafe fn unlock(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::released(self as *const RawSharedSpinLock as usize);
+        let previous = self.word.swap(UNLOCKED, Ordering::Release);
+        debug_assert!(
+            previous == LOCKED,
+            "unlock() called on a shared spin lock that is not locked"
+        );
+    }
+
+    /// Runs `f` under the lock, releasing it even if `f` panics.
+    pub fn with_lock<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Unlock<'a>(&'a RawSharedSpinLock);
+
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                unsafe { self.0.unlock() };
+            }
+        }
+
+        self.lock();
+        let _unlock = Unlock(self);
+        f()
+    }
+}
+
+impl Default for RawSharedSpinLock {
+    fn default() -> RawSharedSpinLock {
+        RawSharedSpinLock::new()
+    }
+}
+
+impl std::fmt::Debug for RawSharedSpinLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawSharedSpinLock")
+            .field("locked", &self.is_locked())
+            .finish()
+    }
+}
+
+// How long `RobustSharedSpinLock::lock` waits before it starts asking
+// whether the holder is still alive.
+const DEFAULT_PROBE_AFTER: Duration = Duration::from_millis(10);
+
+#[cfg(unix)]
+const ESRCH: i32 = 3;
+
+#[cfg(unix)]
+extern "C" {
+    fn getpid() -> i32;
+    fn kill(pid: i32, signal: i32) -> i32;
+}
+
+/// A process-shared lock that survives its holder dying, like a
+/// `PTHREAD_MUTEX_ROBUST` mutex. The lock word holds the owner, by default
+/// the holder's PID, or 0 while unlocked. A waiter that has waited past a
+/// threshold checks whether the owner is still alive and, if it is not,
+/// takes the lock over with a CAS from the dead owner to itself, so of
+/// several recoverers exactly one wins. The guard it gets reports
+/// `recovered()`, telling the caller to repair whatever the dead owner left
+/// half-done.
+///
+/// The layout is `#[repr(C)]` around one `u32`, 4 bytes with 4-byte
+/// alignment; a zero-filled page holds unlocked locks. Threads of one
+/// process share its PID, so they exclude each other as usual but are never
+/// recovered from. A dead owner that has not been reaped yet (a zombie)
+/// still counts as alive. PIDs are reused: an owner that died and whose PID
+/// now belongs to a live process is not detected until that process exits
+/// too.
+#[repr(C)]
+pub struct RobustSharedSpinLock {
+    owner: AtomicU32,
+}
+
+const _: () = assert!(std::mem::size_of::<RobustSharedSpinLock>() == 4);
+const _: () = assert!(std::mem::align_of::<RobustSharedSpinLock>() == 4);
+
+impl RobustSharedSpinLock {
+    pub const fn new() -> RobustSharedSpinLock {
+        RobustSharedSpinLock {
+            owner: AtomicU32::new(UNLOCKED),
+        }
+    }
+
+    /// As `RawSharedSpinLock::from_raw`: the 4 bytes must be zeroed or hold
+    /// an owner written by this type.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be non-null, 4-byte aligned and valid for reads and writes
+    /// for `'a`, and must only be accessed through locks created this way.
+    pub unsafe fn from_raw<'a>(ptr: *mut RobustSharedSpinLock) -> &'a RobustSharedSpinLock {
+        &*ptr
+    }
+
+    /// Locks as this process, recovering the lock from a holder process
+    /// that no longer exists once the wait passes 10 ms.
+    #[cfg(unix)]
+    pub fn lock(&self) -> RobustSharedSpinLockGuard<'_> {
+        self.lock_probing_after(DEFAULT_PROBE_AFTER)
+    }
+
+    /// Like `lock`, starting the liveness checks after `probe_after`.
+    #[cfg(unix)]
+    pub fn lock_probing_after(&self, probe_after: Duration) -> RobustSharedSpinLockGuard<'_> {
+        self.lock_as(current_pid(), probe_after, pid_alive)
+    }
+
+    #[cfg(unix)]
+    pub fn try_lock(&self) -> Option<RobustSharedSpinLockGuard<'_>> {
+        self.try_lock_as(current_pid())
+    }
+
+    /// Locks with a caller-chosen owner token instead of the PID. Once the
+    /// wait passes `probe_after`, every backoff step asks `is_alive` about
+    /// the current owner and takes the lock over when it answers false.
+    ///
+    /// Panics if `owner` is 0, which marks the lock free.
+    pub fn lock_as(
+        &self,
+        owner: u32,
+        probe_after: Duration,
+        mut is_alive: impl FnMut(u32) -> bool,
+    ) -> RobustSharedSpinLockGuard<'_> {
+        assert!(owner != UNLOCKED, "owner token 0 is reserved for unlocked");
+
+        let start = Instant::now();
+        let mut backoff = SpinWait::new();
+
+        loop {
+            if let Some(guard) = self.try_lock_as(owner) {
+                return guard;
+            }
+
+            loop {
+                let holder = self.owner.load(Ordering::Relaxed);
+
+                if holder == UNLOCKED {
+                    break;
+                }
+
+                if holder != owner && start.elapsed() >= probe_after && !is_alive(holder) {
+                    if self
+                        .owner
+                        .compare_exchange(holder, owner, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                    {
+                        return self.guard(owner, true);
+                    }
+
+                    // Another recoverer won, or the holder let go.
+                    continue;
+                }
+
+                backoff.spin();
+            }
+        }
+    }
+
+    pub fn try_lock_as(&self, owner: u32) -> Option<RobustSharedSpinLockGuard<'_>> {
+        assert!(owner != UNLOCKED, "owner token 0 is reserved for unlocked");
+
+        self.owner
+            .compare_exchange(UNLOCKED, owner, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| self.guard(owner, false))
+    }
+
+    fn guard(&self, owner: u32, recovered: bool) -> RobustSharedSpinLockGuard<'_> {
+        #[cfg(feature = "held_locks")]
+        held_locks::acquired(self as *const RobustSharedSpinLock as usize, None);
+
+        RobustSharedSpinLockGuard {
+            lock: self,
+            owner,
+            recovered,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The current owner token (the holder's PID for `lock`), or `None` if
+    /// unlocked. Only a hint.
+    pub fn owner(&self) -> Option<u32> {
+        match self.owner.load(Ordering::Relaxed) {
+            UNLOCKED => None,
+            owner => Some(owner),
+        }
+    }
+}
+
+impl Default for RobustSharedSpinLock {
+    fn default() -> RobustSharedSpinLock {
+        RobustSharedSpinLock::new()
+    }
+}
+
+impl std::fmt::Debug for RobustSharedSpinLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RobustSharedSpinLock")
+            .field("owner", &self.owner())
+            .finish()
+    }
+}
+
+/// Holds a `RobustSharedSpinLock` until dropped.
+pub struct RobustSharedSpinLockGuard<'a> {
+    lock: &'a RobustSharedSpinLock,
+    owner: u32,
+    recovered: bool,
+    _marker: GuardMarker,
+}
+
+// The guard reaches no data, so sharing it only shares `recovered`.
+unsafe impl Sync for RobustSharedSpinLockGuard<'_> {}
+
+impl RobustSharedSpinLockGuard<'_> {
+    /// Whether the lock was taken over from an owner that had died holding
+    /// it, so the data it protects may be in an inconsistent state.
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+}
+
+impl Drop for RobustSharedSpinLockGuard<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::released(self.lock as *const RobustSharedSpinLock as usize);
+        let previous = self.lock.owner.swap(UNLOCKED, Ordering::Release);
+        debug_assert!(
+            previous == self.owner,
+            "robust shared spin lock was taken over from a live owner"
+        );
+    }
+}
+
+#[cfg(unix)]
+fn current_pid() -> u32 {
+    unsafe { getpid() as u32 }
+}
+
+// Signal 0 runs the existence and permission checks without sending
+// anything. Only ESRCH means gone: EPERM is a live process of another user.
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+
+    let found = unsafe { kill(pid, 0) } == 0;
+    found || std::io::Error::last_os_error().raw_os_error() != Some(ESRCH)
+}
@@ -0,0 +1,116 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+/* The C side of the `ffi` feature (ffi.rs), in the layout cbindgen emits
+ * for it with `language = "C"`. */
+
+#ifndef SPIN_LOCK_H
+#define SPIN_LOCK_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The call succeeded; for `spinlock_try_lock`, the lock was acquired.
+ */
+#define SPINLOCK_OK 0
+
+/**
+ * `spinlock_try_lock` found the lock held.
+ */
+#define SPINLOCK_BUSY 1
+
+/**
+ * The handle was null.
+ */
+#define SPINLOCK_NULL -1
+
+/**
+ * `spinlock_unlock` was called on a lock that is not locked.
+ */
+#define SPINLOCK_NOT_LOCKED -2
+
+/**
+ * A panic (from a feature such as `deadlock_detection`) was caught at the
+ * boundary; the lock is in the state it was in before the call.
+ */
+#define SPINLOCK_PANICKED -3
+
+/**
+ * The lock behind a C handle. Opaque to C, which only ever sees a pointer
+ * to it; the data it protects is whatever the caller decides.
+ */
+typedef struct CSpinLock CSpinLock;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Allocates a new, unlocked lock. Returns null if construction panicked.
+ * Release it with `spinlock_free`.
+ */
+CSpinLock *spinlock_new(void);
+
+/**
+ * Blocks until the lock is acquired. Returns `SPINLOCK_OK`, or
+ * `SPINLOCK_NULL` for a null handle.
+ *
+ * # Safety
+ *
+ * `lock` must be null or a live handle from `spinlock_new`.
+ */
+int spinlock_lock(const CSpinLock *lock);
+
+/**
+ * Makes a single acquisition attempt. Returns `SPINLOCK_OK` if the lock
+ * was acquired, `SPINLOCK_BUSY` if it is held, or `SPINLOCK_NULL`.
+ *
+ * # Safety
+ *
+ * As for `spinlock_lock`.
+ */
+int spinlock_try_lock(const CSpinLock *lock);
+
+/**
+ * Releases the lock. Returns `SPINLOCK_OK`, `SPINLOCK_NOT_LOCKED` if the
+ * lock is free, or `SPINLOCK_NULL`.
+ *
+ * # Safety
+ *
+ * As for `spinlock_lock`, and the calling thread must be the one holding
+ * the lock. Only a free lock is caught; releasing one held by another
+ * thread lets both race on the data.
+ */
+int spinlock_unlock(const CSpinLock *lock);
+
+/**
+ * Frees a handle from `spinlock_new`. A null handle is ignored.
+ *
+ * # Safety
+ *
+ * `lock` must be null or a live handle from `spinlock_new` that no thread
+ * holds or is waiting for, and it must not be used again.
+ */
+void spinlock_free(CSpinLock *lock);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif // __cplusplus
+
+#endif /* SPIN_LOCK_H */
@@ -14,106 +14,1859 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+#![cfg_attr(
+    all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom)),
+    feature(stdarch_wasm_atomic_wait)
+)]
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, PoisonError};
 use std::time::Duration;
+use std::time::Instant;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+#[cfg(feature = "contention_hook")]
+use std::sync::atomic::AtomicPtr;
+
+#[cfg(feature = "stats")]
+use sync::AtomicU64;
+use sync::{AtomicBool, Ordering, UnsafeCell};
+
+// A sent guard releases its lock on another thread than the one that took
+// it, which these features cannot follow: a deadlock report would blame the
+// wrong thread, and a critical section or a hardware transaction has to end
+// on the thread that began it.
+#[cfg(all(feature = "send_guard", feature = "deadlock_detection"))]
+compile_error!("the `send_guard` and `deadlock_detection` features cannot be enabled together");
+#[cfg(all(feature = "send_guard", feature = "critical-section"))]
+compile_error!("the `send_guard` and `critical-section` features cannot be enabled together");
+#[cfg(all(feature = "send_guard", feature = "elision"))]
+compile_error!("the `send_guard` and `elision` features cannot be enabled together");
+
+// Emits `fn` as a `const fn` except under loom, whose atomics cannot be
+// created in a const context. Defined ahead of the modules that use it.
+macro_rules! const_unless_loom {
+    ($(#[$attr:meta])* $vis:vis fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty $body:block) => {
+        $(#[$attr])*
+        #[cfg(not(loom))]
+        $vis const fn $name($($arg: $ty),*) -> $ret $body
+
+        $(#[$attr])*
+        #[cfg(loom)]
+        $vis fn $name($($arg: $ty),*) -> $ret $body
+    };
+}
+
+// Forwards `Debug`, `Display` and `PartialEq<T>` from a guard to the data
+// it derefs to, so printing or comparing a guard shows the data rather than
+// the guard. Every guard flavor over a `T` invokes it. Defined ahead of the
+// modules that use it.
+macro_rules! forward_to_data {
+    ($guard:ident<$t:ident $(: ?$sized:ident)?>) => {
+        impl<$t: $(?$sized +)? std::fmt::Debug> std::fmt::Debug for $guard<'_, $t> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Debug::fmt(&**self, f)
+            }
+        }
+
+        impl<$t: $(?$sized +)? std::fmt::Display> std::fmt::Display for $guard<'_, $t> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Display::fmt(&**self, f)
+            }
+        }
+
+        impl<$t: $(?$sized +)? PartialEq> PartialEq<$t> for $guard<'_, $t> {
+            fn eq(&self, other: &$t) -> bool {
+                **self == *other
+            }
+        }
+    };
+}
+
+/// Runs a block with a lock held, written either as `lock => |data| body`,
+/// with `data` bound to `&mut` the locked value, or as
+/// `let [mut] guard = lock; statements`, with the guard bound instead. The
+/// lock is anything with a `lock()` method, taken by reference, and the guard
+/// drops when the block ends. The block evaluates to the body's value.
+///
+/// Unlike `with_lock`, the body is not a closure: `return`, `?` and `break`
+/// leave the enclosing function or loop, releasing the lock on the way out.
+///
+/// ```
+/// use spin_lock::{spin_lock, SpinLock};
+///
+/// let counter = SpinLock::new(0);
+/// spin_lock!(counter => |count| *count += 1);
+/// let doubled = spin_lock!(let mut count = counter; *count += 1; *count * 2);
+/// assert_eq!(doubled, 4);
+/// ```
+#[macro_export]
+macro_rules! spin_lock {
+    ($lock:expr => |$data:pat_param| $body:expr) => {{
+        let mut guard = $lock.lock();
+        let $data = &mut *guard;
+        $body
+    }};
+    (let mut $guard:ident = $lock:expr; $($body:tt)*) => {{
+        let mut $guard = $lock.lock();
+        $($body)*
+    }};
+    (let $guard:ident = $lock:expr; $($body:tt)*) => {{
+        let $guard = $lock.lock();
+        $($body)*
+    }};
+}
+
+#[cfg(feature = "deadlock_detection")]
+mod deadlock_detection;
+#[cfg(all(feature = "elision", target_arch = "x86_64", not(loom)))]
+mod elision;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(all(feature = "futex", target_os = "linux", not(loom)))]
+mod futex;
+#[cfg(feature = "held_locks")]
+mod held_locks;
+#[cfg(all(feature = "numa", target_os = "linux"))]
+mod numa;
+#[cfg(all(feature = "priority_ceiling", unix))]
+mod priority_ceiling;
+#[cfg(all(feature = "wasm_wait", target_arch = "wasm32", target_feature = "atomics", not(loom)))]
+mod wasm_wait;
+#[cfg(all(target_arch = "aarch64", not(loom)))]
+mod wfe;
+mod atomic_cell_compat;
+mod atomic_spin_cell;
+mod bravo_rw_lock;
+mod cache_padded;
+mod cohort_lock;
+mod lock;
+mod lock_stripe;
+pub mod compat;
+mod packed_spin_lock;
+pub mod prelude;
+mod priority_spin_lock;
+mod raw_spin_lock;
+mod rcu_cell;
+mod shared_spin_lock;
+mod spin_once_cell;
+mod spin_queue;
+mod spin_rw_lock;
+mod spin_slot;
+mod spin_wait;
+
+pub use atomic_cell_compat::AtomicCellCompat;
+pub use atomic_spin_cell::{AtomicPayload, AtomicSpinCell};
+pub use bravo_rw_lock::{BravoRwLock, BravoRwLockReadGuard, BravoRwLockWriteGuard};
+pub use cache_padded::{Align128, Align256, Align32, Align64, CacheLine, CachePadded};
+pub use cohort_lock::{CohortLock, CohortLockGuard};
+#[cfg(feature = "ffi")]
+pub use ffi::{
+    spinlock_free, spinlock_lock, spinlock_new, spinlock_try_lock, spinlock_unlock, CSpinLock,
+    SPINLOCK_BUSY, SPINLOCK_NOT_LOCKED, SPINLOCK_NULL, SPINLOCK_OK, SPINLOCK_PANICKED,
+};
+#[cfg(feature = "held_locks")]
+pub use held_locks::{assert_no_locks_held, held_lock_count};
+pub use lock::{Lock, RwLockLike};
+pub use lock_stripe::{LockStripe, StripeAllGuard, StripeGuard};
+pub use packed_spin_lock::{
+    PackedSpinLockU16, PackedSpinLockU16Guard, PackedSpinLockU8, PackedSpinLockU8Guard, SpinLockU8,
+};
+#[cfg(all(feature = "priority_ceiling", unix))]
+pub use priority_ceiling::{set_ceiling_warning_hook, CeilingError, CeilingSpinLock, CeilingSpinLockGuard};
+pub use priority_spin_lock::{PrioritySpinLock, PrioritySpinLockGuard};
+use raw_spin_lock::{GuardGeneration, WaitProgress};
+pub use raw_spin_lock::RawSpinLock;
+pub use rcu_cell::RcuCell;
+pub use shared_spin_lock::{RawSharedSpinLock, RobustSharedSpinLock, RobustSharedSpinLockGuard};
+pub use spin_once_cell::SpinOnceCell;
+pub use spin_queue::SpinQueue;
+pub use spin_rw_lock::{SpinRwLock, SpinRwLockReadGuard, SpinRwLockUpgradableGuard, SpinRwLockWriteGuard};
+pub use spin_slot::SpinSlot;
+pub use spin_wait::{SpinLockConfig, SpinWait, Strategy, ThreadWait, WaitStrategy};
+
+// Everything the lock synchronizes through goes via this module so that
+// `--cfg loom` can swap in loom's model-checked types.
+mod sync {
+    #[cfg(loom)]
+    pub(crate) use loom::cell::UnsafeCell;
+    #[cfg(loom)]
+    pub(crate) use loom::hint::spin_loop;
+    #[cfg(loom)]
+    pub(crate) use loom::sync::atomic::AtomicU64;
+    #[cfg(loom)]
+    pub(crate) use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU8, AtomicUsize, Ordering};
+    #[cfg(all(loom, feature = "seqlock"))]
+    pub(crate) use loom::sync::atomic::fence;
+
+    #[cfg(not(loom))]
+    pub(crate) use std::cell::UnsafeCell;
+    #[cfg(not(loom))]
+    pub(crate) use std::hint::spin_loop;
+    #[cfg(not(loom))]
+    pub(crate) use std::sync::atomic::AtomicU64;
+    #[cfg(not(loom))]
+    pub(crate) use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU8, AtomicUsize, Ordering};
+    #[cfg(all(not(loom), feature = "seqlock"))]
+    pub(crate) use std::sync::atomic::fence;
+
+    pub(crate) mod thread {
+        #[cfg(loom)]
+        pub(crate) use loom::thread::yield_now;
+        #[cfg(not(any(loom, all(target_family = "wasm", target_os = "unknown", not(target_feature = "atomics")))))]
+        pub(crate) use std::thread::{sleep, yield_now};
+
+        // loom does not model time, so a sleep is just another scheduling point.
+        #[cfg(loom)]
+        pub(crate) fn sleep(_: std::time::Duration) {
+            loom::thread::yield_now();
+        }
+
+        // Without the atomics proposal a wasm module has a single thread:
+        // `std::thread::sleep` panics there and `yield_now` does nothing, so
+        // both come down to a spin hint.
+        #[cfg(all(not(loom), all(target_family = "wasm", target_os = "unknown", not(target_feature = "atomics"))))]
+        pub(crate) fn sleep(_: std::time::Duration) {
+            std::hint::spin_loop();
+        }
+
+        #[cfg(all(not(loom), all(target_family = "wasm", target_os = "unknown", not(target_feature = "atomics"))))]
+        pub(crate) fn yield_now() {
+            std::hint::spin_loop();
+        }
+    }
+}
 
-const USE_SLEEP_SPIN_LOCK: bool = true;
 const SPIN_LOCK_SLEEP_ONE_FREQUENCY: usize = 50;
+// Randomizes how many yields happen between sleeps (25..75) and the sleep
+// itself (0.5..1.5 ms) so waiters that started together drift apart instead
+// of waking in lockstep. Off under loom, which needs deterministic runs.
+const USE_JITTERED_BACKOFF: bool = !cfg!(loom);
 const SPIN_LOCK_MAX_ATTEMPTS: usize = 500;
+// Optimistic copies `read_snapshot` makes before it takes the lock instead.
+#[cfg(all(feature = "seqlock", not(loom)))]
+const SNAPSHOT_ATTEMPTS: usize = 4;
+// Acquisition attempts `lock_async_tokio` spins through between awaits.
+#[cfg(feature = "tokio")]
+const ASYNC_SPIN_BURST: usize = 64;
 
-pub struct SpinLock<T> {
-    lock_: AtomicBool,
-    pub data: std::cell::UnsafeCell<T>,
+thread_local! {
+    static JITTER_STATE: Cell<u32> = const { Cell::new(0) };
 }
 
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+// xorshift32 over a per-thread state, seeded on first use. Returns a value
+// in `0..bound`.
+fn jitter(bound: u32) -> u32 {
+    JITTER_STATE.with(|state| {
+        let mut x = state.get();
 
-impl<T> SpinLock<T> {
-    pub fn new(data: T) -> SpinLock<T> {
-        SpinLock {
-            lock_: AtomicBool::new(false),
-            data: std::cell::UnsafeCell::new(data),
+        if x == 0 {
+            let seed = RandomState::new().build_hasher().finish();
+            x = (seed as u32 ^ (seed >> 32) as u32) | 1;
+        }
+
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        state.set(x);
+        x % bound
+    })
+}
+
+fn sleep_frequency() -> usize {
+    if USE_JITTERED_BACKOFF {
+        SPIN_LOCK_SLEEP_ONE_FREQUENCY / 2 + jitter(SPIN_LOCK_SLEEP_ONE_FREQUENCY as u32) as usize
+    } else {
+        SPIN_LOCK_SLEEP_ONE_FREQUENCY
+    }
+}
+
+// The yield-then-sleep backoff of `SpinLock::lock`, for the crate's other
+// primitives to wait on their own state.
+fn wait_while(busy: impl FnMut() -> bool) {
+    wait_while_with(SpinLockConfig::DEFAULT, busy);
+}
+
+// `wait_while` backing off as `config` describes, so the `compat::spin`
+// types can pass `Strategy::Busy` and never yield or sleep.
+fn wait_while_with(config: SpinLockConfig, mut busy: impl FnMut() -> bool) {
+    let mut backoff = SpinWait::with_config(config);
+
+    while busy() {
+        backoff.spin();
+    }
+}
+
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    pub contended_acquisitions: u64,
+    pub spin_iterations: u64,
+    pub sleeps: u64,
+    pub timeouts: u64,
+}
+
+#[cfg(feature = "stats")]
+struct StatsCounters {
+    acquisitions: AtomicU64,
+    contended_acquisitions: AtomicU64,
+    spin_iterations: AtomicU64,
+    sleeps: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl StatsCounters {
+    const_unless_loom! {
+        fn new() -> StatsCounters {
+            StatsCounters {
+                acquisitions: AtomicU64::new(0),
+                contended_acquisitions: AtomicU64::new(0),
+                spin_iterations: AtomicU64::new(0),
+                sleeps: AtomicU64::new(0),
+                timeouts: AtomicU64::new(0),
+            }
+        }
+    }
+
+    fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended_acquisitions: self.contended_acquisitions.load(Ordering::Relaxed),
+            spin_iterations: self.spin_iterations.load(Ordering::Relaxed),
+            sleeps: self.sleeps.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended_acquisitions.store(0, Ordering::Relaxed);
+        self.spin_iterations.store(0, Ordering::Relaxed);
+        self.sleeps.store(0, Ordering::Relaxed);
+        self.timeouts.store(0, Ordering::Relaxed);
+    }
+
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "tracing")]
+struct ContentionTrace {
+    name: &'static str,
+    addr: usize,
+    started: Instant,
+    spins: usize,
+    slept: bool,
+}
+
+#[cfg(feature = "tracing")]
+impl ContentionTrace {
+    fn begin(name: Option<&'static str>, addr: usize) -> ContentionTrace {
+        let name = name.unwrap_or("<unnamed>");
+        tracing::trace!(lock_name = name, lock_addr = addr, "spin lock contended");
+
+        ContentionTrace {
+            name,
+            addr,
+            started: Instant::now(),
+            spins: 0,
+            slept: false,
+        }
+    }
+
+    fn spin(&mut self) {
+        self.spins += 1;
+    }
+
+    fn sleep(&mut self) {
+        self.slept = true;
+    }
+
+    fn acquired(&self) {
+        tracing::trace!(
+            lock_name = self.name,
+            lock_addr = self.addr,
+            spins = self.spins,
+            slept = self.slept,
+            elapsed_us = self.started.elapsed().as_micros() as u64,
+            "spin lock acquired after contention"
+        );
+    }
+
+    fn timed_out(&self) {
+        tracing::trace!(
+            lock_name = self.name,
+            lock_addr = self.addr,
+            spins = self.spins,
+            slept = self.slept,
+            elapsed_us = self.started.elapsed().as_micros() as u64,
+            "spin lock acquisition timed out"
+        );
+    }
+}
+
+#[cfg(feature = "contention_hook")]
+const DEFAULT_CONTENTION_HOOK_THRESHOLD: usize = 1000;
+
+#[cfg(feature = "contention_hook")]
+#[derive(Debug, Clone, Copy)]
+pub struct ContentionInfo {
+    pub lock_addr: usize,
+    pub waited: Duration,
+    pub attempts: usize,
+}
+
+/// One acquisition that had to wait, passed to the lock's `on_contention`
+/// callback once the waiter holds the lock.
+#[cfg(feature = "contention_hook")]
+#[derive(Debug, Clone, Copy)]
+pub struct ContentionEvent {
+    pub lock_addr: usize,
+    /// Looks at the lock the acquisition took, counted as for
+    /// `TimeoutError::attempts`.
+    pub spins: usize,
+    /// Whether the wait got as far as yielding the thread, rather than only
+    /// spinning (under `Strategy::Busy`, or within the `adaptive` budget).
+    pub yielded: bool,
+    /// Whether the wait got as far as sleeping or parking.
+    pub slept: bool,
+    pub waited: Duration,
+}
+
+#[cfg(feature = "contention_hook")]
+static CONTENTION_HOOK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+#[cfg(feature = "contention_hook")]
+static CONTENTION_HOOK_THRESHOLD: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(DEFAULT_CONTENTION_HOOK_THRESHOLD);
+
+#[cfg(feature = "contention_hook")]
+thread_local! {
+    static IN_CONTENTION_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs (or with `None`, removes) a hook called from the slow path once a
+/// waiter has spun past the contention hook threshold. The hook runs while the
+/// waiter holds nothing, so it may take other spin locks; contention inside the
+/// hook itself does not re-invoke it.
+#[cfg(feature = "contention_hook")]
+pub fn set_global_contention_hook(hook: Option<fn(&ContentionInfo)>) {
+    let ptr = hook.map_or(std::ptr::null_mut(), |hook| hook as *mut ());
+    CONTENTION_HOOK.store(ptr, Ordering::Release);
+}
+
+#[cfg(feature = "contention_hook")]
+pub fn set_contention_hook_threshold(attempts: usize) {
+    CONTENTION_HOOK_THRESHOLD.store(attempts, Ordering::Relaxed);
+}
+
+#[cfg(feature = "contention_hook")]
+struct ContentionWatch {
+    addr: usize,
+    started: Instant,
+    attempts: usize,
+    threshold: usize,
+}
+
+#[cfg(feature = "contention_hook")]
+impl ContentionWatch {
+    fn begin(addr: usize) -> ContentionWatch {
+        ContentionWatch {
+            addr,
+            started: Instant::now(),
+            attempts: 0,
+            threshold: CONTENTION_HOOK_THRESHOLD.load(Ordering::Relaxed),
+        }
+    }
+
+    fn spin(&mut self) {
+        self.attempts += 1;
+
+        if self.attempts == self.threshold {
+            self.fire();
+        }
+    }
+
+    fn waited(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    #[cold]
+    fn fire(&self) {
+        let ptr = CONTENTION_HOOK.load(Ordering::Acquire);
+
+        if ptr.is_null() || IN_CONTENTION_HOOK.with(|in_hook| in_hook.replace(true)) {
+            return;
+        }
+
+        let hook = unsafe { std::mem::transmute::<*mut (), fn(&ContentionInfo)>(ptr) };
+
+        hook(&ContentionInfo {
+            lock_addr: self.addr,
+            waited: self.started.elapsed(),
+            attempts: self.attempts,
+        });
+
+        IN_CONTENTION_HOOK.with(|in_hook| in_hook.set(false));
+    }
+}
+
+/// A critical section that held its lock for longer than the threshold given
+/// to `on_long_hold`, passed to that lock's callback after the release.
+#[cfg(feature = "timed")]
+#[derive(Debug, Clone, Copy)]
+pub struct LongHold {
+    pub lock_addr: usize,
+    pub held: Duration,
+    pub threshold: Duration,
+}
+
+// Acquisition times are kept as nanoseconds since the first timed lock
+// operation, so they fit in an atomic word.
+#[cfg(feature = "timed")]
+pub(crate) fn hold_clock() -> u64 {
+    static EPOCH: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+// The `on_modify` callback with the value's type erased, since a field that
+// names `T` ahead of `data` would rule out unsizing `SpinLock<[T; N]>` into
+// `SpinLock<[T]>`. It is called with pointers to two `T`s.
+#[cfg(feature = "audit")]
+type AuditCallback = Box<dyn Fn(*const (), *const ()) + Send + Sync>;
+
+pub struct SpinLock<T: ?Sized> {
+    raw_: RawSpinLock,
+    poison_: AtomicBool,
+    // Read and replaced only under the lock.
+    #[cfg(feature = "audit")]
+    audit_: UnsafeCell<Option<AuditCallback>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized> Sync for SpinLock<T> where T: Send {}
+
+/// Why a non-blocking or timed acquisition failed, mirroring
+/// `std::sync::TryLockError`. `G` is the guard type handed back when the lock
+/// was acquired but is poisoned.
+pub enum TryLockError<G> {
+    /// The lock was held at the instant of the attempt.
+    WouldBlock,
+    /// The lock stayed held for the whole timeout.
+    TimedOut,
+    /// The lock was acquired, but a previous holder panicked.
+    Poisoned(PoisonError<G>),
+}
+
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+impl<G> From<PoisonError<G>> for TryLockError<G> {
+    fn from(err: PoisonError<G>) -> TryLockError<G> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::WouldBlock => f.write_str("WouldBlock"),
+            TryLockError::TimedOut => f.write_str("TimedOut"),
+            TryLockError::Poisoned(..) => f.write_str("Poisoned(..)"),
         }
     }
+}
+
+impl<G> fmt::Display for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::WouldBlock => f.write_str("the spin lock is held by another thread"),
+            TryLockError::TimedOut => f.write_str("timed out waiting for the spin lock"),
+            TryLockError::Poisoned(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl<G> Error for TryLockError<G> {}
+
+/// Returned by `with_lock_timeout` and `lock_with_max_attempts` when the
+/// caller gave up on the lock: what the wait looked like, for telling a
+/// momentary pile-up from a stuck holder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    waited: Duration,
+    attempts: usize,
+    slept: u32,
+    held_throughout: bool,
+}
+
+impl TimeoutError {
+    fn new(waited: Duration, progress: WaitProgress) -> TimeoutError {
+        TimeoutError {
+            waited,
+            attempts: progress.attempts,
+            slept: progress.slept,
+            held_throughout: !progress.saw_unlocked,
+        }
+    }
+
+    /// How long the caller waited before giving up.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// How many times the lock was looked at, counted as for
+    /// `lock_with_max_attempts`.
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// How many backoff steps slept (or parked, with the `futex` feature).
+    pub fn slept(&self) -> u32 {
+        self.slept
+    }
+
+    /// Whether every look found the lock held. When true, one holder may have
+    /// kept it the whole time; when false it came free at least once and the
+    /// caller lost the race for it. Fair handoffs keep the lock held, so they
+    /// do not show here.
+    pub fn held_throughout(&self) -> bool {
+        self.held_throughout
+    }
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gave up on the spin lock after {:?} ({} attempts, {} sleeps, {})",
+            self.waited,
+            self.attempts,
+            self.slept,
+            if self.held_throughout { "held throughout" } else { "lost races for it" }
+        )
+    }
+}
+
+impl Error for TimeoutError {}
+
+#[deprecated(note = "renamed to `TimeoutError`; its fields are now accessors")]
+pub type LockError = TimeoutError;
+
+/// Returned by `lock_checked` when the calling thread already holds the lock,
+/// where `lock` would spin forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldDeadlock;
+
+impl fmt::Display for WouldDeadlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("lock_checked() called by the thread that already holds this spin lock")
+    }
+}
+
+impl Error for WouldDeadlock {}
+
+#[inline]
+fn poison_if_panicking(poison: &AtomicBool) {
+    if std::thread::panicking() {
+        poison.store(true, Ordering::Relaxed);
+    }
+}
+
+// Every guard's marker. Guards release the lock on the thread that took it,
+// so they are !Send, unless `send_guard` lets them move: then each guard is
+// `Send` exactly when its fields are.
+#[cfg(not(feature = "send_guard"))]
+pub(crate) type GuardMarker = PhantomData<*const ()>;
+#[cfg(feature = "send_guard")]
+pub(crate) type GuardMarker = PhantomData<()>;
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    generation: GuardGeneration,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T: ?Sized> Sync for SpinLockGuard<'_, T> where T: Sync {}
+
+// Re-acquires on drop, so a guard released for a closure is locked again
+// even when the closure unwinds.
+struct Relock<'a, 'b, T: ?Sized>(&'b mut SpinLockGuard<'a, T>);
+
+impl<T: ?Sized> Drop for Relock<'_, '_, T> {
+    fn drop(&mut self) {
+        self.0.lock.raw_.lock();
+        self.0.generation = self.0.lock.raw_.guard_generation();
+    }
+}
+
+impl<T: ?Sized> SpinLockGuard<'_, T> {
+    /// Releases the lock while `f` runs and re-acquires it before returning,
+    /// even if `f` panics. Other threads may lock and modify the data in the
+    /// meantime, so anything read through the guard before the call must be
+    /// re-checked afterwards.
+    pub fn unlocked<R>(s: &mut Self, f: impl FnOnce() -> R) -> R {
+        s.lock.unlock();
+        let _relock = Relock(s);
+        f()
+    }
+
+    /// Like `unlocked`, but releases with `unlock_fair`.
+    pub fn unlocked_fair<R>(s: &mut Self, f: impl FnOnce() -> R) -> R {
+        s.lock.unlock_fair();
+        let _relock = Relock(s);
+        f()
+    }
+
+    pub fn unlock_fair(s: Self) {
+        let lock = s.lock;
+        std::mem::forget(s);
+        lock.poison_if_panicking();
+        lock.unlock_fair();
+    }
+
+    /// Lets a waiting thread run a critical section before re-acquiring:
+    /// a fair unlock followed by a lock. Does nothing when no one is waiting,
+    /// at the cost of one relaxed load, so a long batch can call it every
+    /// step.
+    pub fn bump(s: &mut Self) {
+        unsafe { s.lock.raw_.bump() }
+        s.generation = s.lock.raw_.guard_generation();
+    }
+}
+
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    /// The lock this guard holds.
+    pub fn lock_ref(s: &Self) -> &'a SpinLock<T> {
+        s.lock
+    }
 
-    pub fn lock(&self) {
-        let mut freq = 0;
+    /// Narrows the guard to a component of the protected data. The lock is
+    /// released when the mapped guard is dropped.
+    pub fn map<U: ?Sized>(s: Self, f: impl FnOnce(&mut T) -> &mut U) -> MappedSpinLockGuard<'a, U> {
+        let (lock, generation) = (s.lock, s.generation);
+        let data: *mut U = f(unsafe { &mut *lock.data_ptr() });
+        std::mem::forget(s);
+
+        MappedSpinLockGuard {
+            raw: &lock.raw_,
+            poison: &lock.poison_,
+            generation,
+            data,
+            share: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Releases the lock, fairly so that a waiting producer gets it next, and
+    /// then backs off and re-locks to test `pred` until it holds, returning
+    /// the guard of the acquisition that saw it hold. `pred` only ever sees
+    /// the data under the lock, so there is no wakeup to miss: a change made
+    /// between two checks is seen by the next one.
+    pub fn unlock_and_wait_until(s: Self, mut pred: impl FnMut(&T) -> bool) -> SpinLockGuard<'a, T> {
+        let lock = s.lock;
+        SpinLockGuard::unlock_fair(s);
+        let mut backoff = SpinWait::new();
 
         loop {
-            if self
-                .lock_
-                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
+            backoff.spin();
+            let guard = lock.lock();
+
+            if pred(&guard) {
+                return guard;
+            }
+        }
+    }
+
+    /// Like `map`, but `f` may decline (e.g. when the data holds a different
+    /// enum variant), in which case the original guard is handed back.
+    pub fn try_map<U: ?Sized>(
+        s: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedSpinLockGuard<'a, U>, SpinLockGuard<'a, T>> {
+        let (lock, generation) = (s.lock, s.generation);
+
+        match f(unsafe { &mut *lock.data_ptr() }) {
+            Some(data) => {
+                let data: *mut U = data;
+                std::mem::forget(s);
+
+                Ok(MappedSpinLockGuard {
+                    raw: &lock.raw_,
+                    poison: &lock.poison_,
+                    generation,
+                    data,
+                    share: None,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(s),
+        }
+    }
+
+    /// Splits the guard into guards over two disjoint parts of the data,
+    /// such as two fields, for using both at once. The halves share the
+    /// lock, which is released when the last of them drops, in either order.
+    /// Either half can be mapped or split further.
+    pub fn split_map<A: ?Sized, B: ?Sized>(
+        s: Self,
+        f: impl FnOnce(&mut T) -> (&mut A, &mut B),
+    ) -> (MappedSpinLockGuard<'a, A>, MappedSpinLockGuard<'a, B>) {
+        MappedSpinLockGuard::split_map(SpinLockGuard::map(s, |data| data), f)
+    }
+}
+
+// Holds the raw lock and poison flag rather than the `SpinLock`, so the
+// guard does not need to name the lock's data type.
+pub struct MappedSpinLockGuard<'a, U: ?Sized> {
+    raw: &'a RawSpinLock,
+    poison: &'a AtomicBool,
+    generation: GuardGeneration,
+    data: *mut U,
+    // Shared by the guards `split_map` made out of one acquisition; only the
+    // last of them to drop unlocks.
+    share: Option<Arc<()>>,
+    _marker: PhantomData<&'a mut U>,
+}
+
+unsafe impl<U: ?Sized> Sync for MappedSpinLockGuard<'_, U> where U: Sync {}
+
+// The raw data pointer keeps the guard !Send, whatever its marker.
+#[cfg(feature = "send_guard")]
+unsafe impl<U: ?Sized> Send for MappedSpinLockGuard<'_, U> where U: Send {}
+
+impl<'a, U: ?Sized> MappedSpinLockGuard<'a, U> {
+    pub fn map<V: ?Sized>(mut s: Self, f: impl FnOnce(&mut U) -> &mut V) -> MappedSpinLockGuard<'a, V> {
+        let (raw, poison, generation) = (s.raw, s.poison, s.generation);
+        let data: *mut V = f(unsafe { &mut *s.data });
+        let share = s.share.take();
+        std::mem::forget(s);
+
+        MappedSpinLockGuard {
+            raw,
+            poison,
+            generation,
+            data,
+            share,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn try_map<V: ?Sized>(
+        mut s: Self,
+        f: impl FnOnce(&mut U) -> Option<&mut V>,
+    ) -> Result<MappedSpinLockGuard<'a, V>, MappedSpinLockGuard<'a, U>> {
+        let (raw, poison, generation) = (s.raw, s.poison, s.generation);
+
+        match f(unsafe { &mut *s.data }) {
+            Some(data) => {
+                let data: *mut V = data;
+                let share = s.share.take();
+                std::mem::forget(s);
+
+                Ok(MappedSpinLockGuard {
+                    raw,
+                    poison,
+                    generation,
+                    data,
+                    share,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(s),
+        }
+    }
+
+    /// Like `SpinLockGuard::split_map`. Splitting a half adds a third guard
+    /// to the ones sharing the lock.
+    pub fn split_map<A: ?Sized, B: ?Sized>(
+        mut s: Self,
+        f: impl FnOnce(&mut U) -> (&mut A, &mut B),
+    ) -> (MappedSpinLockGuard<'a, A>, MappedSpinLockGuard<'a, B>) {
+        let (raw, poison, generation) = (s.raw, s.poison, s.generation);
+        let (a, b) = f(unsafe { &mut *s.data });
+        let (a, b): (*mut A, *mut B) = (a, b);
+        let share = s.share.take().unwrap_or_default();
+        std::mem::forget(s);
+
+        let first = MappedSpinLockGuard {
+            raw,
+            poison,
+            generation,
+            data: a,
+            share: Some(Arc::clone(&share)),
+            _marker: PhantomData,
+        };
+
+        let second = MappedSpinLockGuard {
+            raw,
+            poison,
+            generation,
+            data: b,
+            share: Some(share),
+            _marker: PhantomData,
+        };
+
+        (first, second)
+    }
+}
+
+impl<U: ?Sized> Deref for MappedSpinLockGuard<'_, U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U {
+        self.raw.check_generation(self.generation);
+        unsafe { &*self.data }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedSpinLockGuard<'_, U> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut U {
+        self.raw.check_generation(self.generation);
+        self.raw.mark_dirty();
+        unsafe { &mut *self.data }
+    }
+}
+
+forward_to_data!(MappedSpinLockGuard<U: ?Sized>);
+
+impl<U: ?Sized> Drop for MappedSpinLockGuard<'_, U> {
+    #[inline]
+    fn drop(&mut self) {
+        poison_if_panicking(self.poison);
+
+        // Another guard split from the same acquisition still holds the lock.
+        if let Some(share) = self.share.take() {
+            if Arc::into_inner(share).is_none() {
                 return;
             }
+        }
+
+        unsafe { self.raw.unlock() }
+    }
+}
 
-            while self.lock_.load(Ordering::Relaxed) {
-                thread::yield_now();
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
 
-                if USE_SLEEP_SPIN_LOCK {
-                    freq += 1;
+    #[inline]
+    fn deref(&self) -> &T {
+        self.lock.raw_.check_generation(self.generation);
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
 
-                    if freq == SPIN_LOCK_SLEEP_ONE_FREQUENCY {
-                        thread::sleep(Duration::from_millis(1));
-                        freq = 0;
-                    }
-                }
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        self.lock.raw_.check_generation(self.generation);
+        self.lock.raw_.mark_dirty();
+        unsafe { &mut *self.lock.data_ptr() }
+    }
+}
+
+forward_to_data!(SpinLockGuard<T: ?Sized>);
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.poison_if_panicking();
+        self.lock.unlock();
+    }
+}
+
+/// The locks taken by `SpinLock::lock_many`, released (and poisoned, on a
+/// panic) together when dropped.
+pub struct LockManyGuard<'a, T> {
+    locks: &'a [SpinLock<T>],
+    // Sorted and deduplicated.
+    indices: Vec<usize>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for LockManyGuard<'_, T> where T: Sync {}
+
+impl<T> LockManyGuard<'_, T> {
+    /// The indices held, in ascending order.
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        self.indices
+            .iter()
+            .map(move |&index| (index, unsafe { &*self.locks[index].data_ptr() }))
+    }
+
+    /// The locked payloads with their indices, in ascending index order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> + '_ {
+        let locks = self.locks;
+
+        // The indices are distinct, so the references never alias.
+        self.indices.iter().map(move |&index| {
+            locks[index].raw_.mark_dirty();
+            (index, unsafe { &mut *locks[index].data_ptr() })
+        })
+    }
+}
+
+impl<T> Drop for LockManyGuard<'_, T> {
+    fn drop(&mut self) {
+        for &index in self.indices.iter().rev() {
+            self.locks[index].poison_if_panicking();
+            self.locks[index].unlock();
+        }
+    }
+}
+
+impl<T> SpinLock<T> {
+    const_unless_loom! {
+        pub fn new(data: T) -> SpinLock<T> {
+            SpinLock {
+                raw_: RawSpinLock::new(),
+                poison_: AtomicBool::new(false),
+                #[cfg(feature = "audit")]
+                audit_: UnsafeCell::new(None),
+                data: UnsafeCell::new(data),
             }
         }
     }
 
-    pub fn lock_with_max_attempts(&self) -> Result<(), &'static str> {
-        let mut freq = 0;
-        let mut attempts = 0;
+    pub fn new_named(name: &'static str, data: T) -> SpinLock<T> {
+        SpinLock {
+            raw_: RawSpinLock::new_named(name),
+            ..SpinLock::new(data)
+        }
+    }
+
+    /// A lock whose waiters sleep on the schedule `config` describes instead
+    /// of the default one.
+    pub fn with_config(data: T, config: SpinLockConfig) -> SpinLock<T> {
+        SpinLock {
+            raw_: RawSpinLock::with_config(config),
+            ..SpinLock::new(data)
+        }
+    }
 
-        loop {
-            if self
-                .lock_
-                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                return Ok(());
+    const_unless_loom! {
+        /// A lock whose waiters back off with `strategy` rather than the
+        /// target's `Strategy::DEFAULT`.
+        pub fn with_strategy(data: T, strategy: Strategy) -> SpinLock<T> {
+            // Spelled out, since a struct update would drop the unused
+            // `RawSpinLock`, which a `const fn` cannot do.
+            SpinLock {
+                raw_: RawSpinLock::with_strategy(strategy),
+                poison_: AtomicBool::new(false),
+                #[cfg(feature = "audit")]
+                audit_: UnsafeCell::new(None),
+                data: UnsafeCell::new(data),
             }
+        }
+    }
 
-            while self.lock_.load(Ordering::Relaxed) {
-                thread::yield_now();
-                attempts += 1;
+    /// `N` independent locks, each around its own clone of `value`, for
+    /// building a fixed table of shards.
+    pub fn array_of<const N: usize>(value: T) -> [SpinLock<T>; N]
+    where
+        T: Clone,
+    {
+        std::array::from_fn(|_| SpinLock::new(value.clone()))
+    }
 
-                if attempts >= SPIN_LOCK_MAX_ATTEMPTS {
-                    return Err("Failed to acquire lock after maximum attempts");
-                }
+    /// One lock per item, for tables whose size is only known at run time.
+    /// `Vec<SpinLock<T>>` cannot implement `FromIterator<T>` here (both are
+    /// foreign), so this is the collecting constructor instead.
+    pub fn vec_from_iter(items: impl IntoIterator<Item = T>) -> Vec<SpinLock<T>> {
+        items.into_iter().map(SpinLock::new).collect()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
 
-                if USE_SLEEP_SPIN_LOCK {
-                    freq += 1;
+    /// Moves the lock into an `Arc`, for the common case of a lock shared
+    /// between threads: `SpinLock::new(data).into_shared()`.
+    pub fn into_shared(self) -> Arc<SpinLock<T>> {
+        Arc::new(self)
+    }
 
-                    if freq == SPIN_LOCK_SLEEP_ONE_FREQUENCY {
-                        thread::sleep(Duration::from_millis(1));
-                        freq = 0;
+    /// Stores `value`. The old value is dropped after the lock is released.
+    pub fn set(&self, value: T) {
+        drop(self.replace(value));
+    }
+
+    /// Stores `value` and returns the value it replaced, as `Cell::replace`
+    /// does.
+    pub fn replace(&self, value: T) -> T {
+        self.with_lock(|data| std::mem::replace(data, value))
+    }
+
+    /// Replaces the value with `new` only if it equals `expected`, in one
+    /// acquisition, like a compare-and-swap on data no atomic can hold. On a
+    /// mismatch `new` is handed back. The replaced value is dropped after the
+    /// lock is released.
+    pub fn compare_replace(&self, expected: &T, new: T) -> Result<(), T>
+    where
+        T: PartialEq,
+    {
+        let old = self.with_lock(|data| {
+            if *data == *expected {
+                Ok(std::mem::replace(data, new))
+            } else {
+                Err(new)
+            }
+        })?;
+
+        drop(old);
+        Ok(())
+    }
+
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.with_lock(std::mem::take)
+    }
+
+    pub fn get_cloned(&self) -> T
+    where
+        T: Clone,
+    {
+        self.with_lock(|data| data.clone())
+    }
+
+    /// Copies the value out in one call. Still takes the lock; for payloads
+    /// of at most 8 bytes `AtomicSpinCell` needs no lock at all.
+    pub fn load(&self) -> T
+    where
+        T: Copy,
+    {
+        *self.lock()
+    }
+
+    /// Like `load`, but without taking the lock while no writer holds it
+    /// (`seqlock` feature). The value is copied out optimistically, and the
+    /// copy is kept only if the lock was free throughout and no hold began
+    /// and ended meanwhile; otherwise it is retried a few times. A lock
+    /// that is held, or keeps changing under the copies, is taken as by
+    /// `load`. Readers never write to the lock, so they do not contend with
+    /// one another. Poisoning is ignored.
+    ///
+    /// Like every seqlock, the copy may run concurrently with a writer, a
+    /// data race in the strict model that only the validation makes
+    /// harmless; Miri reports it. Under loom the lock is always taken.
+    #[cfg(feature = "seqlock")]
+    pub fn read_snapshot(&self) -> T
+    where
+        T: Copy,
+    {
+        #[cfg(not(loom))]
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            match self.raw_.snapshot_begin() {
+                Some(seq) => {
+                    // A copy that raced a writer may be torn, so it stays
+                    // uninitialized until the lock vouches for it.
+                    let copy = unsafe {
+                        std::ptr::read_volatile(self.data_ptr() as *const std::mem::MaybeUninit<T>)
+                    };
+
+                    if self.raw_.snapshot_valid(seq) {
+                        return unsafe { copy.assume_init() };
                     }
                 }
+                None => break,
             }
         }
+
+        *self.lock()
     }
 
-    pub fn unlock(&self) {
-        self.lock_.store(false, Ordering::Release);
+    /// Overwrites the value in one call, under the lock.
+    pub fn store(&self, value: T)
+    where
+        T: Copy,
+    {
+        *self.lock() = value;
     }
 
-    #[allow(dead_code)]
-    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
-        self.lock();
-        let result = unsafe { f(&mut *self.data.get()) };
-        self.unlock();
+    /// Like `update`, returning a copy of the new value.
+    pub fn update_and_fetch(&self, f: impl FnOnce(&mut T)) -> T
+    where
+        T: Clone,
+    {
+        let mut guard = self.lock();
+        f(&mut guard);
+        T::clone(&guard)
+    }
+
+    /// Like `update`, returning a copy of the value from before `f` ran.
+    pub fn fetch_and_update(&self, f: impl FnOnce(&mut T)) -> T
+    where
+        T: Clone,
+    {
+        let mut guard = self.lock();
+        let old = T::clone(&guard);
+        f(&mut guard);
+        old
+    }
+
+    /// Like `with_lock`, and reports the change to the callback installed
+    /// with `on_modify` (`audit` feature). The value is cloned before `f`
+    /// runs, and the callback gets that copy and the value `f` left behind.
+    /// It runs before the lock is released, so an audit trail records the
+    /// modifications in the order they happened. Without a callback nothing
+    /// is cloned.
+    #[cfg(feature = "audit")]
+    pub fn modify_logged<R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Clone,
+    {
+        let mut guard = self.lock();
+
+        let Some(callback) = (unsafe { &*self.audit_ptr() }) else {
+            return f(&mut guard);
+        };
+
+        let before = T::clone(&guard);
+        let result = f(&mut guard);
+        let after: &T = &guard;
+        callback(&before as *const T as *const (), after as *const T as *const ());
         result
     }
 
-    #[allow(dead_code)]
-    pub fn with_lock_max_attempts<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, &'static str> {
-        self.lock_with_max_attempts()?;
-        let result = unsafe { f(&mut *self.data.get()) };
-        self.unlock();
-        Ok(result)
+    /// Installs `callback` as the one `modify_logged` reports to, called as
+    /// `callback(before, after)` (`audit` feature). Replaces an earlier
+    /// callback. The callback runs under the lock, so it must not take it;
+    /// if it panics, the lock is released and poisoned as for a panic in
+    /// `f`. Installing takes the lock too, so it waits out a
+    /// `modify_logged` in progress.
+    #[cfg(feature = "audit")]
+    pub fn on_modify(&self, callback: impl Fn(&T, &T) + Send + Sync + 'static) {
+        let callback: AuditCallback = Box::new(move |before, after| {
+            // `modify_logged` passes pointers to two live `T`s.
+            callback(unsafe { &*(before as *const T) }, unsafe { &*(after as *const T) })
+        });
+
+        let guard = self.lock();
+        let replaced = unsafe { (*self.audit_ptr()).replace(callback) };
+        drop(guard);
+        // Dropped after the release, in case its captures take long to free.
+        drop(replaced);
+    }
+
+    /// Swaps the values of two locks, acquiring them in address order.
+    /// Swapping a lock with itself does nothing.
+    pub fn swap(&self, other: &SpinLock<T>) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+
+        self.lock_pair(other);
+        self.raw_.mark_dirty();
+        other.raw_.mark_dirty();
+        unsafe { std::ptr::swap(self.data_ptr(), other.data_ptr()) };
+        self.unlock_pair(other);
+    }
+
+    /// Locks `locks[i]` for every `i` in `indices`, in ascending index (and
+    /// so address) order whatever order `indices` is in, so callers locking
+    /// overlapping subsets cannot deadlock. Repeated indices are locked once.
+    ///
+    /// Panics, before locking anything, if an index is out of bounds.
+    pub fn lock_many<'a>(locks: &'a [SpinLock<T>], indices: &[usize]) -> LockManyGuard<'a, T> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+
+        if let Some(&last) = indices.last() {
+            assert!(
+                last < locks.len(),
+                "lock_many() index {} out of bounds for {} locks",
+                last,
+                locks.len()
+            );
+        }
+
+        for &index in &indices {
+            locks[index].raw_.lock();
+        }
+
+        LockManyGuard {
+            locks,
+            indices,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Blocks until the lock is acquired. Poisoning is ignored, as for
+    /// `with_lock`; `try_lock` and the timed acquisitions report it.
+    #[inline]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        self.raw_.lock();
+        self.guard()
+    }
+
+    /// `lock` for async code on a tokio runtime (`tokio` feature). A
+    /// contended wait spins in short bursts and awaits
+    /// `tokio::task::yield_now()` between them, and where `lock` would sleep
+    /// the thread it awaits `tokio::time::sleep` on the same schedule. The
+    /// worker thread is never blocked, so the task holding the lock can run
+    /// on it meanwhile, even on a single-worker runtime. The runtime needs
+    /// its timer enabled.
+    ///
+    /// Holding the guard across an `.await` is allowed: it is what lets a
+    /// holder on the same worker make progress at all. It is still a spin
+    /// lock, though. Everyone waiting keeps polling for as long as the
+    /// guard lives, so keep such sections short and prefer an async mutex
+    /// for long ones. The guard is `!Send` unless the `send_guard` feature
+    /// is on, so a task holding it across an `.await` has to run on a
+    /// `LocalSet` or through `block_on`.
+    ///
+    /// Async waiters are not counted as waiting, so `unlock_fair` and
+    /// `bump` do not hand the lock to them. Poisoning is ignored, as for
+    /// `lock`.
+    #[cfg(feature = "tokio")]
+    pub async fn lock_async_tokio(&self) -> SpinLockGuard<'_, T> {
+        let mut backoff = SpinWait::with_strategy(self.raw_.config(), spin_wait::DeferredWait::default());
+
+        while !self.raw_.try_lock_n(ASYNC_SPIN_BURST) {
+            backoff.spin();
+
+            match backoff.strategy_mut().take_sleep() {
+                Some(sleep) => tokio::time::sleep(sleep).await,
+                None => tokio::task::yield_now().await,
+            }
+        }
+
+        self.guard()
+    }
+
+    /// `lock` with a contended wait backing off through `backoff`; see
+    /// `RawSpinLock::lock_with`.
+    pub fn lock_with<W: WaitStrategy>(&self, backoff: &mut SpinWait<W>) -> SpinLockGuard<'_, T> {
+        self.raw_.lock_with(backoff);
+        self.guard()
+    }
+
+    /// The acquisition to reach for first. It spins briefly, then yields,
+    /// then sleeps on the lock's escalating schedule (or parks, with
+    /// `futex`), until it has the lock or `timeout` has passed. `None` waits
+    /// as long as it takes, like `lock`, and `Some(timeout)` is
+    /// `try_lock_for`; a zero timeout makes a single attempt. Unlike `lock`
+    /// it reports poisoning: it fails with `TimedOut` once the time is up,
+    /// and with `Poisoned`, which carries the guard, if a previous holder
+    /// panicked.
+    pub fn acquire(&self, timeout: Option<Duration>) -> TryLockResult<SpinLockGuard<'_, T>> {
+        match timeout {
+            Some(timeout) => self.try_lock_for(timeout),
+            None => {
+                self.raw_.lock();
+                self.poison_result(self.guard())
+            }
+        }
+    }
+
+    /// Gives up after `SPIN_LOCK_MAX_ATTEMPTS` (500) looks at the lock: the
+    /// initial CAS, each load that finds it held and each CAS that loses the
+    /// race count one apiece. Under a lock that stays held, that is the CAS
+    /// plus 499 loads.
+    #[deprecated(note = "use `acquire`, which bounds the wait by a `Duration` instead of an attempt count")]
+    pub fn lock_with_max_attempts(&self) -> Result<SpinLockGuard<'_, T>, TimeoutError> {
+        if self.raw_.try_lock() {
+            return Ok(self.guard());
+        }
+
+        let start = Instant::now();
+        let mut last = None;
+
+        if self.raw_.lock_contended(|progress| {
+            last = Some(*progress);
+            progress.attempts >= SPIN_LOCK_MAX_ATTEMPTS
+        }) {
+            Ok(self.guard())
+        } else {
+            Err(TimeoutError::new(start.elapsed(), last.unwrap()))
+        }
+    }
+
+    /// Locks like `lock`, but returns `WouldDeadlock` instead of hanging when
+    /// this thread already holds the lock through an earlier `lock_checked`
+    /// (see `RawSpinLock::lock_checked`). Poisoning is not reported.
+    pub fn lock_checked(&self) -> Result<SpinLockGuard<'_, T>, WouldDeadlock> {
+        self.raw_.lock_checked().map(|()| self.guard())
+    }
+
+    /// Makes a single acquisition attempt without spinning. Fails with
+    /// `WouldBlock` if the lock was held at that instant, or `Poisoned` if it
+    /// was acquired but a previous holder panicked.
+    #[inline]
+    pub fn try_lock(&self) -> TryLockResult<SpinLockGuard<'_, T>> {
+        if self.raw_.try_lock() {
+            self.poison_result(self.guard())
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Retries for up to `spins` iterations of `spin_loop`, never yielding or
+    /// sleeping, so it is safe where the thread must not be descheduled.
+    /// Poisoning is not reported; check `is_poisoned` if it matters.
+    pub fn try_lock_n(&self, spins: usize) -> Option<SpinLockGuard<'_, T>> {
+        self.raw_.try_lock_n(spins).then(|| self.guard())
+    }
+
+    /// Spins, yields and sleeps until the lock is acquired or `timeout` has
+    /// elapsed, in which case `TryLockError::TimedOut` is returned.
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<SpinLockGuard<'_, T>> {
+        if self.raw_.try_lock_for(timeout) {
+            self.poison_result(self.guard())
+        } else {
+            Err(TryLockError::TimedOut)
+        }
+    }
+
+    /// Spins, yields and sleeps until the lock is acquired or `cancel` reads
+    /// `true`, in which case `None` is returned. Another thread sets the flag
+    /// to call off the wait, e.g. on shutdown; it is noticed within one
+    /// backoff sleep. Poisoning is not reported; check `is_poisoned` if it
+    /// matters.
+    pub fn lock_cancellable(&self, cancel: &std::sync::atomic::AtomicBool) -> Option<SpinLockGuard<'_, T>> {
+        self.raw_.lock_cancellable(cancel).then(|| self.guard())
+    }
+
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<SpinLockGuard<'_, T>> {
+        if self.raw_.try_lock_until(deadline) {
+            self.poison_result(self.guard())
+        } else {
+            Err(TryLockError::TimedOut)
+        }
+    }
+
+    fn poison_result<'a>(&self, guard: SpinLockGuard<'a, T>) -> TryLockResult<SpinLockGuard<'a, T>> {
+        if self.is_poisoned() {
+            Err(TryLockError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    #[inline]
+    fn guard(&self) -> SpinLockGuard<'_, T> {
+        SpinLockGuard {
+            lock: self,
+            generation: self.raw_.guard_generation(),
+            _marker: PhantomData,
+        }
+    }
+
+    // Thin address identifying the lock, for lock ordering.
+    fn addr(&self) -> usize {
+        self.raw_.addr()
+    }
+
+    #[cfg(not(loom))]
+    #[inline]
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    // Under loom every access goes through `with_mut` so the model checker
+    // records it against the lock's happens-before edges.
+    #[cfg(loom)]
+    fn data_ptr(&self) -> *mut T {
+        self.data.with_mut(|ptr| ptr)
+    }
+
+    #[cfg(all(feature = "audit", not(loom)))]
+    fn audit_ptr(&self) -> *mut Option<AuditCallback> {
+        self.audit_.get()
+    }
+
+    #[cfg(all(feature = "audit", loom))]
+    fn audit_ptr(&self) -> *mut Option<AuditCallback> {
+        self.audit_.with_mut(|ptr| ptr)
+    }
+
+    /// Returns the word the lock is built on, as `RawSpinLock::as_atomic`
+    /// describes it: nonzero exactly while held.
+    ///
+    /// # Safety
+    ///
+    /// As for `RawSpinLock::as_atomic`: misuse lets safe code race on the
+    /// protected data.
+    #[cfg(feature = "unstable-raw")]
+    pub unsafe fn raw(&self) -> &sync::AtomicU8 {
+        self.raw_.as_atomic()
+    }
+
+    /// A lock is poisoned when a guard is dropped while its thread is
+    /// panicking. Blocking acquisitions ignore the flag; `try_lock`, the timed
+    /// acquisitions and the `compat` types report it.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison_.load(Ordering::Relaxed)
+    }
+
+    pub fn clear_poison(&self) {
+        self.poison_.store(false, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn poison_if_panicking(&self) {
+        poison_if_panicking(&self.poison_);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data_ptr() }
+    }
+
+    /// A raw pointer to the protected data, for handing to foreign code
+    /// while a guard is held: lock, pass the pointer, and keep the guard
+    /// alive until the call returns. Obtaining the pointer is safe;
+    /// dereferencing it without holding the lock is a data race, and it must
+    /// not be used after the guard is dropped unless the lock is taken again.
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data_ptr()
+    }
+
+    // Only for releasing a lock this crate acquired: guards, and the paired
+    // acquisitions in `swap`, `eq` and friends.
+    #[inline]
+    fn unlock(&self) {
+        unsafe { self.raw_.unlock() }
+    }
+
+    fn unlock_fair(&self) {
+        unsafe { self.raw_.unlock_fair() }
+    }
+
+    /// How many holds of the lock took mutable access to the data, through
+    /// a guard's `DerefMut` or `iter_mut`, and have since been released
+    /// (`versioned` feature). A hold that only read through `Deref` leaves
+    /// it alone, as do the projections `map` and `split_map` run and access
+    /// that bypasses the lock through `get_mut` or `as_mut_ptr`. The count
+    /// is read without the lock, so it may already be stale when returned.
+    #[cfg(feature = "versioned")]
+    pub fn version(&self) -> u64 {
+        self.raw_.version()
+    }
+
+    /// Backs off until `version` differs from `last_seen`, yielding and then
+    /// sleeping as a waiter for the lock does, and returns the guard of the
+    /// acquisition that follows with the version under it (`versioned`
+    /// feature). That version matches the data the guard sees, so passing
+    /// it back as `last_seen` waits for the next change. Mutating through
+    /// the returned guard counts as a change too.
+    #[cfg(feature = "versioned")]
+    pub fn wait_for_change(&self, last_seen: u64) -> (SpinLockGuard<'_, T>, u64) {
+        let mut backoff = SpinWait::new();
+
+        while self.raw_.version() == last_seen {
+            backoff.spin();
+        }
+
+        let guard = self.lock();
+        let version = self.raw_.version();
+        (guard, version)
+    }
+
+    /// Runs `f` under a guard, so if `f` panics the lock is released and
+    /// poisoned on the way out instead of staying held.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// `with_lock` through a trait object: one out-of-line copy per `T`
+    /// instead of one per closure, for code calling it from many sites where
+    /// binary size matters more than the indirect call. Results leave `f`
+    /// through its captures.
+    #[inline(never)]
+    pub fn with_lock_dyn(&self, f: &mut dyn FnMut(&mut T)) {
+        f(&mut self.lock())
+    }
+
+    /// Runs `f` only if the lock is free right now, after a single CAS, and
+    /// returns `None` without waiting otherwise; the backoff is never
+    /// touched. As with `with_lock`, poisoning is ignored and a panic in `f`
+    /// releases and poisons the lock.
+    pub fn try_with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.raw_.try_lock().then(|| f(&mut self.guard()))
+    }
+
+    /// `try_with_lock`, waiting up to `timeout` for the lock as
+    /// `try_lock_for` does.
+    pub fn try_with_lock_for<R>(&self, timeout: Duration, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.raw_.try_lock_for(timeout).then(|| f(&mut self.guard()))
+    }
+
+    pub fn with_lock_for<R>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, TryLockError<SpinLockGuard<'_, T>>> {
+        let mut guard = self.try_lock_for(timeout)?;
+        Ok(f(&mut guard))
+    }
+
+    /// Like `with_lock`, but gives up once `timeout` has passed without the
+    /// lock coming free. As with `with_lock`, poisoning is ignored; use
+    /// `with_lock_for` to have it reported.
+    pub fn with_lock_timeout<R>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, TimeoutError> {
+        let start = Instant::now();
+        let mut last = None;
+
+        let acquired = self.raw_.try_lock()
+            || match start.checked_add(timeout) {
+                Some(deadline) => self.raw_.lock_contended(|progress| {
+                    last = Some(*progress);
+                    Instant::now() >= deadline
+                }),
+                None => self.raw_.lock_contended(|_| false),
+            };
+
+        if acquired {
+            Ok(f(&mut self.guard()))
+        } else {
+            Err(TimeoutError::new(start.elapsed(), last.unwrap()))
+        }
+    }
+
+    #[allow(dead_code, deprecated)]
+    #[deprecated(note = "use `with_lock_for`, which takes a `Duration` instead of an attempt count")]
+    pub fn with_lock_max_attempts<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, TimeoutError> {
+        let mut guard = self.lock_with_max_attempts()?;
+        Ok(f(&mut guard))
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        self.with_lock(f);
+    }
+
+    /// Like `update`, acquiring the lock via `try_lock_for`.
+    pub fn try_update_for(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut T),
+    ) -> Result<(), TryLockError<SpinLockGuard<'_, T>>> {
+        let mut guard = self.try_lock_for(timeout)?;
+        f(&mut guard);
+        Ok(())
+    }
+
+    /// Locks, passes the data to `pred` and returns its result once it is
+    /// `Some`; otherwise unlocks and backs off, on the lock's own sleep
+    /// schedule and `Strategy`, before checking again. The lock is never held
+    /// while backing off, so other threads can change the data in between.
+    pub fn wait_until<R>(&self, mut pred: impl FnMut(&T) -> Option<R>) -> R {
+        let mut found = None;
+
+        wait_while_with(self.raw_.config(), || {
+            found = self.with_lock(|data| pred(data));
+            found.is_none()
+        });
+
+        found.expect("wait_while returned before the predicate held")
+    }
+
+    /// Runs `f` with both locks held, acquiring them in address order so
+    /// that threads locking the same pair in opposite argument order cannot
+    /// deadlock. Both are released (and poisoned, if `f` panics) afterwards.
+    ///
+    /// Panics if `self` and `other` are the same lock.
+    pub fn with_two_locks<U: ?Sized, R>(
+        &self,
+        other: &SpinLock<U>,
+        f: impl FnOnce(&mut T, &mut U) -> R,
+    ) -> R {
+        assert!(
+            !std::ptr::addr_eq(self, other),
+            "with_two_locks() called with the same lock twice"
+        );
+
+        // The guards are declared in locking order so that they drop in
+        // reverse, on unwinding too.
+        if self.addr() < other.addr() {
+            self.raw_.lock();
+            let mut first = self.guard();
+            other.raw_.lock();
+            let mut second = other.guard();
+            f(&mut first, &mut second)
+        } else {
+            other.raw_.lock();
+            let mut second = other.guard();
+            self.raw_.lock();
+            let mut first = self.guard();
+            f(&mut first, &mut second)
+        }
+    }
+
+    fn lock_pair<U: ?Sized>(&self, other: &SpinLock<U>) {
+        if self.addr() < other.addr() {
+            self.raw_.lock();
+            other.raw_.lock();
+        } else {
+            other.raw_.lock();
+            self.raw_.lock();
+        }
+    }
+
+    // Releases a `lock_pair` in the reverse order.
+    fn unlock_pair<U: ?Sized>(&self, other: &SpinLock<U>) {
+        if self.addr() < other.addr() {
+            other.unlock();
+            self.unlock();
+        } else {
+            self.unlock();
+            other.unlock();
+        }
+    }
+
+    /// Calls `callback` after every release that ends a hold longer than
+    /// `threshold`, turning the lock into a profiler for its slowest
+    /// critical sections. Replaces an earlier threshold and callback; holds
+    /// that began before the call are not timed.
+    #[cfg(feature = "timed")]
+    pub fn on_long_hold(&self, threshold: Duration, callback: fn(&LongHold)) {
+        self.raw_.on_long_hold(threshold, callback);
+    }
+
+    /// Calls `callback` once for every acquisition of this lock that had to
+    /// wait, describing the wait (`contention_hook` feature). It runs on the
+    /// acquiring thread right after it got the lock and before it gets the
+    /// guard, so it must be quick, must not take this lock, and must not
+    /// panic: a panic there aborts, since nothing could release the lock.
+    /// Replaces an earlier callback. Until one is installed, a contended
+    /// acquisition only checks for it.
+    #[cfg(feature = "contention_hook")]
+    pub fn on_contention(&self, callback: impl Fn(ContentionEvent) + Send + Sync + 'static) {
+        self.raw_.on_contention(callback);
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.raw_.stats()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.raw_.reset_stats();
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> SpinLock<T> {
+        SpinLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for SpinLock<T> {
+    fn from(data: T) -> SpinLock<T> {
+        SpinLock::new(data)
+    }
+}
+
+/// Tears down a shared lock: when `lock` is the last reference, consumes it
+/// and returns the data, without locking. Otherwise hands the `Arc` back.
+/// A free function rather than a method, since `Arc<SpinLock<T>>` is foreign.
+pub fn try_unwrap_inner<T>(lock: Arc<SpinLock<T>>) -> Result<T, Arc<SpinLock<T>>> {
+    Arc::try_unwrap(lock).map(SpinLock::into_inner)
+}
+
+/// Prints the protected value if the lock is free and `<locked>` otherwise.
+/// Only `try_lock` is used, so formatting never blocks, even on a thread
+/// that already holds the lock.
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SpinLock");
+
+        match self.try_lock() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(_) => d.field("data", &format_args!("<locked>")),
+        };
+
+        d.finish()
+    }
+}
+
+/// Clones the protected value into a new, unlocked and unpoisoned lock with
+/// fresh statistics, waiting for the lock like any other acquirer.
+///
+/// Panics if the lock is poisoned, since the value may be half-updated.
+impl<T: Clone> Clone for SpinLock<T> {
+    fn clone(&self) -> SpinLock<T> {
+        let guard = self.lock();
+        assert!(!self.is_poisoned(), "cannot clone a poisoned SpinLock");
+
+        SpinLock {
+            raw_: self.raw_.new_like(),
+            ..SpinLock::new(T::clone(&guard))
+        }
+    }
+}
+
+/// Compares the protected values, blocking until both locks are held.
+///
+/// The two locks are always acquired in ascending address order, so two
+/// threads comparing `a == b` and `b == a` concurrently cannot deadlock.
+/// Comparing a lock with itself acquires it only once. Both locks are held
+/// through guards, so a `T::eq` that panics releases and poisons them. Since
+/// every comparison takes locks, keep it out of hot paths.
+impl<T: ?Sized + PartialEq> PartialEq for SpinLock<T> {
+    fn eq(&self, other: &SpinLock<T>) -> bool {
+        if std::ptr::eq(self, other) {
+            return self.with_lock(|data| T::eq(data, data));
+        }
+
+        self.with_two_locks(other, |data, other| T::eq(data, other))
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for SpinLock<T> {}
+
+/// Hashes the protected value under the lock, so it is consistent with
+/// `PartialEq`. Like comparison, it blocks until the lock is available.
+impl<T: ?Sized + Hash> Hash for SpinLock<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.with_lock(|data| data.hash(state));
+    }
+}
+
+/// Serializes the protected value under the lock, so a concurrent writer can
+/// never leave it half-updated in the output. Like `Clone`, it blocks until
+/// the lock is available, so serializing state that contains a lock the
+/// calling thread holds deadlocks.
+///
+/// Fails with a custom error if the lock is poisoned, since the value may be
+/// half-updated.
+#[cfg(feature = "serde")]
+impl<T: ?Sized + serde::Serialize> serde::Serialize for SpinLock<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let guard = self.lock();
+
+        if self.is_poisoned() {
+            return Err(serde::ser::Error::custom("cannot serialize a poisoned SpinLock"));
+        }
+
+        T::serialize(&guard, serializer)
+    }
+}
+
+/// Deserializes the value into a new, unlocked and unpoisoned lock.
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for SpinLock<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<SpinLock<T>, D::Error> {
+        T::deserialize(deserializer).map(SpinLock::new)
     }
 }
@@ -0,0 +1,158 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::sync::{AtomicU8, Ordering, UnsafeCell};
+use crate::{wait_while_with, SpinLockConfig};
+use std::convert::Infallible;
+use std::mem::MaybeUninit;
+
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const INIT: u8 = 2;
+
+/// A cell written at most once, by the first caller of `get_or_init`.
+/// Concurrent callers spin with the lock's backoff until the value is ready.
+pub struct SpinOnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T> Sync for SpinOnceCell<T> where T: Send + Sync {}
+
+impl<T> SpinOnceCell<T> {
+    #[cfg(not(loom))]
+    pub const fn new() -> SpinOnceCell<T> {
+        SpinOnceCell {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn new() -> SpinOnceCell<T> {
+        SpinOnceCell {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    const_unless_loom! {
+        // A cell that starts out holding `value`.
+        pub(crate) fn with_value(value: T) -> SpinOnceCell<T> {
+            SpinOnceCell {
+                state: AtomicU8::new(INIT),
+                value: UnsafeCell::new(MaybeUninit::new(value)),
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == INIT {
+            Some(unsafe { self.value_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// If `f` panics the cell is left uninitialized and a later caller runs
+    /// its own initializer.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init_with(SpinLockConfig::DEFAULT, || Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    // `get_or_init` for an initializer that can fail, waiting on `config`'s
+    // backoff. An error, like a panic, leaves the cell uninitialized.
+    pub(crate) fn get_or_try_init_with<E>(
+        &self,
+        config: SpinLockConfig,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<&T, E> {
+        loop {
+            match self
+                .state
+                .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    struct Reset<'a>(&'a AtomicU8);
+
+                    impl Drop for Reset<'_> {
+                        fn drop(&mut self) {
+                            self.0.store(UNINIT, Ordering::Release);
+                        }
+                    }
+
+                    let reset = Reset(&self.state);
+                    let value = f()?;
+                    std::mem::forget(reset);
+
+                    unsafe { self.value_ptr().write(MaybeUninit::new(value)) };
+                    self.state.store(INIT, Ordering::Release);
+                    return Ok(unsafe { self.value_ref() });
+                }
+                Err(INIT) => return Ok(unsafe { self.value_ref() }),
+                Err(_) => wait_while_with(config, || self.state.load(Ordering::Acquire) == INITIALIZING),
+            }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> Option<&mut T> {
+        if self.state.load(Ordering::Relaxed) == INIT {
+            Some(unsafe { (*self.value_ptr()).assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        if self.state.load(Ordering::Relaxed) == INIT {
+            self.state.store(UNINIT, Ordering::Relaxed);
+            Some(unsafe { self.value_ptr().read().assume_init() })
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(loom))]
+    fn value_ptr(&self) -> *mut MaybeUninit<T> {
+        self.value.get()
+    }
+
+    #[cfg(loom)]
+    fn value_ptr(&self) -> *mut MaybeUninit<T> {
+        self.value.with_mut(|ptr| ptr)
+    }
+
+    unsafe fn value_ref(&self) -> &T {
+        (*self.value_ptr()).assume_init_ref()
+    }
+}
+
+impl<T> Default for SpinOnceCell<T> {
+    fn default() -> SpinOnceCell<T> {
+        SpinOnceCell::new()
+    }
+}
+
+impl<T> Drop for SpinOnceCell<T> {
+    fn drop(&mut self) {
+        if self.state.load(Ordering::Relaxed) == INIT {
+            unsafe { (*self.value_ptr()).assume_init_drop() };
+        }
+    }
+}
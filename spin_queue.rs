@@ -0,0 +1,108 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{SpinLock, SpinWait};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// A bounded multi-producer, multi-consumer queue: a ring buffer behind a
+/// `SpinLock`. The blocking `push` and `pop` retry with the lock's backoff
+/// and release the lock between attempts, so a producer waiting on a full
+/// queue never holds up the consumer that would make room, or vice versa.
+pub struct SpinQueue<T> {
+    ring: SpinLock<VecDeque<T>>,
+    capacity: usize,
+}
+
+impl<T> SpinQueue<T> {
+    pub fn with_capacity(capacity: usize) -> SpinQueue<T> {
+        assert!(capacity > 0, "a SpinQueue needs room for at least one item");
+
+        SpinQueue {
+            ring: SpinLock::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Appends `value`, or hands it back if the queue is full.
+    pub fn try_push(&self, value: T) -> Result<(), T> {
+        let mut ring = self.ring.lock();
+
+        if ring.len() == self.capacity {
+            return Err(value);
+        }
+
+        ring.push_back(value);
+        Ok(())
+    }
+
+    /// Removes the oldest item, if there is one.
+    pub fn try_pop(&self) -> Option<T> {
+        self.ring.lock().pop_front()
+    }
+
+    /// Appends `value`, backing off until there is room.
+    pub fn push(&self, mut value: T) {
+        let mut backoff = SpinWait::new();
+
+        while let Err(rejected) = self.try_push(value) {
+            value = rejected;
+            backoff.spin();
+        }
+    }
+
+    /// Removes the oldest item, backing off until there is one.
+    pub fn pop(&self) -> T {
+        let mut backoff = SpinWait::new();
+
+        loop {
+            if let Some(value) = self.try_pop() {
+                return value;
+            }
+
+            backoff.spin();
+        }
+    }
+
+    /// Best effort: other threads may push or pop before the caller acts on
+    /// the answer.
+    pub fn len(&self) -> usize {
+        self.ring.lock().len()
+    }
+
+    /// Best effort, like `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The queued items, oldest first.
+    pub fn into_inner(self) -> VecDeque<T> {
+        self.ring.into_inner()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SpinQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpinQueue")
+            .field("ring", &self.ring)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
@@ -0,0 +1,375 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#[cfg(feature = "held_locks")]
+use crate::held_locks;
+use crate::sync::{AtomicUsize, Ordering, UnsafeCell};
+use crate::{wait_while, GuardMarker};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+const WRITER: usize = 1;
+// Set by a blocked writer so that new readers hold back until it gets in.
+const WRITER_WAITING: usize = 2;
+// Held by the one upgradable reader, which also keeps new readers out so
+// that its upgrade only waits for the readers already in.
+const UPGRADABLE: usize = 4;
+const READER: usize = 8;
+
+/// A reader-writer spin lock: any number of readers or a single writer.
+/// Waiting writers take priority over arriving readers. One of the readers
+/// may instead hold an upgradable read, which it can later turn into a write
+/// without letting another writer in first.
+pub struct SpinRwLock<T> {
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinRwLock<T> where T: Send + Sync {}
+
+impl<T> SpinRwLock<T> {
+    const_unless_loom! {
+        pub fn new(data: T) -> SpinRwLock<T> {
+            SpinRwLock {
+                state: AtomicUsize::new(0),
+                data: UnsafeCell::new(data),
+            }
+        }
+    }
+
+    pub fn read(&self) -> SpinRwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+
+            wait_while(|| self.state.load(Ordering::Relaxed) & (WRITER | WRITER_WAITING | UPGRADABLE) != 0);
+        }
+    }
+
+    pub fn try_read(&self) -> Option<SpinRwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        if state & (WRITER | WRITER_WAITING | UPGRADABLE) == 0
+            && self
+                .state
+                .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.note_held();
+            Some(SpinRwLockReadGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Shared access that excludes writers, other upgradable reads and new
+    /// readers, and can be upgraded to a write once the readers already in
+    /// have left.
+    pub fn upgradeable_read(&self) -> SpinRwLockUpgradableGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_upgradeable_read() {
+                return guard;
+            }
+
+            wait_while(|| self.state.load(Ordering::Relaxed) & (WRITER | WRITER_WAITING | UPGRADABLE) != 0);
+        }
+    }
+
+    pub fn try_upgradeable_read(&self) -> Option<SpinRwLockUpgradableGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        if state & (WRITER | WRITER_WAITING | UPGRADABLE) == 0
+            && self
+                .state
+                .compare_exchange(state, state | UPGRADABLE, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.note_held();
+            Some(SpinRwLockUpgradableGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self) -> SpinRwLockWriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            wait_while(|| self.state.load(Ordering::Relaxed) & !WRITER_WAITING != 0);
+        }
+    }
+
+    pub fn try_write(&self) -> Option<SpinRwLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        // Taking the lock clears WRITER_WAITING; other blocked writers set it
+        // again on their next attempt.
+        if state & !WRITER_WAITING == 0
+            && self
+                .state
+                .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.note_held();
+            Some(SpinRwLockWriteGuard {
+                lock: self,
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data_ptr() }
+    }
+
+    // Readers in at this instant, counting an upgradable one, and whether a
+    // writer is; for `compat::spin`, whose `RwLock` reports both.
+    pub(crate) fn reader_count(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        state / READER + (state & UPGRADABLE != 0) as usize
+    }
+
+    pub(crate) fn writer_count(&self) -> usize {
+        self.state.load(Ordering::Relaxed) & WRITER
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    // With `held_locks`, tracks the calling thread's read and write holds.
+    // A downgrade keeps its entry.
+    #[inline]
+    fn note_held(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::acquired(self as *const SpinRwLock<T> as usize, None);
+    }
+
+    #[inline]
+    fn note_released(&self) {
+        #[cfg(feature = "held_locks")]
+        held_locks::released(self as *const SpinRwLock<T> as usize);
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    #[cfg(not(loom))]
+    fn data_ptr_shared(&self) -> *const T {
+        self.data.get()
+    }
+
+    #[cfg(loom)]
+    fn data_ptr(&self) -> *mut T {
+        self.data.with_mut(|ptr| ptr)
+    }
+
+    #[cfg(loom)]
+    fn data_ptr_shared(&self) -> *const T {
+        self.data.with(|ptr| ptr)
+    }
+}
+
+impl<T: Default> Default for SpinRwLock<T> {
+    fn default() -> SpinRwLock<T> {
+        SpinRwLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for SpinRwLock<T> {
+    fn from(data: T) -> SpinRwLock<T> {
+        SpinRwLock::new(data)
+    }
+}
+
+pub struct SpinRwLockReadGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for SpinRwLockReadGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for SpinRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr_shared() }
+    }
+}
+
+forward_to_data!(SpinRwLockReadGuard<T>);
+
+impl<T> Drop for SpinRwLockReadGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.note_released();
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+pub struct SpinRwLockWriteGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for SpinRwLockWriteGuard<'_, T> where T: Sync {}
+
+impl<'a, T> SpinRwLockWriteGuard<'a, T> {
+    /// Turns exclusive access into shared access in a single atomic step, so
+    /// the lock never passes through the unlocked state and no other writer
+    /// can get in between.
+    pub fn downgrade(s: Self) -> SpinRwLockReadGuard<'a, T> {
+        let lock = s.lock;
+        std::mem::forget(s);
+        lock.state.fetch_add(READER - WRITER, Ordering::Release);
+
+        SpinRwLockReadGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `downgrade`, but to an upgradable read, which keeps new readers
+    /// out as well as writers.
+    pub fn downgrade_to_upgradable(s: Self) -> SpinRwLockUpgradableGuard<'a, T> {
+        let lock = s.lock;
+        std::mem::forget(s);
+        lock.state.fetch_add(UPGRADABLE - WRITER, Ordering::Release);
+
+        SpinRwLockUpgradableGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for SpinRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr() }
+    }
+}
+
+impl<T> DerefMut for SpinRwLockWriteGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data_ptr() }
+    }
+}
+
+forward_to_data!(SpinRwLockWriteGuard<T>);
+
+impl<T> Drop for SpinRwLockWriteGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.note_released();
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+pub struct SpinRwLockUpgradableGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+    _marker: GuardMarker,
+}
+
+unsafe impl<T> Sync for SpinRwLockUpgradableGuard<'_, T> where T: Sync {}
+
+impl<'a, T> SpinRwLockUpgradableGuard<'a, T> {
+    /// Waits for the readers already in to leave, then turns the read into a
+    /// write. No other writer can get in first.
+    pub fn upgrade(mut s: Self) -> SpinRwLockWriteGuard<'a, T> {
+        loop {
+            match SpinRwLockUpgradableGuard::try_upgrade(s) {
+                Ok(guard) => return guard,
+                Err(guard) => s = guard,
+            }
+
+            wait_while(|| s.lock.state.load(Ordering::Relaxed) & !WRITER_WAITING != UPGRADABLE);
+        }
+    }
+
+    /// Upgrades if no other reader is in, and hands the guard back otherwise.
+    pub fn try_upgrade(s: Self) -> Result<SpinRwLockWriteGuard<'a, T>, Self> {
+        let state = s.lock.state.load(Ordering::Relaxed);
+
+        // As in `try_write`, the upgrade clears WRITER_WAITING.
+        if state & !WRITER_WAITING == UPGRADABLE
+            && s.lock
+                .state
+                .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            let lock = s.lock;
+            std::mem::forget(s);
+
+            Ok(SpinRwLockWriteGuard {
+                lock,
+                _marker: PhantomData,
+            })
+        } else {
+            Err(s)
+        }
+    }
+
+    /// Gives up the right to upgrade, in one atomic step, and lets new
+    /// readers in again.
+    pub fn downgrade(s: Self) -> SpinRwLockReadGuard<'a, T> {
+        let lock = s.lock;
+        std::mem::forget(s);
+        lock.state.fetch_add(READER - UPGRADABLE, Ordering::Release);
+
+        SpinRwLockReadGuard {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for SpinRwLockUpgradableGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data_ptr_shared() }
+    }
+}
+
+forward_to_data!(SpinRwLockUpgradableGuard<T>);
+
+impl<T> Drop for SpinRwLockUpgradableGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.note_released();
+        self.lock.state.fetch_and(!UPGRADABLE, Ordering::Release);
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{SpinLock, SpinWait};
+use std::fmt;
+
+/// A `SpinLock<Option<T>>` used as a slot that may or may not hold a value,
+/// such as a pending job or a cached connection. Every method takes the
+/// lock once; displaced values are dropped by the caller, after the lock is
+/// released.
+pub struct SpinSlot<T> {
+    slot: SpinLock<Option<T>>,
+}
+
+impl<T> SpinSlot<T> {
+    pub fn new() -> SpinSlot<T> {
+        SpinSlot {
+            slot: SpinLock::new(None),
+        }
+    }
+
+    /// Stores `value`, returning the one it displaced.
+    pub fn put(&self, value: T) -> Option<T> {
+        self.slot.replace(Some(value))
+    }
+
+    pub fn take(&self) -> Option<T> {
+        self.slot.take()
+    }
+
+    /// Takes the value only if `pred` accepts it; otherwise it stays put.
+    pub fn take_if(&self, pred: impl FnOnce(&T) -> bool) -> Option<T> {
+        self.slot.with_lock(|slot| {
+            if slot.as_ref().is_some_and(pred) {
+                slot.take()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Spins with the lock's backoff until the slot holds a value, then
+    /// takes it. Between attempts the lock is free for the producer.
+    pub fn wait_take(&self) -> T {
+        let mut backoff = SpinWait::new();
+
+        loop {
+            if let Some(value) = self.take() {
+                return value;
+            }
+
+            backoff.spin();
+        }
+    }
+
+    pub fn is_some(&self) -> bool {
+        self.slot.with_lock(|slot| slot.is_some())
+    }
+
+    pub fn get_cloned(&self) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.slot.get_cloned()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.slot.into_inner()
+    }
+}
+
+impl<T> Default for SpinSlot<T> {
+    fn default() -> SpinSlot<T> {
+        SpinSlot::new()
+    }
+}
+
+impl<T> From<T> for SpinSlot<T> {
+    fn from(value: T) -> SpinSlot<T> {
+        SpinSlot {
+            slot: SpinLock::new(Some(value)),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SpinSlot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SpinSlot").field(&self.slot).finish()
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::sync::{spin_loop, thread};
+use crate::{jitter, sleep_frequency, USE_JITTERED_BACKOFF};
+use std::time::Duration;
+
+// `spin_no_yield` doubles its busy-wait up to 2^SPIN_NO_YIELD_LIMIT hints.
+const SPIN_NO_YIELD_LIMIT: u32 = 6;
+
+/// What a backed-off waiter does between looks at the lock.
+///
+/// `Busy` only issues CPU spin hints and never gives up the thread. `Yield`
+/// yields to the scheduler and never sleeps. `OsWait` yields, and every
+/// 25..75 yields sleeps on the `SpinLockConfig` schedule, or with the `futex`
+/// and `wasm_wait` features parks until the next unlock.
+///
+/// `DEFAULT` is picked for the target at compile time: `Busy` where there
+/// is no scheduler to hand the CPU to (`target_os = "none"`, and wasm32
+/// without the atomics proposal, where yielding and sleeping do nothing),
+/// `Yield` on wasm with atomics but without `wasm_wait`, where a sleep on a
+/// browser's main thread traps, and `OsWait` everywhere else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Busy,
+    Yield,
+    OsWait,
+}
+
+impl Strategy {
+    pub const DEFAULT: Strategy = if cfg!(any(
+        target_os = "none",
+        all(target_family = "wasm", target_os = "unknown", not(target_feature = "atomics"))
+    )) {
+        Strategy::Busy
+    } else if cfg!(all(target_family = "wasm", not(feature = "wasm_wait"))) {
+        Strategy::Yield
+    } else {
+        Strategy::OsWait
+    };
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::DEFAULT
+    }
+}
+
+/// How long a backed-off waiter sleeps. The first sleep of a wait lasts
+/// `min_sleep`, and each further one twice as long as the last, up to
+/// `max_sleep`: a lock held for microseconds is retaken soon after it comes
+/// free, while a waiter on a lock held for seconds wakes rarely. With
+/// jittered backoff each sleep is scaled by a random 0.5..1.5.
+///
+/// The default goes from 50 µs to 1 ms. A `max_sleep` below `min_sleep` is
+/// raised to it.
+///
+/// With `anti_starvation`, a waiter that has lost the race for a free lock
+/// 16 times in one wait claims the lock's starving slot (one waiter at a time).
+/// It then stops sleeping, the other waiters stop competing for the lock,
+/// and every release hands the lock straight to it instead of freeing it.
+/// Off by default; `SpinWait` ignores it.
+///
+/// `strategy` chooses between spinning, yielding and sleeping, as `Strategy`
+/// describes; the sleep bounds only matter under `Strategy::OsWait`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinLockConfig {
+    pub min_sleep: Duration,
+    pub max_sleep: Duration,
+    pub anti_starvation: bool,
+    pub strategy: Strategy,
+}
+
+impl SpinLockConfig {
+    pub const DEFAULT: SpinLockConfig = SpinLockConfig {
+        min_sleep: Duration::from_micros(50),
+        max_sleep: Duration::from_millis(1),
+        anti_starvation: false,
+        strategy: Strategy::DEFAULT,
+    };
+}
+
+impl Default for SpinLockConfig {
+    fn default() -> SpinLockConfig {
+        SpinLockConfig::DEFAULT
+    }
+}
+
+/// The primitives a `SpinWait` backs off with: a CPU spin hint, a yield,
+/// and a sleep of the duration the schedule asks for. `ThreadWait` is the
+/// real one. A test double that records the calls (and sleeps for no real
+/// time) lets a test drive the schedule, or a contended `lock_with`,
+/// without depending on the clock.
+pub trait WaitStrategy {
+    fn spin(&mut self);
+    fn yield_now(&mut self);
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// Spin hints, `thread::yield_now` and `thread::sleep`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadWait;
+
+impl WaitStrategy for ThreadWait {
+    fn spin(&mut self) {
+        spin_loop();
+    }
+
+    fn yield_now(&mut self) {
+        thread::yield_now();
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// Spin hints, while yields and sleeps are only recorded, for an async waiter
+// to take on its runtime instead of blocking the worker thread. The waiter
+// yields to the runtime after every step anyway, so only a sleep is kept.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+pub(crate) struct DeferredWait {
+    sleep: Option<Duration>,
+}
+
+#[cfg(feature = "tokio")]
+impl DeferredWait {
+    // The sleep the last step asked for, if it asked for one.
+    pub(crate) fn take_sleep(&mut self) -> Option<Duration> {
+        self.sleep.take()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl WaitStrategy for DeferredWait {
+    fn spin(&mut self) {
+        spin_loop();
+    }
+
+    fn yield_now(&mut self) {}
+
+    fn sleep(&mut self, duration: Duration) {
+        self.sleep = Some(duration);
+    }
+}
+
+/// The lock's backoff, for retry loops outside of it: each `spin` yields the
+/// thread, and every 25..75 yields it sleeps instead of spinning on, for
+/// longer each time as `SpinLockConfig` describes. `SpinLock::lock` waits
+/// with the same schedule.
+///
+/// ```
+/// use spin_lock::SpinWait;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// fn acquire(flag: &AtomicBool) {
+///     let mut backoff = SpinWait::new();
+///
+///     while flag
+///         .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+///         .is_err()
+///     {
+///         backoff.spin();
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SpinWait<W: WaitStrategy = ThreadWait> {
+    yields: usize,
+    sleep_after: usize,
+    spins: u32,
+    sleep: Duration,
+    min_sleep: Duration,
+    max_sleep: Duration,
+    mode: Strategy,
+    strategy: W,
+}
+
+impl SpinWait {
+    pub fn new() -> SpinWait {
+        SpinWait::with_config(SpinLockConfig::DEFAULT)
+    }
+
+    /// A backoff whose sleeps escalate as `config` describes.
+    pub fn with_config(config: SpinLockConfig) -> SpinWait {
+        SpinWait::with_strategy(config, ThreadWait)
+    }
+}
+
+impl<W: WaitStrategy> SpinWait<W> {
+    /// A backoff on `config`'s schedule that spins, yields and sleeps
+    /// through `strategy`.
+    pub fn with_strategy(config: SpinLockConfig, strategy: W) -> SpinWait<W> {
+        SpinWait {
+            yields: 0,
+            sleep_after: sleep_frequency(),
+            spins: 0,
+            sleep: config.min_sleep,
+            min_sleep: config.min_sleep,
+            max_sleep: config.max_sleep.max(config.min_sleep),
+            mode: config.strategy,
+            strategy,
+        }
+    }
+
+    /// Backs off once. Returns false when this step had to sleep, which
+    /// means the wait has gone on long enough that spinning is no longer
+    /// helping and the caller may prefer to block some other way.
+    pub fn spin(&mut self) -> bool {
+        self.relax();
+
+        if self.mode != Strategy::OsWait {
+            return true;
+        }
+
+        self.yields += 1;
+
+        if self.yields < self.sleep_after {
+            return true;
+        }
+
+        let sleep = self.next_sleep();
+        self.strategy.sleep(sleep);
+        self.yields = 0;
+        self.sleep_after = sleep_frequency();
+        false
+    }
+
+    // The current step of the sleep schedule, jittered, and the schedule
+    // moved on to the next. Doubling starts from at least 1 µs, so a zero
+    // `min_sleep` escalates too.
+    fn next_sleep(&mut self) -> Duration {
+        let sleep = self.sleep;
+        self.sleep = (sleep * 2).max(Duration::from_micros(1)).min(self.max_sleep);
+
+        if USE_JITTERED_BACKOFF {
+            let micros = sleep.as_micros().min(u32::MAX as u128 / 2) as u32;
+            Duration::from_micros(micros as u64 / 2 + jitter(micros.max(1)) as u64)
+        } else {
+            sleep
+        }
+    }
+
+    // One step that never sleeps: a yield, or a spin hint under
+    // `Strategy::Busy`.
+    pub(crate) fn relax(&mut self) {
+        if self.mode == Strategy::Busy {
+            self.strategy.spin();
+        } else {
+            self.strategy.yield_now();
+        }
+    }
+
+    // Whether a step gives up the thread, which it does unless under
+    // `Strategy::Busy`.
+    #[cfg(feature = "contention_hook")]
+    pub(crate) fn yields(&self) -> bool {
+        self.mode != Strategy::Busy
+    }
+
+    /// Busy-waits with CPU spin hints only, never giving up the thread.
+    /// Each call spins twice as long as the previous one, up to 64 hints.
+    pub fn spin_no_yield(&mut self) {
+        for _ in 0..1u32 << self.spins {
+            self.strategy.spin();
+        }
+
+        self.spins = (self.spins + 1).min(SPIN_NO_YIELD_LIMIT);
+    }
+
+    /// Starts the schedule over, for reuse after the awaited condition held.
+    pub fn reset(&mut self) {
+        self.yields = 0;
+        self.sleep_after = sleep_frequency();
+        self.spins = 0;
+        self.sleep = self.min_sleep;
+    }
+
+    pub fn strategy(&self) -> &W {
+        &self.strategy
+    }
+
+    pub fn strategy_mut(&mut self) -> &mut W {
+        &mut self.strategy
+    }
+
+    pub fn into_strategy(self) -> W {
+        self.strategy
+    }
+}
+
+impl Default for SpinWait {
+    fn default() -> SpinWait {
+        SpinWait::new()
+    }
+}
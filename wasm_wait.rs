@@ -0,0 +1,40 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::arch::wasm32;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+// `memory.atomic.wait32`/`notify` on the lock's epoch word, with the
+// interface of the Linux `futex` module so `RawSpinLock` parks the same way
+// on both. Waiting traps on a browser's main thread, where blocking is not
+// allowed; a Web Worker blocks until notified or `timeout`. Spurious and
+// early returns are fine: callers re-check the lock either way.
+pub(crate) fn wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    unsafe {
+        wasm32::memory_atomic_wait32(
+            word.as_ptr() as *mut i32,
+            expected as i32,
+            timeout.as_nanos() as i64,
+        );
+    }
+}
+
+pub(crate) fn wake_one(word: &AtomicU32) {
+    unsafe {
+        wasm32::memory_atomic_notify(word.as_ptr() as *mut i32, 1);
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * SpinLock - custom implementation of a spinlock in Rust
+ * Copyright (c) 2024 Eungsuk Jeon
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::arch::asm;
+use std::sync::atomic::AtomicU8;
+
+// Waits for an event while the byte `flag` is still nonzero. The exclusive
+// load arms the monitor on the flag's granule, so the store that clears it
+// (from another core's `unlock`) wakes the `wfe` without a `sev`; so do
+// interrupts and, on Linux, the generic timer's event stream every 100 us.
+// Returns at once if the flag is already clear, and spurious returns are
+// fine: callers re-check the lock either way.
+#[inline]
+pub(crate) fn wait_while_set(flag: &AtomicU8) {
+    unsafe {
+        asm!(
+            "ldxrb {value:w}, [{flag}]",
+            "cbz {value:w}, 2f",
+            "wfe",
+            "2:",
+            flag = in(reg) flag.as_ptr(),
+            value = out(reg) _,
+            options(nostack, readonly),
+        );
+    }
+}
+
+// Wakes every core waiting in `wait_while_set`, for a change the waiters must
+// see that is not a write to the flag they monitor.
+#[inline]
+pub(crate) fn wake_all() {
+    unsafe {
+        asm!("sev", options(nomem, nostack));
+    }
+}